@@ -0,0 +1,512 @@
+use anyhow::Result;
+use chrono::{DateTime, Days, Duration, NaiveDate, Timelike, Utc};
+use clap::{Args, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::live::{Live, LiveDetection};
+use crate::notifier::{self, Notification, SinkConfig};
+use crate::{BirdDateAndTime, BirdDb};
+
+#[derive(Debug, Args)]
+pub struct Command {
+    /// Rules file, defaults to $BIRBS_ALERTS or alerts.toml.
+    #[arg(short, long)]
+    config: Option<String>,
+    #[command(subcommand)]
+    command: AlertsCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AlertsCommand {
+    /// Replays stored detections through the rules, printing what would have fired.
+    Test(TestOptions),
+    /// Sends a sample alert through every configured sink.
+    Notify(NotifyOptions),
+}
+
+#[derive(Debug, Args)]
+pub struct NotifyOptions {
+    /// Deliver to local stand-in HTTP and SMTP servers that print what they receive.
+    #[arg(long)]
+    stand_in: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct TestOptions {
+    /// Only report alerts from this date on, earlier detections just prime the rules.
+    #[arg(long)]
+    since: Option<NaiveDate>,
+}
+
+pub async fn execute(cmd: Command) -> Result<()> {
+    let config = AlertsConfig::load(&cmd.config.unwrap_or_else(get_alerts_config))?;
+
+    match cmd.command {
+        AlertsCommand::Test(options) => {
+            let db = BirdDb::new()?;
+            let mut engine = AlertEngine::new(config);
+            let since = options
+                .since
+                .unwrap_or_else(|| (Utc::now() - Duration::days(30)).date_naive());
+
+            for detection in db.detections()? {
+                let detection = LiveDetection::from(detection);
+                if BirdDateAndTime::from_utc(detection.when).local.date_naive() < since {
+                    engine.prime(&detection);
+                } else {
+                    for alert in engine.evaluate(&detection) {
+                        println!("{}", serde_json::to_string(&alert)?);
+                    }
+                }
+            }
+
+            Ok(())
+        }
+        AlertsCommand::Notify(options) => {
+            let sinks = if options.stand_in {
+                let (http, _) = notifier::http_stand_in().await?;
+                let (smtp, _) = notifier::smtp_stand_in().await?;
+                config
+                    .sinks
+                    .iter()
+                    .map(|sink| notifier::redirect_to_stand_in(sink, http, smtp))
+                    .collect::<Result<Vec<_>>>()?
+            } else {
+                config.sinks.clone()
+            };
+
+            let sample = Alert {
+                rule: "test".into(),
+                message: "Testing birbs notifications".into(),
+                detection: LiveDetection {
+                    when: Utc::now(),
+                    common_name: "American Crow".into(),
+                    scientific_name: "Corvus brachyrhynchos".into(),
+                    confidence: 0.9,
+                    file_name: "American_Crow-90-test.mp3".into(),
+                },
+            };
+
+            notifier::deliver(
+                Arc::new(notifier::new_notifiers(&sinks)?),
+                Notification::new(sample, &config.public_url),
+            )
+            .await;
+
+            Ok(())
+        }
+    }
+}
+
+pub fn get_alerts_config() -> String {
+    std::env::var("BIRBS_ALERTS").unwrap_or_else(|_| "alerts.toml".into())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AlertsConfig {
+    /// Detections below this are ignored by every rule.
+    #[serde(default)]
+    pub min_confidence: f32,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+    /// Where `serve` can be reached from, for photo links in notifications.
+    #[serde(default = "default_public_url")]
+    pub public_url: String,
+}
+
+fn default_public_url() -> String {
+    "http://127.0.0.1:3100".into()
+}
+
+impl AlertsConfig {
+    pub fn load(path: &str) -> Result<Self> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Alerts are optional, but a file that's there has to parse.
+    pub fn load_if_present(path: &str) -> Result<Option<Self>> {
+        if Path::new(path).exists() {
+            Ok(Some(Self::load(path)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    /// Minutes before the rule fires again for the same species.
+    #[serde(default = "default_cooldown")]
+    pub cooldown_minutes: i64,
+    #[serde(flatten)]
+    pub kind: RuleKind,
+}
+
+fn default_cooldown() -> i64 {
+    60
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RuleKind {
+    /// A species never detected at the station before.
+    NewSpecies,
+    /// A species back after going undetected for `days`.
+    NotSeen { days: i64 },
+    /// Any of `species`, common or scientific names, at or above `min_confidence`.
+    Watchlist {
+        species: Vec<String>,
+        #[serde(default)]
+        min_confidence: f32,
+    },
+    /// More than `factor` times the usual number of detections for this hour
+    /// of the day, averaged over the previous `days`.
+    UnusualActivity {
+        #[serde(default = "default_factor")]
+        factor: f32,
+        #[serde(default = "default_activity_days")]
+        days: u64,
+        #[serde(default = "default_min_detections")]
+        min_detections: u32,
+    },
+}
+
+fn default_factor() -> f32 {
+    3.0
+}
+
+fn default_activity_days() -> u64 {
+    14
+}
+
+fn default_min_detections() -> u32 {
+    10
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Alert {
+    pub rule: String,
+    pub message: String,
+    pub detection: LiveDetection,
+}
+
+pub struct AlertEngine {
+    config: AlertsConfig,
+    last_seen: HashMap<String, DateTime<Utc>>,
+    hourly: HashMap<(NaiveDate, u32), u32>,
+    /// The first day with any history, days before it aren't quiet ones.
+    first_day: Option<NaiveDate>,
+    fired: HashMap<(String, String), DateTime<Utc>>,
+}
+
+impl AlertEngine {
+    pub fn new(config: AlertsConfig) -> Self {
+        Self {
+            config,
+            last_seen: HashMap::new(),
+            hourly: HashMap::new(),
+            first_day: None,
+            fired: HashMap::new(),
+        }
+    }
+
+    /// Records a detection as history without evaluating any rules.
+    pub fn prime(&mut self, d: &LiveDetection) {
+        if d.confidence < self.config.min_confidence {
+            return;
+        }
+
+        let local = BirdDateAndTime::from_utc(d.when).local;
+        let date = local.date_naive();
+        self.first_day = Some(self.first_day.map_or(date, |first| first.min(date)));
+        *self
+            .hourly
+            .entry((local.date_naive(), local.hour()))
+            .or_default() += 1;
+
+        let last = self
+            .last_seen
+            .entry(d.scientific_name.clone())
+            .or_insert(d.when);
+        *last = d.when.max(*last);
+    }
+
+    pub fn evaluate(&mut self, d: &LiveDetection) -> Vec<Alert> {
+        if d.confidence < self.config.min_confidence {
+            return Vec::new();
+        }
+
+        let previously = self.last_seen.get(&d.scientific_name).copied();
+        self.prime(d);
+
+        let local = BirdDateAndTime::from_utc(d.when).local;
+        let Self {
+            config,
+            hourly,
+            first_day,
+            fired,
+            ..
+        } = self;
+        let mut alerts = Vec::new();
+
+        for rule in config.rules.iter() {
+            let message = match &rule.kind {
+                RuleKind::NewSpecies => previously
+                    .is_none()
+                    .then(|| format!("{} detected for the first time", d.common_name)),
+                RuleKind::NotSeen { days } => previously
+                    .filter(|p| d.when - *p >= Duration::days(*days))
+                    .map(|p| {
+                        format!(
+                            "{} is back after {} days",
+                            d.common_name,
+                            (d.when - p).num_days()
+                        )
+                    }),
+                RuleKind::Watchlist {
+                    species,
+                    min_confidence,
+                } => (d.confidence >= *min_confidence
+                    && species.iter().any(|s| {
+                        s.eq_ignore_ascii_case(&d.common_name)
+                            || s.eq_ignore_ascii_case(&d.scientific_name)
+                    }))
+                .then(|| {
+                    format!(
+                        "{} detected with confidence {:.2}",
+                        d.common_name, d.confidence
+                    )
+                }),
+                RuleKind::UnusualActivity {
+                    factor,
+                    days,
+                    min_detections,
+                } => {
+                    let date = local.date_naive();
+                    let hour = local.hour();
+                    let current = hourly.get(&(date, hour)).copied().unwrap_or_default();
+                    let previous = (1..=*days)
+                        .filter_map(|n| date.checked_sub_days(Days::new(n)))
+                        .filter(|day| first_day.is_some_and(|first| *day >= first))
+                        .map(|day| hourly.get(&(day, hour)).copied().unwrap_or_default())
+                        .collect::<Vec<_>>();
+                    let typical =
+                        previous.iter().sum::<u32>() as f32 / previous.len().max(1) as f32;
+
+                    // Without a day of history there's nothing to be unusual against.
+                    (!previous.is_empty()
+                        && current >= *min_detections
+                        && current as f32 > typical * factor)
+                        .then(|| {
+                            format!(
+                                "{} detections between {}:00 and {}:59, usually {:.1}",
+                                current, hour, hour, typical
+                            )
+                        })
+                }
+            };
+
+            let Some(message) = message else {
+                continue;
+            };
+
+            // Unusual activity is about the station rather than any one species.
+            let species = match rule.kind {
+                RuleKind::UnusualActivity { .. } => "*".to_owned(),
+                _ => d.scientific_name.clone(),
+            };
+            let key = (rule.name.clone(), species);
+            let cooldown = Duration::minutes(rule.cooldown_minutes);
+
+            if let Some(last) = fired.get(&key) {
+                if d.when - *last < cooldown {
+                    continue;
+                }
+            }
+            fired.insert(key, d.when);
+
+            alerts.push(Alert {
+                rule: rule.name.clone(),
+                message,
+                detection: d.clone(),
+            });
+        }
+
+        alerts
+    }
+}
+
+/// Primes the rules with everything in BirdNET-Pi's database and then
+/// evaluates detections as they're published, until the live stream closes.
+/// The stream reads the same database, so whatever it replays on startup has
+/// already been primed.
+pub async fn run(config: AlertsConfig, live: Arc<Live>) -> Result<()> {
+    let (_, mut receiver) = live.subscribe(0);
+
+    let notifiers = Arc::new(notifier::new_notifiers(&config.sinks)?);
+    let public_url = config.public_url.clone();
+
+    let mut engine = AlertEngine::new(config);
+    let history = tokio::task::spawn_blocking(|| BirdDb::birdnet()?.detections()).await??;
+    let primed_until = history.iter().map(|d| d.when).max();
+    for detection in history {
+        engine.prime(&detection.into());
+    }
+
+    info!("alerts ready");
+
+    loop {
+        match receiver.recv().await {
+            Ok(detection) if Some(detection.when) <= primed_until => {}
+            Ok(detection) => {
+                for alert in engine.evaluate(&detection) {
+                    info!("alert: {}: {}", alert.rule, alert.message);
+                    tokio::spawn(notifier::deliver(
+                        notifiers.clone(),
+                        Notification::new(alert, &public_url),
+                    ));
+                }
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("alerts lagged, skipped {}", skipped);
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::tests::detection;
+
+    fn engine(rules: &str) -> AlertEngine {
+        AlertEngine::new(toml::from_str(rules).unwrap())
+    }
+
+    fn at(day: u32, hour: u32, minute: u32, scientific_name: &str) -> LiveDetection {
+        detection(
+            Utc.with_ymd_and_hms(2024, 5, day, hour, minute, 0).unwrap(),
+            scientific_name,
+            "Some Bird",
+        )
+        .into()
+    }
+
+    fn fired(engine: &mut AlertEngine, detection: LiveDetection) -> Vec<String> {
+        engine
+            .evaluate(&detection)
+            .into_iter()
+            .map(|alert| alert.rule)
+            .collect()
+    }
+
+    #[test]
+    fn cools_down_per_rule_and_species() {
+        let mut engine = engine(
+            r#"
+            [[rules]]
+            name = "robins"
+            kind = "watchlist"
+            species = ["Turdus migratorius", "Corvus brachyrhynchos"]
+            cooldown_minutes = 30
+            "#,
+        );
+
+        assert_eq!(
+            fired(&mut engine, at(1, 14, 0, "Turdus migratorius")),
+            ["robins"]
+        );
+        assert!(fired(&mut engine, at(1, 14, 29, "Turdus migratorius")).is_empty());
+        assert_eq!(
+            fired(&mut engine, at(1, 14, 29, "Corvus brachyrhynchos")),
+            ["robins"]
+        );
+        assert_eq!(
+            fired(&mut engine, at(1, 14, 30, "Turdus migratorius")),
+            ["robins"]
+        );
+    }
+
+    #[test]
+    fn fires_when_a_species_is_back() {
+        let mut engine = engine(
+            r#"
+            [[rules]]
+            name = "back"
+            kind = "not_seen"
+            days = 7
+
+            [[rules]]
+            name = "new"
+            kind = "new_species"
+            "#,
+        );
+
+        engine.prime(&at(1, 14, 0, "Turdus migratorius"));
+
+        assert!(fired(&mut engine, at(7, 14, 0, "Turdus migratorius")).is_empty());
+        assert!(fired(&mut engine, at(14, 13, 59, "Turdus migratorius")).is_empty());
+        assert_eq!(
+            fired(&mut engine, at(21, 14, 0, "Turdus migratorius")),
+            ["back"]
+        );
+        assert_eq!(
+            fired(&mut engine, at(21, 14, 0, "Corvus brachyrhynchos")),
+            ["new"]
+        );
+    }
+
+    #[test]
+    fn fires_on_unusual_activity_for_the_hour() {
+        let mut engine = engine(
+            r#"
+            [[rules]]
+            name = "busy"
+            kind = "unusual_activity"
+            factor = 2.0
+            days = 14
+            min_detections = 3
+            cooldown_minutes = 0
+            "#,
+        );
+
+        // A detection an hour on each of two days, the first days of history.
+        engine.prime(&at(1, 14, 0, "Turdus migratorius"));
+        engine.prime(&at(2, 14, 0, "Turdus migratorius"));
+
+        assert!(fired(&mut engine, at(3, 14, 0, "Turdus migratorius")).is_empty());
+        assert!(fired(&mut engine, at(3, 14, 1, "Turdus migratorius")).is_empty());
+        assert_eq!(
+            fired(&mut engine, at(3, 14, 2, "Turdus migratorius")),
+            ["busy"]
+        );
+        // A different hour has no history at all.
+        assert!(fired(&mut engine, at(3, 16, 0, "Turdus migratorius")).is_empty());
+    }
+
+    #[test]
+    fn needs_a_day_of_history_for_unusual_activity() {
+        let mut engine = engine(
+            r#"
+            [[rules]]
+            name = "busy"
+            kind = "unusual_activity"
+            min_detections = 1
+            "#,
+        );
+
+        for minute in 0..10 {
+            assert!(fired(&mut engine, at(1, 14, minute, "Turdus migratorius")).is_empty());
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use anyhow::Result;
+use axum::extract::Query;
+use axum::{http::StatusCode, Json};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::review::ReviewFilter;
+use crate::species::{self, Species};
+use crate::sun::{Almanac, SunTimes};
+use crate::taxonomy::LangQuery;
+use crate::{BirdDateAndTime, BirdDb, Detection};
+
+const DEFAULT_BIN_MINUTES: u32 = 15;
+
+#[derive(Deserialize, Debug)]
+pub struct SunQuery {
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+    min_confidence: Option<f32>,
+    bin_minutes: Option<u32>,
+}
+
+impl SunQuery {
+    /// Everything through today unless asked otherwise.
+    fn detections(&self, db: &BirdDb) -> Result<Vec<Detection>> {
+        let today = BirdDateAndTime::from_utc(Utc::now()).local.date_naive();
+        let start = match self.start {
+            Some(start) => start,
+            None => match db.oldest_date()? {
+                Some(oldest) => NaiveDate::parse_from_str(&oldest, "%Y-%m-%d")?,
+                None => today,
+            },
+        };
+
+        db.detections_between(
+            start,
+            self.end.unwrap_or(today),
+            self.min_confidence.unwrap_or(0.0),
+        )
+    }
+
+    fn bin_minutes(&self) -> u32 {
+        self.bin_minutes
+            .filter(|m| *m > 0)
+            .unwrap_or(DEFAULT_BIN_MINUTES)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SunBin {
+    /// Start of the bin, in minutes after the sun event. Negative is before.
+    minutes: i64,
+    detections: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SunHistogram {
+    bin_minutes: u32,
+    sunrise: Vec<SunBin>,
+    sunset: Vec<SunBin>,
+}
+
+fn histogram(almanac: &mut Almanac, detections: &[Detection], bin_minutes: u32) -> SunHistogram {
+    let width = bin_minutes as i64;
+    let seconds = width * 60;
+    let mut sunrise: BTreeMap<i64, u32> = BTreeMap::new();
+    let mut sunset: BTreeMap<i64, u32> = BTreeMap::new();
+
+    for detection in detections {
+        let sun = day_of(almanac, detection);
+        let count = |bins: &mut BTreeMap<i64, u32>, event: Option<DateTime<Utc>>| {
+            if let Some(event) = event {
+                // By seconds, whole minutes round towards the event and would
+                // put the minute before it in the first bin after.
+                let bin = (detection.when - event).num_seconds().div_euclid(seconds);
+                *bins.entry(bin * width).or_default() += 1;
+            }
+        };
+        count(&mut sunrise, sun.sunrise);
+        count(&mut sunset, sun.sunset);
+    }
+
+    let bins = |bins: BTreeMap<i64, u32>| {
+        bins.into_iter()
+            .map(|(minutes, detections)| SunBin {
+                minutes,
+                detections,
+            })
+            .collect()
+    };
+
+    SunHistogram {
+        bin_minutes,
+        sunrise: bins(sunrise),
+        sunset: bins(sunset),
+    }
+}
+
+/// The sun's day where and when a detection was made, by the station's local date.
+fn day_of(almanac: &mut Almanac, detection: &Detection) -> SunTimes {
+    almanac.day(
+        BirdDateAndTime::from_utc(detection.when).local.date_naive(),
+        detection.latitude as f64,
+        detection.longitude as f64,
+    )
+}
+
+#[derive(Serialize, Debug)]
+pub struct FirstSong {
+    date: NaiveDate,
+    civil_dawn: Option<DateTime<Utc>>,
+    sunrise: DateTime<Utc>,
+    first: DateTime<Utc>,
+    /// Minutes after sunrise of the first detection that day.
+    minutes: i64,
+}
+
+/// The first detection on each day, relative to that day's sunrise. Detections
+/// are in order, so the first seen for a date is the earliest.
+fn first_songs<'a>(
+    almanac: &mut Almanac,
+    detections: impl Iterator<Item = &'a Detection>,
+) -> Vec<FirstSong> {
+    let mut days: BTreeMap<NaiveDate, FirstSong> = BTreeMap::new();
+    for detection in detections {
+        let date = BirdDateAndTime::from_utc(detection.when).local.date_naive();
+        if days.contains_key(&date) {
+            continue;
+        }
+
+        let sun = day_of(almanac, detection);
+        if let Some(sunrise) = sun.sunrise {
+            days.insert(
+                date,
+                FirstSong {
+                    date,
+                    civil_dawn: sun.civil_dawn,
+                    sunrise,
+                    first: detection.when,
+                    minutes: (detection.when - sunrise).num_minutes(),
+                },
+            );
+        }
+    }
+
+    days.into_values().collect()
+}
+
+fn median(days: &[FirstSong]) -> Option<f64> {
+    let mut minutes = days.iter().map(|d| d.minutes).collect::<Vec<_>>();
+    minutes.sort();
+
+    let middle = minutes.len() / 2;
+    match minutes.len() {
+        0 => None,
+        n if n % 2 == 0 => Some((minutes[middle - 1] + minutes[middle]) as f64 / 2.0),
+        _ => Some(minutes[middle] as f64),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SpeciesSun {
+    #[serde(flatten)]
+    histogram: SunHistogram,
+    /// Median minutes after sunrise of the first detection each day.
+    median_first_song: Option<f64>,
+    first_songs: Vec<FirstSong>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FirstSongSummary {
+    slug: String,
+    common_name: String,
+    scientific_name: String,
+    days: usize,
+    /// Median minutes after sunrise of the first detection each day.
+    median_first_song: f64,
+}
+
+fn station_sun(db: &BirdDb, query: &SunQuery) -> Result<SunHistogram> {
+    let detections = query.detections(db)?;
+
+    Ok(histogram(
+        &mut Almanac::default(),
+        &detections,
+        query.bin_minutes(),
+    ))
+}
+
+fn species_sun(db: &BirdDb, scientific_name: &str, query: &SunQuery) -> Result<SpeciesSun> {
+    let detections = query
+        .detections(db)?
+        .into_iter()
+        .filter(|d| d.scientific_name == scientific_name)
+        .collect::<Vec<_>>();
+
+    let mut almanac = Almanac::default();
+    let first_songs = first_songs(&mut almanac, detections.iter());
+
+    Ok(SpeciesSun {
+        histogram: histogram(&mut almanac, &detections, query.bin_minutes()),
+        median_first_song: median(&first_songs),
+        first_songs,
+    })
+}
+
+fn first_song_summaries(
+    db: &BirdDb,
+    query: &SunQuery,
+    lang: &LangQuery,
+) -> Result<Vec<FirstSongSummary>> {
+    let detections = query.detections(db)?;
+    let translation = lang.translation();
+
+    let mut by_species: HashMap<&str, Vec<&Detection>> = HashMap::new();
+    for detection in &detections {
+        by_species
+            .entry(&detection.scientific_name)
+            .or_default()
+            .push(detection);
+    }
+
+    let mut almanac = Almanac::default();
+    let mut summaries = by_species
+        .into_iter()
+        .filter_map(|(scientific_name, detections)| {
+            let days = first_songs(&mut almanac, detections.iter().copied());
+            let mut common_name = detections.last()?.common_name.clone();
+            if let Some(translation) = &translation {
+                translation.localize(Some(scientific_name), &mut common_name);
+            }
+
+            Some(FirstSongSummary {
+                slug: species::slug(scientific_name),
+                common_name,
+                scientific_name: scientific_name.to_owned(),
+                median_first_song: median(&days)?,
+                days: days.len(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Earliest singers first, the way a dawn chorus builds.
+    summaries.sort_by(|a, b| a.median_first_song.total_cmp(&b.median_first_song));
+
+    Ok(summaries)
+}
+
+#[axum_macros::debug_handler]
+pub async fn sun_json(
+    Query(query): Query<SunQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<Json<SunHistogram>, StatusCode> {
+    Ok(Json(
+        tokio::task::spawn_blocking(move || station_sun(&reviews.db()?, &query))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    ))
+}
+
+#[axum_macros::debug_handler]
+pub async fn species_sun_json(
+    Species {
+        scientific_name, ..
+    }: Species,
+    Query(query): Query<SunQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<Json<SpeciesSun>, StatusCode> {
+    Ok(Json(
+        tokio::task::spawn_blocking(move || species_sun(&reviews.db()?, &scientific_name, &query))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    ))
+}
+
+#[axum_macros::debug_handler]
+pub async fn first_song_json(
+    Query(query): Query<SunQuery>,
+    Query(reviews): Query<ReviewFilter>,
+    Query(lang): Query<LangQuery>,
+) -> Result<Json<Vec<FirstSongSummary>>, StatusCode> {
+    Ok(Json(
+        tokio::task::spawn_blocking(move || first_song_summaries(&reviews.db()?, &query, &lang))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::tests::detection;
+
+    #[test]
+    fn bins_around_sunrise_by_the_second() {
+        let mut almanac = Almanac::default();
+        let date = NaiveDate::from_ymd_opt(2024, 6, 20).unwrap();
+        let sunrise = almanac.day(date, 47.6, -122.3).sunrise.unwrap();
+
+        let detections = [-901, -30, 0, 899, 900]
+            .into_iter()
+            .map(|s| {
+                detection(
+                    sunrise + Duration::seconds(s),
+                    "Turdus migratorius",
+                    "American Robin",
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let histogram = histogram(&mut almanac, &detections, 15);
+
+        assert_eq!(
+            histogram
+                .sunrise
+                .iter()
+                .map(|b| (b.minutes, b.detections))
+                .collect::<Vec<_>>(),
+            vec![(-30, 1), (-15, 1), (0, 2), (15, 1)]
+        );
+        assert_eq!(
+            histogram.sunset.iter().map(|b| b.detections).sum::<u32>(),
+            5
+        );
+    }
+
+    #[test]
+    fn first_songs_are_the_earliest_each_day() {
+        let mut almanac = Almanac::default();
+        let mut sunrise = |d| {
+            almanac
+                .day(NaiveDate::from_ymd_opt(2024, 6, d).unwrap(), 47.6, -122.3)
+                .sunrise
+                .unwrap()
+        };
+        let (first, second) = (sunrise(20), sunrise(21));
+
+        let detections = [
+            first - Duration::minutes(20),
+            first + Duration::minutes(40),
+            second + Duration::minutes(10),
+        ]
+        .map(|when| detection(when, "Turdus migratorius", "American Robin"));
+
+        let days = first_songs(&mut almanac, detections.iter());
+
+        assert_eq!(
+            days.iter().map(|d| d.minutes).collect::<Vec<_>>(),
+            vec![-20, 10]
+        );
+        assert_eq!(median(&days), Some(-5.0));
+        assert_eq!(median(&[]), None);
+    }
+}
@@ -0,0 +1,273 @@
+use anyhow::Result;
+use axum::extract::Query;
+use axum::{http::StatusCode, Json};
+use chrono::{Datelike, Days, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::review::ReviewFilter;
+use crate::taxonomy::{LangQuery, Translation};
+use crate::{species, BirdDb, SpeciesDay};
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    #[default]
+    Day,
+    /// Weeks starting on Monday.
+    Week,
+}
+
+impl Period {
+    fn start(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => date,
+            Period::Week => date
+                .checked_sub_days(Days::new(date.weekday().num_days_from_monday() as u64))
+                .unwrap_or(date),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Periods {
+    counts: BTreeMap<NaiveDate, HashMap<String, u32>>,
+    /// The name each species was most recently detected as.
+    common_names: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DiversityQuery {
+    #[serde(default)]
+    period: Period,
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+    min_confidence: Option<f32>,
+}
+
+impl DiversityQuery {
+    /// Detections of each species in each period, by scientific name.
+    fn periods(&self, db: &BirdDb) -> Result<Periods> {
+        let mut periods = Periods::default();
+        for SpeciesDay {
+            scientific_name,
+            common_name,
+            date,
+            detections,
+        } in db.species_daily_counts(self.min_confidence.unwrap_or(0.0))?
+        {
+            if self.start.is_some_and(|s| date < s) || self.end.is_some_and(|e| date > e) {
+                continue;
+            }
+
+            periods
+                .common_names
+                .insert(scientific_name.clone(), common_name);
+            *periods
+                .counts
+                .entry(self.period.start(date))
+                .or_default()
+                .entry(scientific_name)
+                .or_default() += detections;
+        }
+
+        Ok(periods)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Diversity {
+    start: NaiveDate,
+    /// Species detected.
+    richness: usize,
+    detections: u32,
+    /// Shannon index, H' = -Σ p ln p over each species' share of detections.
+    shannon: f64,
+    /// Gini-Simpson index, 1 - Σ p², the chance two detections are of
+    /// different species.
+    simpson: f64,
+}
+
+fn diversity(start: NaiveDate, counts: &HashMap<String, u32>) -> Diversity {
+    let detections = counts.values().sum::<u32>();
+    let shares = counts
+        .values()
+        .map(|c| *c as f64 / detections as f64)
+        .collect::<Vec<_>>();
+
+    Diversity {
+        start,
+        richness: counts.len(),
+        detections,
+        // Adding to zero keeps a lone species from reporting -0.
+        shannon: 0.0 - shares.iter().map(|p| p * p.ln()).sum::<f64>(),
+        simpson: 1.0 - shares.iter().map(|p| p * p).sum::<f64>(),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct NewSpecies {
+    slug: String,
+    common_name: String,
+    scientific_name: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Accumulation {
+    start: NaiveDate,
+    /// Species detected for the first time in this period.
+    new_species: Vec<NewSpecies>,
+    /// Species detected in this period or any before it.
+    cumulative: usize,
+}
+
+fn accumulation(periods: Periods, translation: Option<Translation>) -> Vec<Accumulation> {
+    let mut seen = HashSet::new();
+
+    periods
+        .counts
+        .into_iter()
+        .map(|(start, counts)| {
+            let mut new_species = counts
+                .into_keys()
+                .filter(|s| seen.insert(s.clone()))
+                .collect::<Vec<_>>();
+            new_species.sort();
+
+            Accumulation {
+                start,
+                new_species: new_species
+                    .into_iter()
+                    .map(|scientific_name| {
+                        let mut common_name = periods.common_names[&scientific_name].clone();
+                        if let Some(translation) = &translation {
+                            translation.localize(Some(&scientific_name), &mut common_name);
+                        }
+
+                        NewSpecies {
+                            slug: species::slug(&scientific_name),
+                            common_name,
+                            scientific_name,
+                        }
+                    })
+                    .collect(),
+                cumulative: seen.len(),
+            }
+        })
+        .collect()
+}
+
+#[axum_macros::debug_handler]
+pub async fn diversity_json(
+    Query(query): Query<DiversityQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<Json<Vec<Diversity>>, StatusCode> {
+    let periods = tokio::task::spawn_blocking(move || query.periods(&reviews.db()?))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(
+        periods
+            .counts
+            .iter()
+            .map(|(start, counts)| diversity(*start, counts))
+            .collect(),
+    ))
+}
+
+#[axum_macros::debug_handler]
+pub async fn accumulation_json(
+    Query(query): Query<DiversityQuery>,
+    Query(reviews): Query<ReviewFilter>,
+    Query(lang): Query<LangQuery>,
+) -> Result<Json<Vec<Accumulation>>, StatusCode> {
+    let periods = tokio::task::spawn_blocking(move || query.periods(&reviews.db()?))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(accumulation(periods, lang.translation())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 5, d).unwrap()
+    }
+
+    fn counts(counts: &[(&str, u32)]) -> HashMap<String, u32> {
+        counts.iter().map(|(s, c)| (s.to_string(), *c)).collect()
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        // The 1st of May 2024 was a Wednesday.
+        assert_eq!(
+            Period::Week.start(date(1)),
+            NaiveDate::from_ymd_opt(2024, 4, 29).unwrap()
+        );
+        assert_eq!(Period::Week.start(date(6)), date(6));
+        assert_eq!(Period::Day.start(date(1)), date(1));
+    }
+
+    #[test]
+    fn even_species_are_most_diverse() {
+        let even = diversity(date(1), &counts(&[("a", 5), ("b", 5)]));
+        assert_eq!(even.richness, 2);
+        assert_eq!(even.detections, 10);
+        assert!((even.shannon - 2f64.ln()).abs() < 1e-9);
+        assert!((even.simpson - 0.5).abs() < 1e-9);
+
+        let skewed = diversity(date(1), &counts(&[("a", 9), ("b", 1)]));
+        assert!(skewed.shannon < even.shannon);
+        assert!((skewed.simpson - 0.18).abs() < 1e-9);
+
+        let lone = diversity(date(1), &counts(&[("a", 3)]));
+        assert_eq!(lone.shannon.to_string(), "0");
+        assert_eq!(lone.simpson, 0.0);
+    }
+
+    #[test]
+    fn accumulates_new_species() {
+        let periods = Periods {
+            counts: BTreeMap::from([
+                (date(1), counts(&[("Corvus brachyrhynchos", 2)])),
+                (
+                    date(2),
+                    counts(&[("Corvus brachyrhynchos", 1), ("Turdus migratorius", 4)]),
+                ),
+                (date(3), counts(&[("Turdus migratorius", 1)])),
+            ]),
+            common_names: HashMap::from([
+                (
+                    "Corvus brachyrhynchos".to_owned(),
+                    "American Crow".to_owned(),
+                ),
+                ("Turdus migratorius".to_owned(), "American Robin".to_owned()),
+            ]),
+        };
+
+        let accumulation = accumulation(periods, None);
+
+        assert_eq!(
+            accumulation
+                .iter()
+                .map(|a| (
+                    a.new_species
+                        .iter()
+                        .map(|s| s.common_name.as_str())
+                        .collect::<Vec<_>>(),
+                    a.cumulative
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (vec!["American Crow"], 1),
+                (vec!["American Robin"], 2),
+                (vec![], 2)
+            ]
+        );
+    }
+}
@@ -0,0 +1,330 @@
+use anyhow::Result;
+use axum::extract::Query;
+use axum::{http::StatusCode, Json};
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::review::ReviewFilter;
+use crate::species::{self, Species};
+use crate::taxonomy::LangQuery;
+use crate::{recording_url, BirdDateAndTime, BirdDb, Daily, Detection, Hourly};
+
+/// Minutes of quiet after which the same species is counted again.
+pub const DEFAULT_GAP_MINUTES: u32 = 10;
+
+/// A run of detections of one species no more than the gap apart, most likely
+/// one bird singing or calling for a while.
+#[derive(Serialize, Debug, Clone)]
+pub struct Encounter {
+    pub slug: String,
+    pub common_name: String,
+    pub scientific_name: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub detections: usize,
+    pub peak_confidence: f32,
+    /// The most confident detection's recording.
+    pub file_name: String,
+    pub audio_url: String,
+    pub spectrogram_url: String,
+}
+
+impl Encounter {
+    fn new(detection: &Detection) -> Self {
+        let (file_name, audio_url) = best_recording(detection);
+
+        Self {
+            slug: species::slug(&detection.scientific_name),
+            common_name: detection.common_name.clone(),
+            scientific_name: detection.scientific_name.clone(),
+            start: detection.when,
+            end: detection.when,
+            detections: 1,
+            peak_confidence: detection.confidence,
+            file_name,
+            spectrogram_url: format!("{}.png", audio_url),
+            audio_url,
+        }
+    }
+
+    fn extend(&mut self, detection: &Detection) {
+        self.end = detection.when;
+        self.detections += 1;
+        if detection.confidence > self.peak_confidence {
+            let (file_name, audio_url) = best_recording(detection);
+            self.peak_confidence = detection.confidence;
+            self.file_name = file_name;
+            self.spectrogram_url = format!("{}.png", audio_url);
+            self.audio_url = audio_url;
+        }
+    }
+}
+
+fn best_recording(detection: &Detection) -> (String, String) {
+    let when = BirdDateAndTime::from_utc(detection.when);
+    (
+        detection.file_name.clone(),
+        recording_url(&when, &detection.common_name, &detection.file_name),
+    )
+}
+
+/// Merges detections ordered by time into encounters, each species on its
+/// own. Encounters are ordered by when they started.
+pub fn encounters<'a>(
+    detections: impl IntoIterator<Item = &'a Detection>,
+    gap: u32,
+) -> Vec<Encounter> {
+    let gap = Duration::minutes(gap as i64);
+    let mut open: HashMap<&str, Encounter> = HashMap::new();
+    let mut closed = Vec::new();
+
+    for detection in detections {
+        match open.get_mut(detection.scientific_name.as_str()) {
+            Some(encounter) if detection.when - encounter.end <= gap => {
+                encounter.extend(detection);
+            }
+            _ => {
+                if let Some(previous) =
+                    open.insert(&detection.scientific_name, Encounter::new(detection))
+                {
+                    closed.push(previous);
+                }
+            }
+        }
+    }
+
+    closed.extend(open.into_values());
+    closed.sort_by(|a, b| {
+        a.start
+            .cmp(&b.start)
+            .then_with(|| a.scientific_name.cmp(&b.scientific_name))
+    });
+
+    closed
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Count {
+    #[default]
+    Detections,
+    Encounters,
+}
+
+/// Lets the aggregate endpoints count encounters instead of detections.
+#[derive(Deserialize, Debug)]
+pub struct CountQuery {
+    #[serde(default)]
+    pub count: Count,
+    pub gap: Option<u32>,
+}
+
+impl CountQuery {
+    pub fn gap(&self) -> u32 {
+        self.gap.unwrap_or(DEFAULT_GAP_MINUTES)
+    }
+
+    /// The gap between encounters when they're what's counted.
+    pub fn encounter_gap(&self) -> Option<u32> {
+        match self.count {
+            Count::Detections => None,
+            Count::Encounters => Some(self.gap()),
+        }
+    }
+}
+
+/// Encounters by the local date they started.
+pub fn daily(encounters: &[Encounter]) -> Vec<Daily> {
+    let mut days: BTreeMap<NaiveDate, u64> = BTreeMap::new();
+    for encounter in encounters {
+        let local = BirdDateAndTime::from_utc(encounter.start).local;
+        *days.entry(local.date_naive()).or_default() += 1;
+    }
+
+    days.into_iter()
+        .map(|(date, detections)| Daily {
+            date: BirdDateAndTime::new_naive(date, NaiveTime::MIN)
+                .expect("valid date")
+                .into(),
+            detections,
+        })
+        .collect()
+}
+
+/// Encounters by the local hour they started.
+pub fn hourly(encounters: &[Encounter]) -> Vec<Hourly> {
+    let mut hours: BTreeMap<u32, u64> = BTreeMap::new();
+    for encounter in encounters {
+        let local = BirdDateAndTime::from_utc(encounter.start).local;
+        *hours.entry(local.hour()).or_default() += 1;
+    }
+
+    hours
+        .into_iter()
+        .map(|(hour, detections)| Hourly {
+            number: hour,
+            time: NaiveTime::from_hms_opt(hour, 0, 0).expect("valid hour"),
+            detections,
+        })
+        .collect()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EncountersQuery {
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+    min_confidence: Option<f32>,
+    gap: Option<u32>,
+}
+
+/// Today's encounters unless asked otherwise.
+fn station_encounters(db: &BirdDb, query: &EncountersQuery) -> Result<Vec<Encounter>> {
+    let today = BirdDateAndTime::from_utc(Utc::now()).local.date_naive();
+    let detections = db.detections_between(
+        query.start.unwrap_or(today),
+        query.end.unwrap_or(today),
+        query.min_confidence.unwrap_or(0.0),
+    )?;
+
+    Ok(encounters(
+        &detections,
+        query.gap.unwrap_or(DEFAULT_GAP_MINUTES),
+    ))
+}
+
+fn species_encounters(
+    db: &BirdDb,
+    scientific_name: &str,
+    query: &EncountersQuery,
+) -> Result<Vec<Encounter>> {
+    let detections = db.species_detections(scientific_name)?;
+    let min_confidence = query.min_confidence.unwrap_or(0.0);
+
+    Ok(encounters(
+        detections.iter().filter(|d| {
+            let date = BirdDateAndTime::from_utc(d.when).local.date_naive();
+            d.confidence >= min_confidence
+                && query.start.is_none_or(|s| date >= s)
+                && query.end.is_none_or(|e| date <= e)
+        }),
+        query.gap.unwrap_or(DEFAULT_GAP_MINUTES),
+    ))
+}
+
+/// Names are translated last, recordings are filed under the English ones.
+fn localize(lang: &LangQuery, mut encounters: Vec<Encounter>) -> Vec<Encounter> {
+    if let Some(translation) = lang.translation() {
+        for encounter in encounters.iter_mut() {
+            translation.localize(Some(&encounter.scientific_name), &mut encounter.common_name);
+        }
+    }
+
+    encounters
+}
+
+#[axum_macros::debug_handler]
+pub async fn encounters_json(
+    Query(query): Query<EncountersQuery>,
+    Query(reviews): Query<ReviewFilter>,
+    Query(lang): Query<LangQuery>,
+) -> Result<Json<Vec<Encounter>>, StatusCode> {
+    let encounters =
+        tokio::task::spawn_blocking(move || station_encounters(&reviews.db()?, &query))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(localize(&lang, encounters)))
+}
+
+#[axum_macros::debug_handler]
+pub async fn species_encounters_json(
+    Species {
+        scientific_name, ..
+    }: Species,
+    Query(query): Query<EncountersQuery>,
+    Query(reviews): Query<ReviewFilter>,
+    Query(lang): Query<LangQuery>,
+) -> Result<Json<Vec<Encounter>>, StatusCode> {
+    let encounters = tokio::task::spawn_blocking(move || {
+        species_encounters(&reviews.db()?, &scientific_name, &query)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(localize(&lang, encounters)))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::tests::detection;
+
+    fn at(hour: u32, minute: u32, scientific_name: &str, confidence: f32) -> Detection {
+        let mut detection = detection(
+            Utc.with_ymd_and_hms(2024, 5, 1, hour, minute, 0).unwrap(),
+            scientific_name,
+            "",
+        );
+        detection.confidence = confidence;
+        detection.file_name = format!("{}-{}.mp3", hour, minute);
+        detection
+    }
+
+    #[test]
+    fn merges_detections_within_the_gap() {
+        let detections = [
+            at(14, 0, "Turdus migratorius", 0.7),
+            at(14, 2, "Corvus brachyrhynchos", 0.8),
+            at(14, 5, "Turdus migratorius", 0.9),
+            at(14, 15, "Turdus migratorius", 0.6),
+            at(14, 26, "Turdus migratorius", 0.8),
+        ];
+
+        let encounters = encounters(&detections, 10);
+
+        assert_eq!(
+            encounters
+                .iter()
+                .map(|e| (
+                    e.scientific_name.as_str(),
+                    e.start.minute(),
+                    e.end.minute(),
+                    e.detections
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("Turdus migratorius", 0, 15, 3),
+                ("Corvus brachyrhynchos", 2, 2, 1),
+                ("Turdus migratorius", 26, 26, 1),
+            ]
+        );
+        assert_eq!(encounters[0].peak_confidence, 0.9);
+        assert_eq!(encounters[0].file_name, "14-5.mp3");
+        assert!(encounters[0].spectrogram_url.ends_with("/14-5.mp3.png"));
+    }
+
+    #[test]
+    fn counts_encounters_by_hour_and_day() {
+        let detections = [
+            at(14, 0, "Turdus migratorius", 0.7),
+            at(14, 30, "Turdus migratorius", 0.7),
+            at(15, 0, "Corvus brachyrhynchos", 0.7),
+        ];
+
+        let encounters = encounters(&detections, 10);
+
+        assert_eq!(
+            hourly(&encounters)
+                .iter()
+                .map(|h| (h.number, h.detections))
+                .collect::<Vec<_>>(),
+            vec![(7, 2), (8, 1)]
+        );
+        assert_eq!(daily(&encounters)[0].detections, 3);
+    }
+}
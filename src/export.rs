@@ -0,0 +1,554 @@
+use anyhow::Result;
+use chrono::{Duration, NaiveDate, NaiveTime, Timelike};
+use clap::{Args, Subcommand};
+use itertools::Itertools;
+use serde::Serialize;
+use std::io::{Seek, Write};
+use zip::write::SimpleFileOptions;
+
+use crate::encounters::encounters;
+use crate::{BirdDateAndTime, BirdDb, Detection};
+
+#[derive(Debug, Args)]
+pub struct Command {
+    #[command(subcommand)]
+    format: Format,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Format {
+    Ebird(EbirdOptions),
+    Dwca(DwcaOptions),
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct EbirdOptions {
+    #[arg(long)]
+    pub start: NaiveDate,
+    #[arg(long)]
+    pub end: NaiveDate,
+    /// Checklist length in minutes, one checklist per day when omitted.
+    #[arg(long)]
+    pub interval: Option<u32>,
+    /// Detections of a species further apart than this many minutes are separate encounters.
+    #[arg(long, default_value_t = 10)]
+    pub gap: u32,
+    #[arg(long, default_value_t = 0.7)]
+    pub min_confidence: f32,
+    /// Common or scientific names to leave off the checklists.
+    #[arg(long, value_delimiter = ',')]
+    pub exclude: Vec<String>,
+    #[arg(long, default_value = "birbs")]
+    pub location: String,
+    #[arg(long, default_value = "")]
+    pub state: String,
+    #[arg(long, default_value = "")]
+    pub country: String,
+    #[arg(short, long)]
+    pub output: Option<String>,
+}
+
+pub async fn execute(cmd: Command) -> Result<()> {
+    let db = BirdDb::new()?;
+
+    match cmd.format {
+        Format::Ebird(options) => match &options.output {
+            Some(path) => write_ebird(&db, &options, std::fs::File::create(path)?),
+            None => write_ebird(&db, &options, std::io::stdout()),
+        },
+        Format::Dwca(options) => {
+            write_dwca(&db, &options, std::fs::File::create(&options.output)?)?;
+            Ok(())
+        }
+    }
+}
+
+/// One row of eBird Record Format, which has no header and exactly these 19 columns.
+#[derive(Serialize, Debug)]
+struct EbirdRecord {
+    common_name: String,
+    genus: String,
+    species: String,
+    number: usize,
+    species_comments: String,
+    location: String,
+    latitude: f32,
+    longitude: f32,
+    date: String,
+    start_time: String,
+    state: String,
+    country: String,
+    protocol: &'static str,
+    observers: u32,
+    duration: u32,
+    all_observations_reported: &'static str,
+    distance_miles: Option<f32>,
+    area_acres: Option<f32>,
+    checklist_comments: String,
+}
+
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Debug)]
+struct Checklist {
+    date: NaiveDate,
+    slot: u32,
+}
+
+impl Checklist {
+    fn start_time(&self, interval: u32) -> NaiveTime {
+        NaiveTime::MIN + Duration::minutes((self.slot * interval) as i64)
+    }
+}
+
+/// eBird counts are the most individuals seen at once, and repeated detections
+/// may well be the same bird, so the best we can report is the most detections
+/// of a species in a single clip.
+fn most_at_once(detections: &[&Detection]) -> usize {
+    detections
+        .iter()
+        .counts_by(|d| d.when)
+        .into_values()
+        .max()
+        .unwrap_or_default()
+}
+
+fn is_excluded(options: &EbirdOptions, detection: &Detection) -> bool {
+    options.exclude.iter().any(|name| {
+        name.eq_ignore_ascii_case(&detection.common_name)
+            || name.eq_ignore_ascii_case(&detection.scientific_name)
+    })
+}
+
+pub fn write_ebird<W: Write>(db: &BirdDb, options: &EbirdOptions, w: W) -> Result<()> {
+    const MINUTES_PER_DAY: u32 = 24 * 60;
+
+    let interval = options
+        .interval
+        .unwrap_or(MINUTES_PER_DAY)
+        .clamp(1, MINUTES_PER_DAY);
+
+    let detections = db.detections_between(options.start, options.end, options.min_confidence)?;
+
+    let by_checklist = detections
+        .iter()
+        .filter(|d| !is_excluded(options, d))
+        .map(|d| {
+            let local = BirdDateAndTime::from_utc(d.when).local;
+            let minutes = local.hour() * 60 + local.minute();
+            let checklist = Checklist {
+                date: local.date_naive(),
+                slot: minutes / interval,
+            };
+            (checklist, d)
+        })
+        .into_group_map();
+
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(w);
+
+    for (checklist, detections) in by_checklist.into_iter().sorted_by_key(|(c, _)| *c) {
+        let start_time = checklist.start_time(interval);
+        let duration = interval.min(MINUTES_PER_DAY - checklist.slot * interval);
+        let first = detections[0];
+
+        let by_species = detections
+            .into_iter()
+            .into_group_map_by(|d| (d.common_name.clone(), d.scientific_name.clone()));
+
+        for ((common_name, scientific_name), detections) in
+            by_species.into_iter().sorted_by(|(a, _), (b, _)| a.cmp(b))
+        {
+            let (genus, species) = scientific_name
+                .split_once(' ')
+                .unwrap_or((&scientific_name, ""));
+            let peak = detections
+                .iter()
+                .map(|d| d.confidence)
+                .fold(0.0f32, f32::max);
+
+            writer.serialize(EbirdRecord {
+                genus: genus.to_owned(),
+                species: species.to_owned(),
+                number: most_at_once(&detections),
+                species_comments: format!(
+                    "{} BirdNET detections in {} encounters, peak confidence {:.2}",
+                    detections.len(),
+                    encounters(detections.iter().copied(), options.gap).len(),
+                    peak
+                ),
+                common_name,
+                location: options.location.clone(),
+                latitude: first.latitude,
+                longitude: first.longitude,
+                date: checklist.date.format("%m/%d/%Y").to_string(),
+                start_time: start_time.format("%H:%M").to_string(),
+                state: options.state.clone(),
+                country: options.country.clone(),
+                protocol: "Stationary",
+                observers: 1,
+                duration,
+                all_observations_reported: "N",
+                distance_miles: None,
+                area_acres: None,
+                checklist_comments: format!(
+                    "Automated BirdNET recording, minimum confidence {:.2}",
+                    options.min_confidence
+                ),
+            })?;
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct DwcaOptions {
+    #[arg(long)]
+    pub start: NaiveDate,
+    #[arg(long)]
+    pub end: NaiveDate,
+    #[arg(long, default_value_t = 0.7)]
+    pub min_confidence: f32,
+    #[arg(long, default_value = "birbs BirdNET detections")]
+    pub title: String,
+    #[arg(long, default_value = "birbs")]
+    pub creator: String,
+    /// Prefixes every occurrenceID so that clip names are unique across datasets.
+    #[arg(long, default_value = "birbs")]
+    pub dataset_id: String,
+    #[arg(short, long, default_value = "dwca.zip")]
+    pub output: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Occurrence<'a> {
+    #[serde(rename = "occurrenceID")]
+    occurrence_id: &'a str,
+    basis_of_record: &'static str,
+    occurrence_status: &'static str,
+    event_date: String,
+    scientific_name: String,
+    vernacular_name: String,
+    decimal_latitude: f32,
+    decimal_longitude: f32,
+    geodetic_datum: &'static str,
+    identified_by: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MeasurementOrFact<'a> {
+    #[serde(rename = "coreid")]
+    core_id: &'a str,
+    measurement_type: &'static str,
+    measurement_value: f32,
+    measurement_method: &'static str,
+}
+
+const DWC: &str = "http://rs.tdwg.org/dwc/terms/";
+
+const OCCURRENCE_TERMS: [&str; 10] = [
+    "occurrenceID",
+    "basisOfRecord",
+    "occurrenceStatus",
+    "eventDate",
+    "scientificName",
+    "vernacularName",
+    "decimalLatitude",
+    "decimalLongitude",
+    "geodeticDatum",
+    "identifiedBy",
+];
+
+const MEASUREMENT_TERMS: [&str; 3] = ["measurementType", "measurementValue", "measurementMethod"];
+
+fn tab_writer<W: Write>(w: W) -> csv::Writer<W> {
+    csv::WriterBuilder::new()
+        .has_headers(false)
+        .delimiter(b'\t')
+        .quote_style(csv::QuoteStyle::Never)
+        .from_writer(w)
+}
+
+/// Fields are written unquoted, so tabs and line breaks would split them.
+fn unquoted(i: &str) -> String {
+    i.replace(['\t', '\r', '\n'], " ")
+}
+
+fn meta_xml() -> String {
+    let fields = |terms: &[&str], offset: usize| {
+        terms
+            .iter()
+            .enumerate()
+            .map(|(i, term)| {
+                format!(
+                    r#"    <field index="{}" term="{}{}"/>"#,
+                    i + offset,
+                    DWC,
+                    term
+                )
+            })
+            .join("\n")
+    };
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<archive xmlns="http://rs.tdwg.org/dwc/text/" metadata="eml.xml">
+  <core encoding="UTF-8" fieldsTerminatedBy="\t" linesTerminatedBy="\n" fieldsEnclosedBy="" ignoreHeaderLines="1" rowType="{dwc}Occurrence">
+    <files><location>occurrence.txt</location></files>
+    <id index="0"/>
+{occurrence}
+  </core>
+  <extension encoding="UTF-8" fieldsTerminatedBy="\t" linesTerminatedBy="\n" fieldsEnclosedBy="" ignoreHeaderLines="1" rowType="{dwc}MeasurementOrFact">
+    <files><location>measurementorfact.txt</location></files>
+    <coreid index="0"/>
+{measurements}
+  </extension>
+</archive>
+"#,
+        dwc = DWC,
+        occurrence = fields(&OCCURRENCE_TERMS, 0),
+        measurements = fields(&MEASUREMENT_TERMS, 1),
+    )
+}
+
+fn eml_xml(options: &DwcaOptions) -> String {
+    fn escape(i: &str) -> String {
+        i.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<eml:eml xmlns:eml="eml://ecoinformatics.org/eml-2.1.1" packageId="{dataset}-{start}-{end}" system="birbs" xml:lang="en">
+  <dataset>
+    <title>{title}</title>
+    <creator><organizationName>{creator}</organizationName></creator>
+    <pubDate>{published}</pubDate>
+    <abstract><para>Automated BirdNET detections from {start} to {end} with a minimum confidence of {confidence:.2}.</para></abstract>
+    <coverage>
+      <temporalCoverage>
+        <rangeOfDates>
+          <beginDate><calendarDate>{start}</calendarDate></beginDate>
+          <endDate><calendarDate>{end}</calendarDate></endDate>
+        </rangeOfDates>
+      </temporalCoverage>
+    </coverage>
+  </dataset>
+</eml:eml>
+"#,
+        dataset = escape(&options.dataset_id),
+        title = escape(&options.title),
+        creator = escape(&options.creator),
+        published = chrono::Utc::now().format("%Y-%m-%d"),
+        start = options.start,
+        end = options.end,
+        confidence = options.min_confidence,
+    )
+}
+
+pub fn write_dwca<W: Write + Seek>(db: &BirdDb, options: &DwcaOptions, w: W) -> Result<W> {
+    let detections = db.detections_between(options.start, options.end, options.min_confidence)?;
+
+    let mut occurrences = tab_writer(Vec::new());
+    let mut measurements = tab_writer(Vec::new());
+
+    occurrences.write_record(OCCURRENCE_TERMS)?;
+    measurements.write_record(std::iter::once("coreid").chain(MEASUREMENT_TERMS))?;
+
+    for d in detections.iter() {
+        let occurrence_id = unquoted(&format!("{}:{}", options.dataset_id, d.file_name));
+
+        occurrences.serialize(Occurrence {
+            occurrence_id: &occurrence_id,
+            basis_of_record: "MachineObservation",
+            occurrence_status: "present",
+            event_date: BirdDateAndTime::from_utc(d.when).local.to_rfc3339(),
+            scientific_name: unquoted(&d.scientific_name),
+            vernacular_name: unquoted(&d.common_name),
+            decimal_latitude: d.latitude,
+            decimal_longitude: d.longitude,
+            geodetic_datum: "WGS84",
+            identified_by: "BirdNET",
+        })?;
+
+        for (measurement_type, measurement_value) in [
+            ("confidence", d.confidence),
+            ("cutoff", d.cutoff),
+            ("sens", d.sens),
+            ("overlap", d.overlap),
+        ] {
+            measurements.serialize(MeasurementOrFact {
+                core_id: &occurrence_id,
+                measurement_type,
+                measurement_value,
+                measurement_method: "BirdNET",
+            })?;
+        }
+    }
+
+    let mut zip = zip::ZipWriter::new(w);
+    let file_options = SimpleFileOptions::default();
+
+    zip.start_file("occurrence.txt", file_options)?;
+    zip.write_all(&occurrences.into_inner()?)?;
+    zip.start_file("measurementorfact.txt", file_options)?;
+    zip.write_all(&measurements.into_inner()?)?;
+    zip.start_file("meta.xml", file_options)?;
+    zip.write_all(meta_xml().as_bytes())?;
+    zip.start_file("eml.xml", file_options)?;
+    zip.write_all(eml_xml(options).as_bytes())?;
+
+    Ok(zip.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::tests::{db, detection};
+
+    #[test]
+    fn writes_a_checklist_per_interval() {
+        let at = |hour, minute, scientific_name, common_name, confidence| {
+            let mut detection = detection(
+                Utc.with_ymd_and_hms(2024, 5, 1, hour, minute, 0).unwrap(),
+                scientific_name,
+                common_name,
+            );
+            detection.confidence = confidence;
+            detection
+        };
+        let db = db(&[
+            at(14, 0, "Turdus migratorius", "American Robin", 0.8),
+            at(14, 5, "Turdus migratorius", "American Robin", 0.95),
+            at(14, 20, "Corvus brachyrhynchos", "American Crow", 0.9),
+            at(14, 30, "Turdus migratorius", "American Robin", 0.75),
+            at(14, 40, "Passer domesticus", "House Sparrow", 0.9),
+            at(14, 50, "Cyanocitta stelleri", "Steller's Jay", 0.5),
+            at(15, 10, "Turdus migratorius", "American Robin", 0.85),
+        ]);
+        let date = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        let options = EbirdOptions {
+            start: date,
+            end: date,
+            interval: Some(60),
+            gap: 10,
+            min_confidence: 0.7,
+            exclude: vec!["passer domesticus".into()],
+            location: "Backyard".into(),
+            state: "WA".into(),
+            country: "US".into(),
+            output: None,
+        };
+
+        let mut written = Vec::new();
+        write_ebird(&db, &options, &mut written).unwrap();
+
+        let checklist = "47.6,-122.3,05/01/2024";
+        let comments = "\"Automated BirdNET recording, minimum confidence 0.70\"";
+        assert_eq!(
+            String::from_utf8(written).unwrap().lines().collect::<Vec<_>>(),
+            vec![
+                format!("American Crow,Corvus,brachyrhynchos,1,\"1 BirdNET detections in 1 encounters, peak confidence 0.90\",Backyard,{},07:00,WA,US,Stationary,1,60,N,,,{}", checklist, comments),
+                format!("American Robin,Turdus,migratorius,1,\"3 BirdNET detections in 2 encounters, peak confidence 0.95\",Backyard,{},07:00,WA,US,Stationary,1,60,N,,,{}", checklist, comments),
+                format!("American Robin,Turdus,migratorius,1,\"1 BirdNET detections in 1 encounters, peak confidence 0.85\",Backyard,{},08:00,WA,US,Stationary,1,60,N,,,{}", checklist, comments),
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_the_most_detections_at_once() {
+        let when = |minute| Utc.with_ymd_and_hms(2024, 5, 1, 14, minute, 0).unwrap();
+        let robins = [
+            detection(when(0), "Turdus migratorius", "American Robin"),
+            detection(when(0), "Turdus migratorius", "American Robin"),
+            detection(when(1), "Turdus migratorius", "American Robin"),
+            detection(when(30), "Turdus migratorius", "American Robin"),
+        ];
+        let robins = robins.iter().collect::<Vec<_>>();
+
+        assert_eq!(most_at_once(&robins), 2);
+        assert_eq!(encounters(robins.iter().copied(), 10).len(), 2);
+        assert_eq!(most_at_once(&[]), 0);
+    }
+
+    #[test]
+    fn writes_a_darwin_core_archive() {
+        let at = |minute, scientific_name, common_name, file_name: &str| {
+            let mut detection = detection(
+                Utc.with_ymd_and_hms(2024, 5, 1, 14, minute, 0).unwrap(),
+                scientific_name,
+                common_name,
+            );
+            detection.file_name = file_name.to_owned();
+            detection
+        };
+        let db = db(&[
+            at(0, "Turdus migratorius", "American Robin", "robin.mp3"),
+            at(5, "Cyanocitta stelleri", "Steller's\tJay\n", "jay.mp3"),
+        ]);
+        let date = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        let options = DwcaOptions {
+            start: date,
+            end: date,
+            min_confidence: 0.7,
+            title: "Backyard".into(),
+            creator: "Us".into(),
+            dataset_id: "backyard".into(),
+            output: String::new(),
+        };
+
+        let archive = write_dwca(&db, &options, std::io::Cursor::new(Vec::new())).unwrap();
+        let mut archive = zip::ZipArchive::new(archive).unwrap();
+        let mut read = |name| {
+            let mut contents = String::new();
+            std::io::Read::read_to_string(&mut archive.by_name(name).unwrap(), &mut contents)
+                .unwrap();
+            contents
+        };
+        let (occurrences, measurements, meta) = (
+            read("occurrence.txt"),
+            read("measurementorfact.txt"),
+            read("meta.xml"),
+        );
+
+        let core = meta.split("</core>").next().unwrap();
+        let fields = core
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("<field index=\""))
+            .map(|field| {
+                let (index, term) = field.split_once("\" term=\"").unwrap();
+                (
+                    index.parse::<usize>().unwrap(),
+                    term.trim_end_matches("\"/>").trim_start_matches(DWC),
+                )
+            })
+            .collect::<Vec<_>>();
+        let header = occurrences.lines().next().unwrap().split('\t');
+        assert_eq!(fields, header.enumerate().collect::<Vec<_>>());
+
+        let rows = occurrences
+            .lines()
+            .skip(1)
+            .map(|line| line.split('\t').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(|row| row.len() == OCCURRENCE_TERMS.len()));
+        assert_eq!(rows[0][0], "backyard:robin.mp3");
+        assert_eq!(rows[1][5], "Steller's Jay ");
+
+        for row in rows {
+            let facts = measurements
+                .lines()
+                .skip(1)
+                .filter(|line| line.split('\t').next() == Some(row[0]))
+                .count();
+            assert_eq!(facts, 4);
+        }
+    }
+}
@@ -1,7 +1,11 @@
 use anyhow::Result;
+use futures::future::BoxFuture;
 use reqwest_middleware::ClientWithMiddleware;
 use serde::Deserialize;
 
+use crate::metrics;
+use crate::providers::{Image, ProviderPhoto, SpeciesImageProvider};
+
 #[derive(Deserialize, Debug)]
 pub struct PhotosPayload {
     pub photos: Photos,
@@ -12,6 +16,67 @@ pub struct Photos {
     pub photo: Vec<SimplePhoto>,
 }
 
+#[derive(Deserialize, Debug)]
+struct InfoPayload {
+    photo: PhotoInfo,
+}
+
+#[derive(Deserialize, Debug)]
+struct Content {
+    #[serde(rename = "_content")]
+    content: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct InfoOwner {
+    nsid: String,
+    username: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct InfoTags {
+    tag: Vec<Content>,
+}
+
+/// What `flickr.photos.getInfo` returns, which is shaped differently from
+/// search results for no good reason.
+#[derive(Deserialize, Debug)]
+struct PhotoInfo {
+    id: String,
+    secret: String,
+    server: String,
+    farm: u64,
+    license: Option<String>,
+    owner: InfoOwner,
+    title: Content,
+    tags: InfoTags,
+}
+
+impl From<PhotoInfo> for SimplePhoto {
+    fn from(info: PhotoInfo) -> Self {
+        Self {
+            id: info.id,
+            owner: info.owner.nsid,
+            title: info.title.content,
+            farm: info.farm,
+            server: info.server,
+            secret: info.secret,
+            license: info.license,
+            ownername: Some(info.owner.username),
+            tags: info
+                .tags
+                .tag
+                .into_iter()
+                .map(|t| t.content)
+                .collect::<Vec<_>>()
+                .join(" "),
+            machine_tags: String::new(),
+            url_l: None,
+            url_c: None,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct SimplePhoto {
     pub id: String,
@@ -20,11 +85,178 @@ pub struct SimplePhoto {
     pub farm: u64,
     pub server: String,
     pub secret: String,
+    #[serde(default)]
+    pub license: Option<String>,
+    #[serde(default)]
+    pub ownername: Option<String>,
+    /// Space separated, lower cased with spaces and punctuation removed.
+    #[serde(default)]
+    pub tags: String,
+    #[serde(default)]
+    pub machine_tags: String,
+    /// 1024 on the longest side.
+    #[serde(default)]
+    pub url_l: Option<String>,
+    /// 800 on the longest side.
+    #[serde(default)]
+    pub url_c: Option<String>,
+}
+
+impl SimplePhoto {
+    /// The largest size Flickr offered, falling back to the 500px default.
+    pub fn image_url(&self) -> String {
+        self.url_l
+            .clone()
+            .or_else(|| self.url_c.clone())
+            .unwrap_or_else(|| {
+                format!(
+                    "https://farm{}.static.flickr.com/{}/{}_{}.jpg",
+                    self.farm, self.server, self.id, self.secret
+                )
+            })
+    }
+
+    /// Higher for photos tagged as the species we're after, and for larger ones.
+    fn score(&self, scientific_name: Option<&str>, common_name: &str) -> u32 {
+        let tags = self.tags.split_whitespace().collect::<Vec<_>>();
+        let mut score = 0;
+
+        if let Some(scientific_name) = scientific_name {
+            let binomial = format!("taxonomy:binomial={}", normalize_tag(scientific_name));
+            if self.machine_tags.split_whitespace().any(|t| t == binomial) {
+                score += 4;
+            }
+            if tags.contains(&normalize_tag(scientific_name).as_str()) {
+                score += 2;
+            }
+        }
+        if tags.contains(&normalize_tag(common_name).as_str()) {
+            score += 2;
+        }
+        if tags.iter().any(|t| *t == "bird" || *t == "birds") {
+            score += 1;
+        }
+        if self.url_l.is_some() {
+            score += 2;
+        } else if self.url_c.is_some() {
+            score += 1;
+        }
+
+        score
+    }
+
+    /// The photo's page on Flickr, where attribution should link to.
+    pub fn page_url(&self) -> String {
+        format!("https://www.flickr.com/photos/{}/{}", self.owner, self.id)
+    }
+}
+
+/// Flickr's normalized form of a tag, `"Corvus brachyrhynchos"` becomes `corvusbrachyrhynchos`.
+fn normalize_tag(tag: &str) -> String {
+    tag.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+/// Creative Commons licenses, including CC0 and the public domain mark.
+const CREATIVE_COMMONS: &str = "1,2,3,4,5,6,9,10,11,12,13,14,15,16";
+
+/// Name and deed for one of Flickr's license ids, see `flickr.photos.licenses.getInfo`.
+pub fn license(id: &str) -> Option<(&'static str, &'static str)> {
+    Some(match id {
+        "0" => ("All Rights Reserved", ""),
+        "1" => (
+            "CC BY-NC-SA 2.0",
+            "https://creativecommons.org/licenses/by-nc-sa/2.0/",
+        ),
+        "2" => (
+            "CC BY-NC 2.0",
+            "https://creativecommons.org/licenses/by-nc/2.0/",
+        ),
+        "3" => (
+            "CC BY-NC-ND 2.0",
+            "https://creativecommons.org/licenses/by-nc-nd/2.0/",
+        ),
+        "4" => ("CC BY 2.0", "https://creativecommons.org/licenses/by/2.0/"),
+        "5" => (
+            "CC BY-SA 2.0",
+            "https://creativecommons.org/licenses/by-sa/2.0/",
+        ),
+        "6" => (
+            "CC BY-ND 2.0",
+            "https://creativecommons.org/licenses/by-nd/2.0/",
+        ),
+        "7" => (
+            "No known copyright restrictions",
+            "https://www.flickr.com/commons/usage/",
+        ),
+        "8" => (
+            "United States Government Work",
+            "http://www.usa.gov/copyright.shtml",
+        ),
+        "9" => (
+            "CC0 1.0",
+            "https://creativecommons.org/publicdomain/zero/1.0/",
+        ),
+        "10" => (
+            "Public Domain Mark 1.0",
+            "https://creativecommons.org/publicdomain/mark/1.0/",
+        ),
+        "11" => ("CC BY 4.0", "https://creativecommons.org/licenses/by/4.0/"),
+        "12" => (
+            "CC BY-SA 4.0",
+            "https://creativecommons.org/licenses/by-sa/4.0/",
+        ),
+        "13" => (
+            "CC BY-ND 4.0",
+            "https://creativecommons.org/licenses/by-nd/4.0/",
+        ),
+        "14" => (
+            "CC BY-NC 4.0",
+            "https://creativecommons.org/licenses/by-nc/4.0/",
+        ),
+        "15" => (
+            "CC BY-NC-SA 4.0",
+            "https://creativecommons.org/licenses/by-nc-sa/4.0/",
+        ),
+        "16" => (
+            "CC BY-NC-ND 4.0",
+            "https://creativecommons.org/licenses/by-nc-nd/4.0/",
+        ),
+        _ => return None,
+    })
+}
+
+fn get_flickr_api_url() -> String {
+    std::env::var("FLICKR_API_URL")
+        .unwrap_or_else(|_| "https://www.flickr.com/services/rest/".into())
+}
+
+impl From<SimplePhoto> for ProviderPhoto {
+    fn from(photo: SimplePhoto) -> Self {
+        let license = photo.license.as_deref().and_then(license);
+
+        Self {
+            source: "flickr".into(),
+            source_url: photo.page_url(),
+            image_url: photo.image_url(),
+            id: photo.id,
+            title: photo.title,
+            owner: photo.owner,
+            owner_name: photo.ownername,
+            license: license.map(|(name, _)| name.to_owned()),
+            license_url: license
+                .map(|(_, url)| url.to_owned())
+                .filter(|url| !url.is_empty()),
+        }
+    }
 }
 
 pub struct FlickrClient {
     http: ClientWithMiddleware,
     api_key: String,
+    base_url: String,
 }
 
 impl FlickrClient {
@@ -32,25 +264,266 @@ impl FlickrClient {
         Self {
             http,
             api_key: api_key.into(),
+            base_url: get_flickr_api_url(),
         }
     }
 
-    pub async fn search(&self, query: &str) -> Result<Vec<SimplePhoto>> {
-        let url = format!("https://www.flickr.com/services/rest/?method=flickr.photos.search&api_key={}&text={}&sort=relevance&per_page=10&media=photos&format=json&nojsoncallback=1", self.api_key, query);
-        let response = self.http.get(url).send().await?;
+    /// Creative Commons photos matching `query`, most relevant first.
+    pub async fn search_text(&self, query: &str) -> Result<Vec<SimplePhoto>> {
+        let response = self
+            .http
+            .get(&self.base_url)
+            .query(&[
+                ("method", "flickr.photos.search"),
+                ("api_key", &self.api_key),
+                ("text", query),
+                ("license", CREATIVE_COMMONS),
+                ("extras", "license,owner_name,tags,machine_tags,url_l,url_c"),
+                ("sort", "relevance"),
+                ("per_page", "50"),
+                ("media", "photos"),
+                ("content_type", "1"),
+                ("safe_search", "1"),
+                ("format", "json"),
+                ("nojsoncallback", "1"),
+            ])
+            .send()
+            .await?;
+        metrics::flickr_response(&response);
         let payload = response.json::<PhotosPayload>().await?;
 
         Ok(payload.photos.photo)
     }
 
-    pub async fn image(&self, photo: &SimplePhoto) -> Result<Vec<u8>> {
-        let url = format!(
-            "https://farm{}.static.flickr.com/{}/{}_{}.jpg",
-            photo.farm, photo.server, photo.id, photo.secret
+    /// Photos of a species, best first. Searches by scientific name, which is
+    /// far less ambiguous, and by common name when that finds nothing.
+    pub async fn search_species(
+        &self,
+        scientific_name: Option<&str>,
+        common_name: &str,
+    ) -> Result<Vec<SimplePhoto>> {
+        let mut photos = match scientific_name {
+            Some(scientific_name) => self.search_text(scientific_name).await?,
+            None => Vec::new(),
+        };
+        if photos.is_empty() {
+            photos = self.search_text(common_name).await?;
+        }
+
+        // Stable, so relevance still decides between equally scored photos.
+        photos.sort_by_key(|p| std::cmp::Reverse(p.score(scientific_name, common_name)));
+
+        Ok(photos)
+    }
+
+    /// A photo by id, regardless of its license.
+    pub async fn info(&self, id: &str) -> Result<SimplePhoto> {
+        let response = self
+            .http
+            .get(&self.base_url)
+            .query(&[
+                ("method", "flickr.photos.getInfo"),
+                ("api_key", &self.api_key),
+                ("photo_id", id),
+                ("format", "json"),
+                ("nojsoncallback", "1"),
+            ])
+            .send()
+            .await?;
+        metrics::flickr_response(&response);
+        let payload = response.json::<InfoPayload>().await?;
+
+        Ok(payload.photo.into())
+    }
+}
+
+impl SpeciesImageProvider for FlickrClient {
+    fn name(&self) -> &'static str {
+        "flickr"
+    }
+
+    fn search<'a>(
+        &'a self,
+        scientific_name: Option<&'a str>,
+        common_name: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<ProviderPhoto>>> {
+        Box::pin(async move {
+            Ok(self
+                .search_species(scientific_name, common_name)
+                .await?
+                .into_iter()
+                .map(ProviderPhoto::from)
+                .collect())
+        })
+    }
+
+    fn photo<'a>(
+        &'a self,
+        _scientific_name: Option<&'a str>,
+        _common_name: &'a str,
+        id: &'a str,
+    ) -> BoxFuture<'a, Result<ProviderPhoto>> {
+        Box::pin(async move { Ok(self.info(id).await?.into()) })
+    }
+
+    fn image<'a>(&'a self, photo: &'a ProviderPhoto) -> BoxFuture<'a, Result<Image>> {
+        Box::pin(async move {
+            let response = self.http.get(&photo.image_url).send().await?;
+            metrics::flickr_response(&response);
+
+            Ok(Image {
+                content_type: "image/jpeg".into(),
+                bytes: response.error_for_status()?.bytes().await?.into(),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::Query;
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+    use axum::Router;
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::providers::testing::{http, json, serve};
+
+    fn photo(id: &str, tags: &str, machine_tags: &str, url_l: Option<&str>) -> SimplePhoto {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "owner": "owner",
+            "title": "title",
+            "farm": 1,
+            "server": "1",
+            "secret": "secret",
+            "tags": tags,
+            "machine_tags": machine_tags,
+            "url_l": url_l,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn scores_tagged_and_larger_photos_higher() {
+        let untagged = photo("1", "sunset", "", None);
+        let named = photo("2", "americancrow bird", "", None);
+        let binomial = photo(
+            "3",
+            "corvusbrachyrhynchos",
+            "taxonomy:binomial=corvusbrachyrhynchos",
+            None,
+        );
+        let large = photo("4", "sunset", "", Some("https://example.com/4_l.jpg"));
+
+        let score = |p: &SimplePhoto| p.score(Some("Corvus brachyrhynchos"), "American Crow");
+        assert_eq!(score(&untagged), 0);
+        assert_eq!(score(&named), 3);
+        assert_eq!(score(&binomial), 6);
+        assert_eq!(score(&large), 2);
+        assert_eq!(large.image_url(), "https://example.com/4_l.jpg");
+        assert_eq!(
+            untagged.image_url(),
+            "https://farm1.static.flickr.com/1/1_secret.jpg"
+        );
+        assert_eq!(
+            normalize_tag("Corvus brachyrhynchos"),
+            "corvusbrachyrhynchos"
+        );
+    }
+
+    async fn flickr() -> FlickrClient {
+        let router = Router::new().route(
+            "/",
+            get(|Query(query): Query<HashMap<String, String>>| async move {
+                let get = |key: &str| query.get(key).map(|v| v.as_str());
+                if get("method") != Some("flickr.photos.search")
+                    || get("license") != Some(CREATIVE_COMMONS)
+                {
+                    return StatusCode::BAD_REQUEST.into_response();
+                }
+
+                match get("text") {
+                    Some("Corvus brachyrhynchos" | "American Crow") => {
+                        json(include_str!("../tests/fixtures/flickr-search.json")).into_response()
+                    }
+                    _ => json(r#"{"photos":{"page":1,"pages":0,"perpage":50,"total":0,"photo":[]},"stat":"ok"}"#)
+                        .into_response(),
+                }
+            }),
         );
 
-        let response = self.http.get(url).send().await?;
+        FlickrClient {
+            http: http(),
+            api_key: "key".into(),
+            base_url: serve(router).await,
+        }
+    }
+
+    #[tokio::test]
+    async fn searches_creative_commons_photos_best_first() {
+        let flickr = flickr().await;
+
+        let photos = flickr
+            .search(Some("Corvus brachyrhynchos"), "American Crow")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            photos.iter().map(|p| p.id.as_str()).collect::<Vec<_>>(),
+            vec!["7234567890", "8345678901", "5123456789"]
+        );
+        assert_eq!(photos[0].license.as_deref(), Some("CC BY 2.0"));
+        assert_eq!(
+            photos[0].image_url,
+            "https://live.staticflickr.com/7012/7234567890_0123abcdef_b.jpg"
+        );
+        assert_eq!(
+            photos[0].source_url,
+            "https://www.flickr.com/photos/87654321@N02/7234567890"
+        );
+        assert_eq!(photos[1].license.as_deref(), Some("CC0 1.0"));
+        assert_eq!(
+            photos[2].image_url,
+            "https://farm5.static.flickr.com/4107/5123456789_abcdef1234.jpg"
+        );
+    }
+
+    #[tokio::test]
+    async fn searches_common_names_when_scientific_names_find_nothing() {
+        let flickr = flickr().await;
+
+        let photos = flickr
+            .search(Some("Corvus renamedus"), "American Crow")
+            .await
+            .unwrap();
+        assert_eq!(photos.len(), 3);
+
+        let photos = flickr.search(None, "Snark").await.unwrap();
+        assert!(photos.is_empty());
+    }
+
+    #[test]
+    fn reserved_photos_have_no_deed() {
+        let photo = ProviderPhoto::from(SimplePhoto {
+            id: "1".into(),
+            owner: "owner".into(),
+            title: "title".into(),
+            farm: 1,
+            server: "1".into(),
+            secret: "s".into(),
+            license: Some("0".into()),
+            ownername: None,
+            tags: String::new(),
+            machine_tags: String::new(),
+            url_l: None,
+            url_c: None,
+        });
 
-        Ok(response.bytes().await?.into())
+        assert_eq!(photo.license.as_deref(), Some("All Rights Reserved"));
+        assert_eq!(photo.license_url, None);
+        assert!(license("17").is_none());
     }
 }
@@ -0,0 +1,311 @@
+use anyhow::Result;
+use axum::extract::Query;
+use axum::{http::StatusCode, Json};
+use chrono::{DateTime, Days, Duration, NaiveDate, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
+
+use crate::publish::Checkpoint;
+use crate::review::ReviewFilter;
+use crate::{alerts, get_database, store, BirdDateAndTime, BirdDb};
+
+/// Days of history the last hour's activity is compared against.
+const TYPICAL_DAYS: u64 = 14;
+
+/// Walking a large recordings directory is slow, so its size is only
+/// recomputed this often.
+const DIRECTORY_SIZE_TTL: std::time::Duration = std::time::Duration::from_secs(300);
+
+static DIRECTORY_SIZES: LazyLock<Mutex<HashMap<PathBuf, (Instant, u64)>>> =
+    LazyLock::new(Default::default);
+
+/// Where BirdNET-Pi keeps extracted recordings, when disk usage should be reported.
+pub fn get_recordings_directory() -> Option<PathBuf> {
+    std::env::var("BIRDNET_RECORDINGS").ok().map(PathBuf::from)
+}
+
+#[axum_macros::debug_handler]
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    name: &'static str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<()>) -> Self {
+        Self {
+            name,
+            ok: result.is_ok(),
+            error: result.err().map(|e| format!("{:#}", e)),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    ready: bool,
+    checks: Vec<Check>,
+}
+
+fn readiness() -> Readiness {
+    let checks = vec![
+        Check::new(
+            "database",
+            BirdDb::new().and_then(|db| db.newest_detection().map(|_| ())),
+        ),
+        Check::new(
+            "alerts",
+            alerts::AlertsConfig::load_if_present(&alerts::get_alerts_config()).map(|_| ()),
+        ),
+        Check::new(
+            "recordings",
+            match get_recordings_directory() {
+                Some(path) if !path.is_dir() => Err(anyhow::anyhow!("{:?} is missing", path)),
+                _ => Ok(()),
+            },
+        ),
+    ];
+
+    Readiness {
+        ready: checks.iter().all(|c| c.ok),
+        checks,
+    }
+}
+
+#[axum_macros::debug_handler]
+pub async fn readyz() -> Result<(StatusCode, Json<Readiness>), StatusCode> {
+    let readiness = tokio::task::spawn_blocking(readiness)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Ok((status, Json(readiness)))
+}
+
+#[derive(Serialize, Debug)]
+pub struct DatabaseStatus {
+    path: String,
+    modified: DateTime<Utc>,
+    /// Seconds since BirdNET-Pi last wrote to its database.
+    age_seconds: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ActivityStatus {
+    /// Detections in the last hour.
+    detections: usize,
+    /// Average detections in the same hour of the day over the previous days.
+    typical: f32,
+    /// Previous days averaged over, fewer than usual while history is short.
+    days: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RecordingsStatus {
+    path: PathBuf,
+    used_bytes: u64,
+    available_bytes: u64,
+    total_bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PublisherStatus {
+    lines: u64,
+    last_entry: Option<DateTime<Utc>>,
+    /// Seconds since the newest published detection.
+    lag_seconds: Option<i64>,
+    updated: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Status {
+    station: String,
+    database: Option<DatabaseStatus>,
+    newest_detection: Option<DateTime<Utc>>,
+    last_hour: ActivityStatus,
+    recordings: Option<RecordingsStatus>,
+    publisher: Option<PublisherStatus>,
+}
+
+fn database_status(now: DateTime<Utc>) -> Result<DatabaseStatus> {
+    let path = get_database()?;
+    let modified: DateTime<Utc> = std::fs::metadata(&path)?.modified()?.into();
+
+    Ok(DatabaseStatus {
+        path,
+        modified,
+        age_seconds: (now - modified).num_seconds(),
+    })
+}
+
+fn activity(db: &BirdDb, now: DateTime<Utc>) -> Result<ActivityStatus> {
+    let hour_ago = now - Duration::hours(1);
+    let today = BirdDateAndTime::from_utc(now).local.date_naive();
+    let start = today
+        .checked_sub_days(Days::new(TYPICAL_DAYS + 1))
+        .unwrap_or(today);
+
+    let detections = db.detections_between(start, today, 0.0)?;
+    let in_window = |days: u64| {
+        let offset = Duration::days(days as i64);
+        detections
+            .iter()
+            .filter(|d| d.when > hour_ago - offset && d.when <= now - offset)
+            .count()
+    };
+
+    // Days before the first detection aren't quiet ones.
+    let first_day = db
+        .oldest_date()?
+        .and_then(|date| date.parse::<NaiveDate>().ok());
+    let days = (1..=TYPICAL_DAYS)
+        .filter(|n| {
+            today
+                .checked_sub_days(Days::new(*n))
+                .zip(first_day)
+                .is_some_and(|(day, first)| day >= first)
+        })
+        .collect::<Vec<_>>();
+    let previous = days.iter().copied().map(in_window).sum::<usize>();
+
+    Ok(ActivityStatus {
+        detections: in_window(0),
+        typical: previous as f32 / days.len().max(1) as f32,
+        days: days.len() as u64,
+    })
+}
+
+fn directory_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            directory_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+
+    Ok(size)
+}
+
+fn cached_directory_size(path: &Path) -> Result<u64> {
+    let mut sizes = DIRECTORY_SIZES.lock().expect("directory sizes lock");
+    if let Some((at, size)) = sizes.get(path) {
+        if at.elapsed() < DIRECTORY_SIZE_TTL {
+            return Ok(*size);
+        }
+    }
+
+    let size = directory_size(path)?;
+    sizes.insert(path.to_owned(), (Instant::now(), size));
+
+    Ok(size)
+}
+
+fn recordings_status(path: PathBuf) -> Result<RecordingsStatus> {
+    Ok(RecordingsStatus {
+        used_bytes: cached_directory_size(&path)?,
+        available_bytes: fs2::available_space(&path)?,
+        total_bytes: fs2::total_space(&path)?,
+        path,
+    })
+}
+
+fn status(reviews: ReviewFilter) -> Result<Status> {
+    let now = Utc::now();
+    let db = reviews.db()?;
+
+    Ok(Status {
+        station: store::get_station(),
+        database: database_status(now).ok(),
+        newest_detection: db.newest_detection()?,
+        last_hour: activity(&db, now)?,
+        recordings: get_recordings_directory()
+            .map(recordings_status)
+            .transpose()?,
+        publisher: Checkpoint::load()?.map(|c| PublisherStatus {
+            lines: c.lines,
+            last_entry: c.last_entry,
+            lag_seconds: c.last_entry.map(|l| (now - l).num_seconds()),
+            updated: c.updated,
+        }),
+    })
+}
+
+#[axum_macros::debug_handler]
+pub async fn status_json(Query(reviews): Query<ReviewFilter>) -> Result<Json<Status>, StatusCode> {
+    Ok(Json(
+        tokio::task::spawn_blocking(move || status(reviews))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::tests::{db, detection};
+
+    #[test]
+    fn averages_activity_over_days_with_history() {
+        let at = |day, hour, minute| Utc.with_ymd_and_hms(2024, 5, day, hour, minute, 0).unwrap();
+        let db = db(&[
+            detection(at(8, 14, 10), "Turdus migratorius", "American Robin"),
+            detection(at(9, 14, 0), "Turdus migratorius", "American Robin"),
+            detection(at(9, 14, 20), "Corvus brachyrhynchos", "American Crow"),
+            detection(at(9, 16, 0), "Corvus brachyrhynchos", "American Crow"),
+            detection(at(10, 13, 29), "Turdus migratorius", "American Robin"),
+            detection(at(10, 14, 0), "Turdus migratorius", "American Robin"),
+            detection(at(10, 14, 15), "Corvus brachyrhynchos", "American Crow"),
+        ]);
+
+        let activity = activity(&db, at(10, 14, 30)).unwrap();
+
+        assert_eq!(activity.detections, 2);
+        assert_eq!(activity.days, 2);
+        assert_eq!(activity.typical, 1.5);
+    }
+
+    #[test]
+    fn has_no_typical_activity_without_history() {
+        let activity = activity(&db(&[]), Utc::now()).unwrap();
+
+        assert_eq!(activity.detections, 0);
+        assert_eq!(activity.days, 0);
+        assert_eq!(activity.typical, 0.0);
+    }
+
+    #[test]
+    fn caches_directory_sizes() {
+        let path = std::env::temp_dir().join(format!("birbs-recordings-{}", std::process::id()));
+        std::fs::create_dir_all(path.join("2024-05-01")).unwrap();
+        std::fs::write(path.join("2024-05-01").join("a.mp3"), b"abc").unwrap();
+
+        assert_eq!(cached_directory_size(&path).unwrap(), 3);
+
+        std::fs::write(path.join("b.mp3"), b"def").unwrap();
+        assert_eq!(cached_directory_size(&path).unwrap(), 3);
+        assert_eq!(directory_size(&path).unwrap(), 6);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
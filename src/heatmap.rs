@@ -0,0 +1,197 @@
+use anyhow::Result;
+use axum::extract::Query;
+use axum::{http::StatusCode, Json};
+use chrono::{NaiveDate, NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::review::ReviewFilter;
+use crate::species::Species;
+use crate::{week_of_year, BirdDateAndTime, BirdDb};
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Bin {
+    /// Each date by hour of the day.
+    #[default]
+    Day,
+    /// Each week of the year by half hour, every year folded together.
+    Week,
+}
+
+impl Bin {
+    fn slot_minutes(&self) -> u32 {
+        match self {
+            Bin::Day => 60,
+            Bin::Week => 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HeatmapQuery {
+    #[serde(default)]
+    bin: Bin,
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+    min_confidence: Option<f32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Cell {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date: Option<NaiveDate>,
+    /// Week of the year, the first starting January 1st.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    week: Option<u32>,
+    slot: u32,
+    /// Local time the slot starts.
+    time: NaiveTime,
+    detections: u32,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Period {
+    Date(NaiveDate),
+    Week(u32),
+}
+
+/// Only cells with detections are included.
+#[derive(Serialize, Debug)]
+pub struct Heatmap {
+    bin: Bin,
+    slot_minutes: u32,
+    cells: Vec<Cell>,
+}
+
+fn heatmap(db: &BirdDb, scientific_name: Option<&str>, query: &HeatmapQuery) -> Result<Heatmap> {
+    let times = db.detection_times(
+        scientific_name,
+        query.start,
+        query.end,
+        query.min_confidence.unwrap_or(0.0),
+    )?;
+
+    let slot_minutes = query.bin.slot_minutes();
+    let mut counts: BTreeMap<(Period, u32), u32> = BTreeMap::new();
+    for BirdDateAndTime { local, .. } in times {
+        let period = match query.bin {
+            Bin::Day => Period::Date(local.date_naive()),
+            Bin::Week => Period::Week(week_of_year(local)),
+        };
+        let slot = (local.hour() * 60 + local.minute()) / slot_minutes;
+        *counts.entry((period, slot)).or_default() += 1;
+    }
+
+    Ok(Heatmap {
+        bin: query.bin,
+        slot_minutes,
+        cells: counts
+            .into_iter()
+            .map(|((period, slot), detections)| Cell {
+                date: match period {
+                    Period::Date(date) => Some(date),
+                    Period::Week(_) => None,
+                },
+                week: match period {
+                    Period::Week(week) => Some(week),
+                    Period::Date(_) => None,
+                },
+                slot,
+                time: NaiveTime::from_num_seconds_from_midnight_opt(slot * slot_minutes * 60, 0)
+                    .expect("slot within a day"),
+                detections,
+            })
+            .collect(),
+    })
+}
+
+#[axum_macros::debug_handler]
+pub async fn heatmap_json(
+    Query(query): Query<HeatmapQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<Json<Heatmap>, StatusCode> {
+    Ok(Json(
+        tokio::task::spawn_blocking(move || heatmap(&reviews.db()?, None, &query))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    ))
+}
+
+#[axum_macros::debug_handler]
+pub async fn species_heatmap_json(
+    Species {
+        scientific_name, ..
+    }: Species,
+    Query(query): Query<HeatmapQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<Json<Heatmap>, StatusCode> {
+    Ok(Json(
+        tokio::task::spawn_blocking(move || {
+            heatmap(&reviews.db()?, Some(&scientific_name), &query)
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::tests::{db, detection};
+
+    fn query(bin: Bin) -> HeatmapQuery {
+        HeatmapQuery {
+            bin,
+            start: None,
+            end: None,
+            min_confidence: None,
+        }
+    }
+
+    fn cells(heatmap: Heatmap) -> Vec<(Option<NaiveDate>, Option<u32>, u32, u32)> {
+        heatmap
+            .cells
+            .into_iter()
+            .map(|c| (c.date, c.week, c.slot, c.detections))
+            .collect()
+    }
+
+    #[test]
+    fn bins_by_local_time_across_daylight_saving() {
+        let at = |h, m| Utc.with_ymd_and_hms(2024, 3, 10, h, m, 0).unwrap();
+        // Clocks went forward at 2am on the 10th, there's no 2 o'clock that day.
+        let db = db(&[
+            detection(at(7, 30), "Corvus brachyrhynchos", "American Crow"),
+            detection(at(9, 30), "Corvus brachyrhynchos", "American Crow"),
+            detection(at(10, 30), "Corvus brachyrhynchos", "American Crow"),
+            detection(at(10, 45), "Corvus brachyrhynchos", "American Crow"),
+            detection(at(10, 45), "Turdus migratorius", "American Robin"),
+        ]);
+        let date = |d| NaiveDate::from_ymd_opt(2024, 3, d);
+
+        assert_eq!(
+            cells(heatmap(&db, None, &query(Bin::Day)).unwrap()),
+            vec![
+                (date(9), None, 23, 1),
+                (date(10), None, 1, 1),
+                (date(10), None, 3, 3),
+            ]
+        );
+
+        let species = heatmap(&db, Some("Corvus brachyrhynchos"), &query(Bin::Week)).unwrap();
+        assert_eq!(species.slot_minutes, 30);
+        assert_eq!(
+            cells(species),
+            vec![
+                (None, Some(10), 3, 1),
+                (None, Some(10), 7, 2),
+                (None, Some(10), 47, 1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,439 @@
+use anyhow::{anyhow, Result};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime};
+use clap::{Args, ValueEnum};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::store::Store;
+use crate::{urlify_string, BirdDateAndTime, BirdDb, Detection};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SourceFormat {
+    Auto,
+    /// BirdNET-Analyzer `--rtype csv` results.
+    Csv,
+    /// BirdNET-Analyzer Raven selection tables.
+    Table,
+    /// BirdNET-Pi `BirdDB.txt` style logs.
+    Log,
+}
+
+#[derive(Debug, Args)]
+pub struct Command {
+    /// Station the recordings were made at.
+    #[arg(short, long)]
+    station: String,
+    #[arg(long, value_enum, default_value_t = SourceFormat::Auto)]
+    format: SourceFormat,
+    /// Local time the recording started, otherwise taken from a YYYYMMDD_HHMMSS file name.
+    #[arg(long)]
+    start: Option<NaiveDateTime>,
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    lat: f32,
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    lon: f32,
+    #[arg(long, default_value_t = 0.1)]
+    min_confidence: f32,
+    #[arg(long, default_value_t = 1.0)]
+    sens: f32,
+    #[arg(long, default_value_t = 0.0)]
+    overlap: f32,
+    files: Vec<String>,
+}
+
+pub async fn execute(cmd: Command) -> Result<()> {
+    let mut store = Store::new()?;
+    let mut names = store.common_name_to_scientific_name()?;
+    if let Ok(db) = BirdDb::birdnet() {
+        names.extend(db.common_name_to_scientific_name()?);
+    }
+
+    for file in cmd.files.iter() {
+        let format = match cmd.format {
+            SourceFormat::Auto => detect_format(file)?,
+            format => format,
+        };
+
+        let detections = match format {
+            SourceFormat::Log => parse_log(file)?,
+            _ => parse_analyzer(&cmd, format, file, &names)?,
+        };
+
+        let inserted = store.insert_detections(&cmd.station, file, &detections)?;
+
+        info!(
+            "{}: {} detections, {} new, {} duplicates",
+            file,
+            detections.len(),
+            inserted,
+            detections.len() - inserted
+        );
+    }
+
+    Ok(())
+}
+
+fn detect_format(file: &str) -> Result<SourceFormat> {
+    let mut header = String::new();
+    BufReader::new(File::open(file)?).read_line(&mut header)?;
+
+    if header.starts_with("Selection\t") {
+        Ok(SourceFormat::Table)
+    } else if header.contains("Start (s)") {
+        Ok(SourceFormat::Csv)
+    } else if header.contains(';') {
+        Ok(SourceFormat::Log)
+    } else {
+        Err(anyhow!("unrecognized detections file: {}", file))
+    }
+}
+
+/// BirdNET's week of the year, four weeks to a month.
+fn birdnet_week(date: NaiveDate) -> u32 {
+    date.month0() * 4 + (date.day0() / 7).min(3) + 1
+}
+
+/// Finds the YYYYMMDD_HHMMSS timestamp most field recorders name their files with.
+fn recording_start(name: &str) -> Option<NaiveDateTime> {
+    let stem = Path::new(name).file_name()?.to_str()?;
+
+    (0..stem.len().saturating_sub(14)).find_map(|i| {
+        let candidate = stem.get(i..i + 15)?;
+        NaiveDateTime::parse_from_str(candidate, "%Y%m%d_%H%M%S").ok()
+    })
+}
+
+/// Length of the segments BirdNET analyzes, for results that leave out the end.
+const SEGMENT_SECONDS: f64 = 3.0;
+
+/// Names a detection after its species and segment of the recording, the way
+/// BirdNET-Pi names its clips, so every detection has a file name of its own.
+fn segment_file_name(common_name: &str, audio_file: &str, start: f64, end: f64) -> String {
+    let path = Path::new(audio_file);
+    let stem = path
+        .file_stem()
+        .and_then(|f| f.to_str())
+        .unwrap_or(audio_file);
+    let name = format!("{}-{}-{}-{}", urlify_string(common_name), stem, start, end);
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) => format!("{}.{}", name, extension),
+        None => name,
+    }
+}
+
+fn new_detection(
+    local: NaiveDateTime,
+    scientific_name: String,
+    common_name: String,
+    confidence: f32,
+    file_name: String,
+    cmd: &Command,
+) -> Result<Detection> {
+    let when = BirdDateAndTime::new_naive(local.date(), local.time())?;
+
+    Ok(Detection {
+        when: when.into(),
+        common_name,
+        scientific_name,
+        confidence,
+        latitude: cmd.lat,
+        longitude: cmd.lon,
+        cutoff: cmd.min_confidence,
+        week: birdnet_week(local.date()),
+        sens: cmd.sens,
+        overlap: cmd.overlap,
+        file_name,
+    })
+}
+
+fn parse_analyzer(
+    cmd: &Command,
+    format: SourceFormat,
+    file: &str,
+    names: &HashMap<String, String>,
+) -> Result<Vec<Detection>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(if format == SourceFormat::Table {
+            b'\t'
+        } else {
+            b','
+        })
+        .flexible(true)
+        .from_path(file)?;
+
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.trim().eq_ignore_ascii_case(name))
+    };
+    let required = |name: &str| column(name).ok_or_else(|| anyhow!("{}: no '{}'", file, name));
+
+    let (begin, end, audio, offset) = match format {
+        SourceFormat::Table => (
+            required("Begin Time (s)")?,
+            column("End Time (s)"),
+            column("Begin Path"),
+            column("File Offset (s)"),
+        ),
+        _ => (
+            required("Start (s)")?,
+            column("End (s)"),
+            column("File"),
+            None,
+        ),
+    };
+    let scientific = column("Scientific name");
+    let common = required("Common name")?;
+    let confidence = required("Confidence")?;
+
+    let mut detections = Vec::new();
+
+    for record in reader.records() {
+        let record = record?;
+        let field = |i: usize| record.get(i).unwrap_or_default().trim();
+
+        let confidence: f32 = field(confidence).parse()?;
+        if confidence < cmd.min_confidence {
+            continue;
+        }
+
+        let audio_file = audio.map(field).unwrap_or(file);
+        let Some(start) = cmd.start.or_else(|| recording_start(audio_file)) else {
+            return Err(anyhow!(
+                "{}: no recording start time, try --start",
+                audio_file
+            ));
+        };
+
+        let seconds: f64 = field(offset.unwrap_or(begin)).parse()?;
+        let length = match end {
+            Some(end) => field(end).parse::<f64>()? - field(begin).parse::<f64>()?,
+            None => SEGMENT_SECONDS,
+        };
+        let local = start + Duration::milliseconds((seconds * 1000.0).round() as i64);
+
+        let common_name = field(common).to_owned();
+        let scientific_name = match scientific.map(field) {
+            Some(name) => name.to_owned(),
+            None => match names.get(&common_name) {
+                Some(name) => name.clone(),
+                None => {
+                    warn!("{}: unknown species '{}', skipping", file, common_name);
+                    continue;
+                }
+            },
+        };
+
+        let file_name = segment_file_name(&common_name, audio_file, seconds, seconds + length);
+
+        detections.push(new_detection(
+            local,
+            scientific_name,
+            common_name,
+            confidence,
+            file_name,
+            cmd,
+        )?);
+    }
+
+    Ok(detections)
+}
+
+/// Reads `Date;Time;Sci_Name;Com_Name;Confidence;Lat;Lon;Cutoff;Week;Sens;Overlap[;File_Name]`
+/// lines, skipping the header and anything else that doesn't parse.
+fn parse_log(file: &str) -> Result<Vec<Detection>> {
+    fn parse_line(line: &str) -> Result<Detection> {
+        let fields = line.split(';').map(|f| f.trim()).collect::<Vec<_>>();
+        if fields.len() < 11 {
+            return Err(anyhow!("expected at least 11 fields"));
+        }
+
+        let date: NaiveDate = fields[0].parse()?;
+        let time: NaiveTime = fields[1].parse()?;
+        let when = BirdDateAndTime::new_naive(date, time)?;
+
+        Ok(Detection {
+            when: when.into(),
+            scientific_name: fields[2].to_owned(),
+            common_name: fields[3].to_owned(),
+            confidence: fields[4].parse()?,
+            latitude: fields[5].parse()?,
+            longitude: fields[6].parse()?,
+            cutoff: fields[7].parse()?,
+            week: fields[8].parse()?,
+            sens: fields[9].parse()?,
+            overlap: fields[10].parse()?,
+            file_name: fields.get(11).copied().unwrap_or_default().to_owned(),
+        })
+    }
+
+    let mut detections = Vec::new();
+
+    for (number, line) in std::fs::read_to_string(file)?.lines().enumerate() {
+        match parse_line(line) {
+            Ok(detection) => detections.push(detection),
+            Err(e) if number > 0 => warn!("{}:{}: {}", file, number + 1, e),
+            Err(_) => {}
+        }
+    }
+
+    Ok(detections)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone, Utc};
+
+    use super::*;
+
+    const ANALYZER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/analyzer.csv");
+    const TABLE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/analyzer-table.txt"
+    );
+    const LOG: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/birdnet-pi.log");
+
+    fn command(format: SourceFormat) -> Command {
+        Command {
+            station: "field".into(),
+            format,
+            start: None,
+            lat: 47.6,
+            lon: -122.3,
+            min_confidence: 0.1,
+            sens: 1.0,
+            overlap: 0.0,
+            files: Vec::new(),
+        }
+    }
+
+    fn summary(detections: &[Detection]) -> Vec<(DateTime<Utc>, &str, &str)> {
+        detections
+            .iter()
+            .map(|d| (d.when, d.scientific_name.as_str(), d.file_name.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn detects_formats_from_the_header() {
+        assert_eq!(detect_format(ANALYZER).unwrap(), SourceFormat::Csv);
+        assert_eq!(detect_format(TABLE).unwrap(), SourceFormat::Table);
+        assert_eq!(detect_format(LOG).unwrap(), SourceFormat::Log);
+        assert!(detect_format(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")).is_err());
+    }
+
+    #[test]
+    fn finds_recording_starts_in_file_names() {
+        assert_eq!(
+            recording_start("/recordings/SMA01_20240501_063000.wav"),
+            NaiveDate::from_ymd_opt(2024, 5, 1)
+                .unwrap()
+                .and_hms_opt(6, 30, 0)
+        );
+        assert_eq!(
+            recording_start("20240501_063000"),
+            NaiveDate::from_ymd_opt(2024, 5, 1)
+                .unwrap()
+                .and_hms_opt(6, 30, 0)
+        );
+        assert_eq!(
+            recording_start("/recordings/20240501_063000/noise.wav"),
+            None
+        );
+        assert_eq!(recording_start("SMA01_20241301_063000.wav"), None);
+    }
+
+    #[test]
+    fn parses_analyzer_results() {
+        let names = HashMap::new();
+        let detections = parse_analyzer(
+            &command(SourceFormat::Csv),
+            SourceFormat::Csv,
+            ANALYZER,
+            &names,
+        )
+        .unwrap();
+
+        assert_eq!(
+            summary(&detections),
+            vec![
+                (
+                    Utc.with_ymd_and_hms(2024, 5, 1, 13, 30, 0).unwrap(),
+                    "Turdus migratorius",
+                    "American_Robin-SMA01_20240501_063000-0-3.wav"
+                ),
+                (
+                    Utc.with_ymd_and_hms(2024, 5, 1, 13, 31, 1).unwrap()
+                        + Duration::milliseconds(500),
+                    "Cyanocitta stelleri",
+                    "Steller's_Jay-SMA01_20240501_063000-61.5-64.5.wav"
+                ),
+            ]
+        );
+        assert_eq!(detections[0].week, 17);
+        assert_eq!(detections[0].latitude, 47.6);
+    }
+
+    #[test]
+    fn parses_selection_tables_with_known_species() {
+        let names = HashMap::from([("American Robin".into(), "Turdus migratorius".into())]);
+        let detections = parse_analyzer(
+            &command(SourceFormat::Table),
+            SourceFormat::Table,
+            TABLE,
+            &names,
+        )
+        .unwrap();
+
+        assert_eq!(
+            summary(&detections),
+            vec![(
+                Utc.with_ymd_and_hms(2024, 5, 1, 13, 30, 0).unwrap(),
+                "Turdus migratorius",
+                "American_Robin-20240501_063000-0-3.wav"
+            )]
+        );
+    }
+
+    #[test]
+    fn prefers_the_given_start() {
+        let mut cmd = command(SourceFormat::Table);
+        let names = HashMap::from([("American Robin".into(), "Turdus migratorius".into())]);
+
+        cmd.start = NaiveDate::from_ymd_opt(2024, 5, 2)
+            .unwrap()
+            .and_hms_opt(0, 0, 0);
+        let detections = parse_analyzer(&cmd, SourceFormat::Table, TABLE, &names).unwrap();
+        assert_eq!(
+            detections[0].when,
+            Utc.with_ymd_and_hms(2024, 5, 2, 7, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn parses_logs_skipping_bad_lines() {
+        let detections = parse_log(LOG).unwrap();
+
+        assert_eq!(
+            summary(&detections),
+            vec![
+                (
+                    Utc.with_ymd_and_hms(2024, 5, 1, 13, 30, 0).unwrap(),
+                    "Turdus migratorius",
+                    ""
+                ),
+                (
+                    Utc.with_ymd_and_hms(2024, 5, 1, 13, 31, 12).unwrap(),
+                    "Corvus brachyrhynchos",
+                    "American_Crow-90-2024-05-01-birdnet-06:31:12.mp3"
+                ),
+            ]
+        );
+        assert_eq!(detections[1].sens, 1.25);
+    }
+}
@@ -0,0 +1,442 @@
+use anyhow::Result;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::Query;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::IntoResponse;
+use axum::Extension;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{info, warn};
+
+use crate::taxonomy::Translation;
+use crate::{get_database, publish, BirdDb, Detection};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LiveDetection {
+    pub when: DateTime<Utc>,
+    pub common_name: String,
+    pub scientific_name: String,
+    pub confidence: f32,
+    pub file_name: String,
+}
+
+impl From<Detection> for LiveDetection {
+    fn from(d: Detection) -> Self {
+        Self {
+            when: d.when,
+            common_name: d.common_name,
+            scientific_name: d.scientific_name,
+            confidence: d.confidence,
+            file_name: d.file_name,
+        }
+    }
+}
+
+pub fn get_replay_size() -> usize {
+    std::env::var("BIRBS_LIVE_REPLAY")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(50)
+}
+
+/// Fans new detections out to subscribers, remembering the most recent few so
+/// new subscribers can catch up.
+pub struct Live {
+    sender: broadcast::Sender<LiveDetection>,
+    recent: Mutex<VecDeque<LiveDetection>>,
+    capacity: usize,
+}
+
+impl Live {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(256);
+
+        Self {
+            sender,
+            recent: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn publish(&self, detection: LiveDetection) {
+        let mut recent = self.recent.lock().expect("live lock");
+        if recent.len() == self.capacity {
+            recent.pop_front();
+        }
+        recent.push_back(detection.clone());
+
+        // Nobody listening is fine.
+        let _ = self.sender.send(detection);
+    }
+
+    /// The last `replay` detections and a receiver for everything after them.
+    pub fn subscribe(
+        &self,
+        replay: usize,
+    ) -> (Vec<LiveDetection>, broadcast::Receiver<LiveDetection>) {
+        let recent = self.recent.lock().expect("live lock");
+        let skip = recent.len().saturating_sub(replay);
+
+        (
+            recent.iter().skip(skip).cloned().collect(),
+            self.sender.subscribe(),
+        )
+    }
+}
+
+/// Blocks, publishing rows as BirdNET-Pi adds them to `birds.db`.
+pub fn watch_database(live: Arc<Live>) -> Result<()> {
+    const BATCH_SIZE: usize = 100;
+
+    let path = PathBuf::from(get_database()?);
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let directory = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_owned(),
+        _ => PathBuf::from("."),
+    };
+
+    let db = BirdDb::birdnet()?;
+    let newest = db.newest_rowid()?;
+    let mut last = newest.saturating_sub(live.capacity as i64);
+
+    let publish_new = |last: &mut i64| -> Result<()> {
+        let newest = db.newest_rowid()?;
+        if newest < *last {
+            // The table was rebuilt, anything in it now has been seen before.
+            warn!(
+                "birdnet-pi rowids went backwards ({} < {}), skipping ahead",
+                newest, *last
+            );
+            *last = newest;
+        }
+
+        loop {
+            let rows = db.detections_after(*last, BATCH_SIZE)?;
+            let Some((rowid, _)) = rows.last() else {
+                return Ok(());
+            };
+            *last = *rowid;
+
+            for (_, detection) in rows {
+                live.publish(detection.into());
+            }
+        }
+    };
+
+    publish_new(&mut last)?;
+
+    info!("watching {:?} for new detections", path);
+
+    // SQLite may write to the journal or WAL next to the database rather than
+    // the database itself, so watch them all.
+    publish::watch_path(&directory, |event| {
+        let relevant = event.paths.iter().any(|p| {
+            p.file_name()
+                .map(|n| n.to_string_lossy().starts_with(&name))
+                .unwrap_or_default()
+        });
+
+        if relevant {
+            if let Err(e) = publish_new(&mut last) {
+                warn!("reading new detections: {:?}", e);
+            }
+        }
+
+        Ok(())
+    })
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct LiveQuery {
+    /// Comma separated common or scientific names.
+    species: Option<String>,
+    min_confidence: Option<f32>,
+    replay: Option<usize>,
+    /// Language for common names, as in BirdNET's label files.
+    lang: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct LiveFilter {
+    #[serde(default)]
+    species: Vec<String>,
+    min_confidence: Option<f32>,
+}
+
+impl From<&LiveQuery> for LiveFilter {
+    fn from(query: &LiveQuery) -> Self {
+        Self {
+            species: query
+                .species
+                .iter()
+                .flat_map(|s| s.split(','))
+                .map(|s| s.trim().to_owned())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            min_confidence: query.min_confidence,
+        }
+    }
+}
+
+impl LiveFilter {
+    fn matches(&self, d: &LiveDetection) -> bool {
+        let species = self.species.is_empty()
+            || self.species.iter().any(|s| {
+                s.eq_ignore_ascii_case(&d.common_name) || s.eq_ignore_ascii_case(&d.scientific_name)
+            });
+
+        species && self.min_confidence.is_none_or(|c| d.confidence >= c)
+    }
+}
+
+fn subscribe(live: &Live, query: &LiveQuery) -> impl Stream<Item = LiveDetection> + Send + 'static {
+    let (replay, receiver) = live.subscribe(query.replay.unwrap_or(live.capacity));
+
+    let received = BroadcastStream::new(receiver).filter_map(|r| async move {
+        match r {
+            Ok(d) => Some(d),
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                warn!("live subscriber lagged, skipped {}", skipped);
+                None
+            }
+        }
+    });
+
+    futures::stream::iter(replay).chain(received)
+}
+
+/// Names are translated after filtering, so filters can keep using English.
+fn translation(query: &LiveQuery) -> Option<Translation> {
+    Translation::new(query.lang.as_deref()?)
+}
+
+fn localize(translation: &Option<Translation>, mut d: LiveDetection) -> LiveDetection {
+    if let Some(translation) = translation {
+        translation.localize(Some(&d.scientific_name), &mut d.common_name);
+    }
+    d
+}
+
+#[axum_macros::debug_handler]
+pub async fn sse(
+    Extension(live): Extension<Arc<Live>>,
+    Query(query): Query<LiveQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let filter = LiveFilter::from(&query);
+    let translation = translation(&query);
+
+    let events = subscribe(&live, &query)
+        .filter(move |d| futures::future::ready(filter.matches(d)))
+        .map(move |d| {
+            Ok(Event::default()
+                .event("detection")
+                .json_data(localize(&translation, d))
+                .expect("serializable detection"))
+        });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[axum_macros::debug_handler]
+pub async fn ws(
+    Extension(live): Extension<Arc<Live>>,
+    Query(query): Query<LiveQuery>,
+    upgrade: WebSocketUpgrade,
+) -> impl IntoResponse {
+    upgrade.on_upgrade(move |socket| websocket(socket, live, query))
+}
+
+/// Sends detections as JSON text messages. Clients may send a `LiveFilter` as
+/// JSON at any time to change what they're subscribed to.
+async fn websocket(mut socket: WebSocket, live: Arc<Live>, query: LiveQuery) {
+    let mut filter = LiveFilter::from(&query);
+    let translation = translation(&query);
+    let mut detections = Box::pin(subscribe(&live, &query));
+
+    loop {
+        tokio::select! {
+            detection = detections.next() => {
+                let Some(detection) = detection else {
+                    break;
+                };
+
+                if !filter.matches(&detection) {
+                    continue;
+                }
+
+                let detection = localize(&translation, detection);
+                let json = serde_json::to_string(&detection).expect("serializable detection");
+                if socket.send(Message::Text(json)).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => {
+                match message {
+                    Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                        Ok(updated) => filter = updated,
+                        Err(e) => warn!("ignoring live filter: {:?}", e),
+                    },
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use axum::Router;
+    use chrono::TimeZone;
+    use futures::SinkExt;
+    use tokio_tungstenite::tungstenite;
+
+    use super::*;
+    use crate::tests::detection;
+
+    fn live_detection(scientific_name: &str, common_name: &str, confidence: f32) -> LiveDetection {
+        let mut detection = detection(
+            Utc.with_ymd_and_hms(2024, 5, 1, 14, 0, 0).unwrap(),
+            scientific_name,
+            common_name,
+        );
+        detection.confidence = confidence;
+        detection.into()
+    }
+
+    async fn received<S>(socket: &mut S) -> String
+    where
+        S: Stream<Item = Result<tungstenite::Message, tungstenite::Error>> + Unpin,
+    {
+        match socket.next().await.unwrap().unwrap() {
+            tungstenite::Message::Text(text) => {
+                serde_json::from_str::<LiveDetection>(&text)
+                    .unwrap()
+                    .common_name
+            }
+            message => panic!("unexpected {:?}", message),
+        }
+    }
+
+    fn common_names(detections: &[LiveDetection]) -> Vec<&str> {
+        detections.iter().map(|d| d.common_name.as_str()).collect()
+    }
+
+    #[test]
+    fn replays_the_most_recent() {
+        let live = Live::new(2);
+        live.publish(live_detection("Turdus migratorius", "American Robin", 0.9));
+        live.publish(live_detection(
+            "Corvus brachyrhynchos",
+            "American Crow",
+            0.9,
+        ));
+        live.publish(live_detection("Cyanocitta stelleri", "Steller's Jay", 0.9));
+
+        let (replay, _) = live.subscribe(5);
+        assert_eq!(
+            common_names(&replay),
+            vec!["American Crow", "Steller's Jay"]
+        );
+
+        let (replay, mut receiver) = live.subscribe(1);
+        assert_eq!(common_names(&replay), vec!["Steller's Jay"]);
+
+        live.publish(live_detection("Passer domesticus", "House Sparrow", 0.9));
+        assert_eq!(receiver.try_recv().unwrap().common_name, "House Sparrow");
+    }
+
+    #[test]
+    fn translates_after_filtering() {
+        let query = LiveQuery {
+            species: Some("American Crow".into()),
+            lang: Some("fr".into()),
+            ..Default::default()
+        };
+        let filter = LiveFilter::from(&query);
+        let crow = live_detection("Corvus brachyrhynchos", "American Crow", 0.9);
+
+        assert!(filter.matches(&crow));
+        assert_eq!(
+            localize(&translation(&query), crow).common_name,
+            "Corneille d'Amérique"
+        );
+    }
+
+    #[test]
+    fn filters_by_species_and_confidence() {
+        let filter = LiveFilter::from(&LiveQuery {
+            species: Some("american robin, Corvus brachyrhynchos,".into()),
+            min_confidence: Some(0.8),
+            ..Default::default()
+        });
+
+        assert!(filter.matches(&live_detection("Turdus migratorius", "American Robin", 0.8)));
+        assert!(filter.matches(&live_detection(
+            "Corvus brachyrhynchos",
+            "American Crow",
+            0.9
+        )));
+        assert!(!filter.matches(&live_detection("Turdus migratorius", "American Robin", 0.7)));
+        assert!(!filter.matches(&live_detection("Cyanocitta stelleri", "Steller's Jay", 0.9)));
+        assert!(LiveFilter::default().matches(&live_detection(
+            "Cyanocitta stelleri",
+            "Steller's Jay",
+            0.1
+        )));
+    }
+
+    #[tokio::test]
+    async fn websockets_update_their_filter() {
+        let live = Arc::new(Live::new(10));
+        live.publish(live_detection(
+            "Corvus brachyrhynchos",
+            "American Crow",
+            0.9,
+        ));
+        live.publish(live_detection("Turdus migratorius", "American Robin", 0.9));
+
+        let app = Router::new()
+            .route("/live/ws", get(ws))
+            .layer(Extension(live.clone()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!(
+            "ws://{}/live/ws?species=American%20Robin",
+            addr
+        ))
+        .await
+        .unwrap();
+        assert_eq!(received(&mut socket).await, "American Robin");
+
+        socket
+            .send(tungstenite::Message::Text(
+                r#"{"species": ["American Crow"]}"#.into(),
+            ))
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        live.publish(live_detection("Turdus migratorius", "American Robin", 0.9));
+        live.publish(live_detection(
+            "Corvus brachyrhynchos",
+            "American Crow",
+            0.9,
+        ));
+
+        assert_eq!(received(&mut socket).await, "American Crow");
+    }
+}
@@ -1,16 +1,36 @@
 use anyhow::Result;
-use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Utc};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Utc};
 use chrono_tz::{Tz, US::Pacific};
 
 use clap::{Parser, Subcommand};
 use rusqlite::Connection;
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use tracing_subscriber::prelude::*;
 
+mod alerts;
+mod chorus;
+mod diversity;
+mod encounters;
+mod export;
 mod flickr;
+mod health;
+mod heatmap;
+mod import;
+mod live;
+mod metrics;
+mod notifier;
+mod phenology;
+mod photos;
+mod providers;
 mod publish;
+mod review;
 mod serve;
+mod species;
+mod store;
+mod sun;
+mod sync;
+mod taxonomy;
 
 #[derive(Serialize)]
 struct Daily {
@@ -36,7 +56,7 @@ impl BirdDateAndTime {
         let pacific = no_tz.and_local_timezone(Pacific);
         let best_case = pacific.single();
         let earliest = pacific.earliest();
-        let riverside = best_case.or_else(|| earliest).expect("no idea time wise");
+        let riverside = best_case.or(earliest).expect("no idea time wise");
 
         Ok(Self {
             utc: riverside.with_timezone(&Utc),
@@ -54,11 +74,18 @@ impl BirdDateAndTime {
         let date_only = NaiveDate::parse_from_str(&date, "%Y-%m-%d")?;
         Self::new_naive(date_only, NaiveTime::MIN)
     }
+
+    fn from_utc(utc: DateTime<Utc>) -> Self {
+        Self {
+            utc,
+            local: utc.with_timezone(&Pacific),
+        }
+    }
 }
 
-impl Into<DateTime<Utc>> for BirdDateAndTime {
-    fn into(self) -> DateTime<Utc> {
-        self.utc
+impl From<BirdDateAndTime> for DateTime<Utc> {
+    fn from(value: BirdDateAndTime) -> Self {
+        value.utc
     }
 }
 
@@ -88,7 +115,9 @@ pub struct Detections {
 
 #[derive(Serialize, Debug)]
 pub struct DetectionsByCommonName {
+    slug: String,
     common_name: String,
+    scientific_name: String,
     total: u32,
     average_confidence: f32,
     last_detection: DateTime<Utc>,
@@ -97,7 +126,9 @@ pub struct DetectionsByCommonName {
 #[derive(Serialize, Debug)]
 pub struct DetectionsByTimeAndCommonName {
     when: DateTime<Utc>,
+    slug: String,
     common_name: String,
+    scientific_name: String,
     total: u32,
     average_confidence: f32,
 }
@@ -126,11 +157,23 @@ pub struct DetectionsSummary {
     total: u64,
 }
 
+#[derive(Serialize, Debug)]
+pub struct SpeciesStats {
+    scientific_name: String,
+    total: u64,
+    average_confidence: f32,
+    first_detection: DateTime<Utc>,
+    last_detection: DateTime<Utc>,
+    days_detected: u64,
+}
+
 #[derive(Serialize, Debug, Clone)]
 pub struct Recently {
     when: DateTime<Utc>,
     file_name: String,
+    slug: String,
     common_name: String,
+    scientific_name: String,
     confidence: f32,
     spectrogram_url: String,
     audio_url: String,
@@ -146,6 +189,82 @@ impl Recently {
     }
 }
 
+fn urlify_string(i: &str) -> String {
+    i.replace(' ', "_")
+}
+
+/// Where BirdNET-Pi serves the audio for a detection, the spectrogram is the same with `.png` appended.
+fn recording_url(when: &BirdDateAndTime, common_name: &str, file_name: &str) -> String {
+    format!(
+        "http://192.168.0.164/By_Date/{}/{}/{}",
+        when.local.format("%Y-%m-%d"),
+        urlify_string(common_name),
+        file_name
+    )
+}
+
+/// Weeks of the year counted from January 1st, so early January is never
+/// folded into the end of the year before the way ISO weeks can be.
+fn week_of_year(date: impl Datelike) -> u32 {
+    date.ordinal0() / 7 + 1
+}
+
+/// Every detection, with `starts` set on those that begin an encounter: the
+/// first of their species, or more than `?1` minutes after the one before.
+/// Times are local, so the hour clocks go back can join two encounters.
+const ENCOUNTER_STARTS: &str = r"(
+    SELECT *,
+        COALESCE(at - LAG(at) OVER (PARTITION BY sci_name ORDER BY at) > ?1 * 60, 1) AS starts
+    FROM (
+        SELECT *, CAST(strftime('%s', date || ' ' || time) AS INTEGER) AS at
+        FROM detections
+    )
+)";
+
+/// What aggregates select from and how they total it, counting encounters
+/// when given the gap between them.
+fn counting(gap: Option<u32>) -> (&'static str, &'static str) {
+    match gap {
+        None => ("detections", "COUNT(*)"),
+        Some(_) => (ENCOUNTER_STARTS, "SUM(starts)"),
+    }
+}
+
+fn detection_from_row(row: &rusqlite::Row) -> rusqlite::Result<Detection> {
+    let when = BirdDateAndTime::new(row.get(0)?, row.get(1)?)
+        .map_err(|_| rusqlite::Error::InvalidParameterName("DATE and TIME".into()))?;
+
+    Ok(Detection {
+        when: when.into(),
+        scientific_name: row.get(2)?,
+        common_name: row.get(3)?,
+        confidence: row.get(4)?,
+        latitude: row.get(5)?,
+        longitude: row.get(6)?,
+        cutoff: row.get(7)?,
+        week: row.get(8)?,
+        sens: row.get(9)?,
+        overlap: row.get(10)?,
+        file_name: row.get(11)?,
+    })
+}
+
+struct SpeciesDay {
+    scientific_name: String,
+    common_name: String,
+    date: NaiveDate,
+    detections: u32,
+}
+
+/// Detections per station and species, and when each was last heard.
+#[derive(Debug)]
+struct SpeciesTotal {
+    station: String,
+    common_name: String,
+    total: u64,
+    last_detection: DateTime<Utc>,
+}
+
 struct BirdDb {
     conn: Connection,
 }
@@ -155,13 +274,33 @@ fn get_database() -> Result<String> {
 }
 
 impl BirdDb {
+    /// Prefers the birbs database when one is configured, falling back to
+    /// reading BirdNET-Pi's directly.
     fn new() -> Result<Self> {
+        match store::get_store_database() {
+            Ok(path) => Ok(Self {
+                conn: store::Store::open(path)?.into_connection(),
+            }),
+            Err(_) => Self::birdnet(),
+        }
+    }
+
+    /// Leaves out detections reviewed as false positives.
+    fn exclude_rejected(self) -> Result<Self> {
+        store::exclude_rejected(&self.conn)?;
+
+        Ok(self)
+    }
+
+    fn birdnet() -> Result<Self> {
         Ok(Self {
             conn: Connection::open(get_database()?)?,
         })
     }
 
     fn common_name_to_scientific_name(&self) -> Result<HashMap<String, String>> {
+        let _timer = metrics::query_timer("common_name_to_scientific_name");
+
         let mut stmt = self
             .conn
             .prepare(r"SELECT com_name, sci_name FROM detections GROUP BY com_name, sci_name")?;
@@ -172,69 +311,161 @@ impl BirdDb {
             Ok((common, scientific))
         })?;
 
-        Ok(res
-            .into_iter()
+        res.into_iter()
             .map(|row| Ok(row?)) // Yeah yeah yeah TODO
-            .collect::<Result<HashMap<_, _>>>()?)
+            .collect::<Result<HashMap<_, _>>>()
     }
 
-    fn by_day_and_common_name(&self) -> Result<Vec<DetectionsByTimeAndCommonName>> {
+    /// Every common and scientific name pair detected, most recently detected
+    /// last.
+    fn species_names(&self) -> Result<Vec<(String, String)>> {
+        let _timer = metrics::query_timer("species_names");
+
         let mut stmt = self.conn.prepare(
+            r"SELECT com_name, sci_name FROM detections
+            GROUP BY com_name, sci_name
+            ORDER BY MAX(date || ' ' || time)",
+        )?;
+
+        let names = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        names
+            .into_iter()
+            .map(|row| Ok(row?))
+            .collect::<Result<Vec<_>>>()
+    }
+
+    /// Counts detections, or encounters starting each day when given the gap
+    /// between them.
+    fn by_day_and_common_name(
+        &self,
+        gap: Option<u32>,
+    ) -> Result<Vec<DetectionsByTimeAndCommonName>> {
+        let _timer = metrics::query_timer("by_day_and_common_name");
+
+        let (detections, total) = counting(gap);
+        let mut stmt = self.conn.prepare(&format!(
             r"SELECT
                 date,
                 com_name,
-                COUNT(com_name) AS total,
-                AVG(confidence) AS average_confidence
-            FROM detections
-            GROUP BY date, com_name",
-        )?;
+                {total} AS total,
+                AVG(confidence) AS average_confidence,
+                MAX(sci_name) AS sci_name
+            FROM {detections}
+            GROUP BY date, com_name
+            HAVING total > 0"
+        ))?;
 
-        let res = stmt.query_map([], |row| {
+        let res = stmt.query_map(rusqlite::params_from_iter(gap), |row| {
             let when = BirdDateAndTime::new_date_only(row.get(0)?).expect("invalid date and time");
+            let scientific_name: String = row.get(4)?;
             Ok(DetectionsByTimeAndCommonName {
                 when: when.into(),
+                slug: species::slug(&scientific_name),
                 common_name: row.get(1)?,
+                scientific_name,
                 total: row.get(2)?,
                 average_confidence: row.get(3)?,
             })
         })?;
 
-        Ok(res
-            .into_iter()
+        res.into_iter()
             .map(|row| Ok(row?)) // Yeah yeah yeah TODO
-            .collect::<Result<Vec<DetectionsByTimeAndCommonName>>>()?)
+            .collect::<Result<Vec<DetectionsByTimeAndCommonName>>>()
     }
 
-    fn by_common_name(&self) -> Result<Vec<DetectionsByCommonName>> {
-        let mut stmt = self.conn.prepare(
+    /// Counts detections, or encounters when given the gap between them.
+    fn by_common_name(&self, gap: Option<u32>) -> Result<Vec<DetectionsByCommonName>> {
+        let _timer = metrics::query_timer("by_common_name");
+
+        let (detections, total) = counting(gap);
+        let mut stmt = self.conn.prepare(&format!(
             r"SELECT
                 com_name,
-                COUNT(com_name) AS total,
+                {total} AS total,
                 AVG(confidence) AS average_confidence,
                 MAX(DATE) AS max_date,
-                MAX(TIME) AS max_time
-            FROM detections
-            GROUP BY com_name",
-        )?;
+                MAX(TIME) AS max_time,
+                MAX(sci_name) AS sci_name
+            FROM {detections}
+            GROUP BY com_name"
+        ))?;
 
-        let res = stmt.query_map([], |row| {
+        let res = stmt.query_map(rusqlite::params_from_iter(gap), |row| {
             let last_detection =
                 BirdDateAndTime::new(row.get(3)?, row.get(4)?).expect("invalid date and time");
+            let scientific_name: String = row.get(5)?;
             Ok(DetectionsByCommonName {
+                slug: species::slug(&scientific_name),
                 common_name: row.get(0)?,
+                scientific_name,
                 total: row.get(1)?,
                 average_confidence: row.get(2)?,
                 last_detection: last_detection.into(),
             })
         })?;
 
-        Ok(res
-            .into_iter()
+        res.into_iter()
             .map(|row| Ok(row?)) // Yeah yeah yeah TODO
-            .collect::<Result<Vec<DetectionsByCommonName>>>()?)
+            .collect::<Result<Vec<DetectionsByCommonName>>>()
+    }
+
+    /// Totals for every station and species. BirdNET-Pi's own database has
+    /// no station column, its rows are all this station's.
+    fn species_totals(&self) -> Result<Vec<SpeciesTotal>> {
+        let _timer = metrics::query_timer("species_totals");
+
+        let has_station: bool = self.conn.query_row(
+            r"SELECT COUNT(*) > 0 FROM pragma_table_info('detections') WHERE name = 'station'",
+            [],
+            |row| row.get(0),
+        )?;
+        let station = if has_station { "station" } else { "?" };
+
+        let mut stmt = self.conn.prepare(&format!(
+            r"SELECT
+                {} AS station,
+                com_name,
+                COUNT(*) AS total,
+                MAX(date || ' ' || time) AS last
+            FROM detections
+            GROUP BY 1, com_name",
+            station
+        ))?;
+
+        let default_station = store::get_station();
+        let params: &[&dyn rusqlite::ToSql] = if has_station {
+            &[]
+        } else {
+            &[&default_station]
+        };
+
+        let res = stmt.query_map(params, |row| {
+            let last: String = row.get(3)?;
+            let (date, time) =
+                last.split_once(' ')
+                    .ok_or(rusqlite::Error::InvalidParameterName(
+                        "DATE and TIME".into(),
+                    ))?;
+            let last_detection = BirdDateAndTime::new(date.into(), time.into())
+                .map_err(|_| rusqlite::Error::InvalidParameterName("DATE and TIME".into()))?;
+
+            Ok(SpeciesTotal {
+                station: row.get(0)?,
+                common_name: row.get(1)?,
+                total: row.get(2)?,
+                last_detection: last_detection.into(),
+            })
+        })?;
+
+        res.into_iter()
+            .map(|row| Ok(row?))
+            .collect::<Result<Vec<_>>>()
     }
 
     fn detections(&self) -> Result<Vec<Detection>> {
+        let _timer = metrics::query_timer("detections");
+
         let mut stmt = self.conn.prepare(
             r"SELECT
                  date, time,
@@ -246,50 +477,234 @@ impl BirdDb {
              ORDER BY date, time, sci_name",
         )?;
 
-        let entities = stmt.query_map([], |row| {
-            let when = BirdDateAndTime::new(row.get(0)?, row.get(1)?).or_else(|_| {
-                Err(rusqlite::Error::InvalidParameterName(
-                    "DATE and TIME".into(),
-                ))
-            })?;
+        let entities = stmt.query_map([], detection_from_row)?;
 
-            Ok(Detection {
-                when: when.into(),
-                scientific_name: row.get(2)?,
-                common_name: row.get(3)?,
-                confidence: row.get(4)?,
-                latitude: row.get(5)?,
-                longitude: row.get(6)?,
-                cutoff: row.get(7)?,
-                week: row.get(8)?,
-                sens: row.get(9)?,
-                overlap: row.get(10)?,
-                file_name: row.get(11)?,
+        entities
+            .into_iter()
+            .map(|row| Ok(row?)) // Yeah yeah yeah TODO
+            .collect::<Result<Vec<Detection>>>()
+    }
+
+    fn species_detections(&self, scientific_name: &str) -> Result<Vec<Detection>> {
+        let _timer = metrics::query_timer("species_detections");
+
+        let mut stmt = self.conn.prepare(
+            r"SELECT
+                 date, time,
+                 sci_name, com_name,
+                 confidence,
+                 lat, lon,
+                 cutoff, week, sens, overlap, file_name
+             FROM detections
+             WHERE sci_name = ?
+             ORDER BY date, time",
+        )?;
+
+        let entities = stmt.query_map([scientific_name], detection_from_row)?;
+
+        entities
+            .into_iter()
+            .map(|row| Ok(row?))
+            .collect::<Result<Vec<Detection>>>()
+    }
+
+    /// Rows added since the given rowid, oldest first, along with their rowids.
+    fn detections_after(&self, rowid: i64, limit: usize) -> Result<Vec<(i64, Detection)>> {
+        let mut stmt = self.conn.prepare(
+            r"SELECT
+                 date, time,
+                 sci_name, com_name,
+                 confidence,
+                 lat, lon,
+                 cutoff, week, sens, overlap, file_name,
+                 rowid
+             FROM detections
+             WHERE rowid > ?
+             ORDER BY rowid
+             LIMIT ?",
+        )?;
+
+        let entities = stmt.query_map(rusqlite::params![rowid, limit], |row| {
+            Ok((row.get(12)?, detection_from_row(row)?))
+        })?;
+
+        entities
+            .into_iter()
+            .map(|row| Ok(row?))
+            .collect::<Result<Vec<_>>>()
+    }
+
+    fn newest_rowid(&self) -> Result<i64> {
+        Ok(self.conn.query_row(
+            r"SELECT COALESCE(MAX(rowid), 0) FROM detections",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn newest_detection(&self) -> Result<Option<DateTime<Utc>>> {
+        let _timer = metrics::query_timer("newest_detection");
+
+        let mut stmt = self
+            .conn
+            .prepare(r"SELECT date, time FROM detections ORDER BY date DESC, time DESC LIMIT 1")?;
+
+        let newest = stmt
+            .query_map([], |row| {
+                BirdDateAndTime::new(row.get(0)?, row.get(1)?)
+                    .map_err(|_| rusqlite::Error::InvalidParameterName("DATE and TIME".into()))
+            })?
+            .next()
+            .transpose()?;
+
+        Ok(newest.map(|n| n.into()))
+    }
+
+    fn count_through(&self, rowid: i64) -> Result<usize> {
+        Ok(self.conn.query_row(
+            r"SELECT COUNT(*) FROM detections WHERE rowid <= ?",
+            [rowid],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// When each detection happened, for one species or all of them.
+    fn detection_times(
+        &self,
+        scientific_name: Option<&str>,
+        start: Option<NaiveDate>,
+        end: Option<NaiveDate>,
+        min_confidence: f32,
+    ) -> Result<Vec<BirdDateAndTime>> {
+        let _timer = metrics::query_timer("detection_times");
+
+        let mut stmt = self.conn.prepare(
+            r"SELECT date, time FROM detections
+             WHERE (?1 IS NULL OR sci_name = ?1)
+               AND (?2 IS NULL OR date >= ?2)
+               AND (?3 IS NULL OR date <= ?3)
+               AND confidence >= ?4
+             ORDER BY date, time",
+        )?;
+
+        let times = stmt.query_map(
+            rusqlite::params![
+                scientific_name,
+                start.map(|d| d.format("%Y-%m-%d").to_string()),
+                end.map(|d| d.format("%Y-%m-%d").to_string()),
+                min_confidence
+            ],
+            |row| {
+                BirdDateAndTime::new(row.get(0)?, row.get(1)?)
+                    .map_err(|_| rusqlite::Error::InvalidParameterName("DATE and TIME".into()))
+            },
+        )?;
+
+        times
+            .into_iter()
+            .map(|row| Ok(row?))
+            .collect::<Result<Vec<_>>>()
+    }
+
+    /// Detections of each species on each day, with the name it was last
+    /// detected as that day.
+    fn species_daily_counts(&self, min_confidence: f32) -> Result<Vec<SpeciesDay>> {
+        let _timer = metrics::query_timer("species_daily_counts");
+
+        // SQLite takes bare columns from the row with the MAX.
+        let mut stmt = self.conn.prepare(
+            r"SELECT sci_name, com_name, date, COUNT(*), MAX(time)
+             FROM detections
+             WHERE confidence >= ?
+             GROUP BY sci_name, date
+             ORDER BY date",
+        )?;
+
+        let days = stmt.query_map([min_confidence], |row| {
+            let date: String = row.get(2)?;
+            Ok(SpeciesDay {
+                scientific_name: row.get(0)?,
+                common_name: row.get(1)?,
+                date: NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+                    .map_err(|_| rusqlite::Error::InvalidParameterName("DATE and TIME".into()))?,
+                detections: row.get(3)?,
             })
         })?;
 
-        Ok(entities
+        days.into_iter()
+            .map(|row| Ok(row?))
+            .collect::<Result<Vec<_>>>()
+    }
+
+    fn rowids_between(&self, first: i64, last: i64) -> Result<HashSet<i64>> {
+        let mut stmt = self
+            .conn
+            .prepare(r"SELECT rowid FROM detections WHERE rowid BETWEEN ? AND ?")?;
+
+        let rowids = stmt.query_map([first, last], |row| row.get(0))?;
+
+        rowids
             .into_iter()
-            .map(|row| Ok(row?)) // Yeah yeah yeah TODO
-            .collect::<Result<Vec<Detection>>>()?)
+            .map(|row| Ok(row?))
+            .collect::<Result<HashSet<_>>>()
+    }
+
+    fn oldest_date(&self) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(r"SELECT MIN(date) FROM detections", [], |row| row.get(0))?)
     }
 
-    fn daily_detections(&self, common_name: &str) -> Result<Vec<Daily>> {
+    fn detections_between(
+        &self,
+        start: NaiveDate,
+        end: NaiveDate,
+        min_confidence: f32,
+    ) -> Result<Vec<Detection>> {
+        let _timer = metrics::query_timer("detections_between");
+
+        let mut stmt = self.conn.prepare(
+            r"SELECT
+                 date, time,
+                 sci_name, com_name,
+                 confidence,
+                 lat, lon,
+                 cutoff, week, sens, overlap, file_name
+             FROM detections
+             WHERE date BETWEEN ? AND ? AND confidence >= ?
+             ORDER BY date, time, sci_name",
+        )?;
+
+        let entities = stmt.query_map(
+            rusqlite::params![
+                start.format("%Y-%m-%d").to_string(),
+                end.format("%Y-%m-%d").to_string(),
+                min_confidence
+            ],
+            detection_from_row,
+        )?;
+
+        entities
+            .into_iter()
+            .map(|row| Ok(row?))
+            .collect::<Result<Vec<Detection>>>()
+    }
+
+    fn daily_detections(&self, scientific_name: &str) -> Result<Vec<Daily>> {
+        let _timer = metrics::query_timer("daily_detections");
+
         let mut stmt = self.conn.prepare(
             r"
             SELECT date, COUNT(*) FROM detections
-            WHERE com_name = ?
+            WHERE sci_name = ?
             GROUP BY date
             ORDER BY date
             ",
         )?;
 
-        let daily = stmt.query_map([common_name], |row| {
-            let date = BirdDateAndTime::new_date_only(row.get(0)?).or_else(|_| {
-                Err(rusqlite::Error::InvalidParameterName(
-                    "DATE and TIME".into(),
-                ))
-            })?;
+        let daily = stmt.query_map([scientific_name], |row| {
+            let date = BirdDateAndTime::new_date_only(row.get(0)?)
+                .map_err(|_| rusqlite::Error::InvalidParameterName("DATE and TIME".into()))?;
 
             Ok(Daily {
                 date: date.into(),
@@ -297,31 +712,30 @@ impl BirdDb {
             })
         })?;
 
-        Ok(daily
+        daily
             .into_iter()
             .map(|row| Ok(row?))
-            .collect::<Result<Vec<Daily>>>()?)
+            .collect::<Result<Vec<Daily>>>()
     }
 
-    fn hourly_detections(&self, common_name: &str) -> Result<Vec<Hourly>> {
+    fn hourly_detections(&self, scientific_name: &str) -> Result<Vec<Hourly>> {
+        let _timer = metrics::query_timer("hourly_detections");
+
         let mut stmt = self.conn.prepare(
             r"
             SELECT q.hour, COUNT(q.hour) FROM (
-                SELECT com_name, strftime('%H:00:00', time) AS hour FROM detections
-                WHERE com_name = ?
+                SELECT strftime('%H:00:00', time) AS hour FROM detections
+                WHERE sci_name = ?
             ) AS q
             GROUP BY q.hour
             ORDER BY q.hour
             ",
         )?;
 
-        let hourly = stmt.query_map([common_name], |row| {
+        let hourly = stmt.query_map([scientific_name], |row| {
             let time: String = row.get(0)?;
-            let time = NaiveTime::parse_from_str(&time, "%H:%M:%S").or_else(|_| {
-                Err(rusqlite::Error::InvalidParameterName(
-                    "DATE and TIME".into(),
-                ))
-            })?;
+            let time = NaiveTime::parse_from_str(&time, "%H:%M:%S")
+                .map_err(|_| rusqlite::Error::InvalidParameterName("DATE and TIME".into()))?;
 
             Ok(Hourly {
                 time,
@@ -330,60 +744,84 @@ impl BirdDb {
             })
         })?;
 
-        Ok(hourly
+        hourly
             .into_iter()
             .map(|row| Ok(row?))
-            .collect::<Result<Vec<Hourly>>>()?)
+            .collect::<Result<Vec<Hourly>>>()
     }
 
-    fn summarize_detections(&self, common_name: &str) -> Result<DetectionsSummary> {
+    fn summarize_detections(&self, scientific_name: &str) -> Result<DetectionsSummary> {
+        let _timer = metrics::query_timer("summarize_detections");
+
         let mut stmt = self
             .conn
-            .prepare(r"SELECT COUNT(date) FROM detections WHERE com_name = ?")?;
+            .prepare(r"SELECT COUNT(date) FROM detections WHERE sci_name = ?")?;
 
-        let total_detections: u64 = stmt.query_row([common_name], |row| row.get(0))?;
+        let total_detections: u64 = stmt.query_row([scientific_name], |row| row.get(0))?;
 
         Ok(DetectionsSummary {
             total: total_detections,
         })
     }
 
-    fn files_for(&self, common_name: &str) -> Result<Vec<FilesFor>> {
+    fn species_stats(&self, scientific_name: &str) -> Result<Option<SpeciesStats>> {
+        let _timer = metrics::query_timer("species_stats");
+
         let mut stmt = self.conn.prepare(
-            r"SELECT date, time, file_name, confidence
-             FROM detections
-             WHERE com_name = ?
-             ORDER BY confidence DESC LIMIT 100",
+            r"SELECT
+                MAX(sci_name),
+                COUNT(*),
+                AVG(confidence),
+                MIN(date || ' ' || time),
+                MAX(date || ' ' || time),
+                COUNT(DISTINCT date)
+            FROM detections
+            WHERE sci_name = ?
+            HAVING COUNT(*) > 0",
         )?;
 
-        let entities = stmt.query_map([common_name], |row| {
-            let when = BirdDateAndTime::new(row.get(0)?, row.get(1)?).or_else(|_| {
-                Err(rusqlite::Error::InvalidParameterName(
-                    "DATE and TIME".into(),
-                ))
-            })?;
+        let stats = stmt
+            .query_map([scientific_name], |row| {
+                let parse = |value: String| {
+                    let (date, time) = value.split_once(' ').unwrap_or((&value, "00:00:00"));
+                    BirdDateAndTime::new(date.to_owned(), time.to_owned())
+                        .map_err(|_| rusqlite::Error::InvalidParameterName("DATE and TIME".into()))
+                };
+                Ok(SpeciesStats {
+                    scientific_name: row.get(0)?,
+                    total: row.get(1)?,
+                    average_confidence: row.get(2)?,
+                    first_detection: parse(row.get(3)?)?.into(),
+                    last_detection: parse(row.get(4)?)?.into(),
+                    days_detected: row.get(5)?,
+                })
+            })?
+            .next()
+            .transpose()?;
+
+        Ok(stats)
+    }
 
-            let date_string = when.local.format("%Y-%m-%d");
-            let file_name: String = row.get(2)?;
+    fn files_for(&self, scientific_name: &str) -> Result<Vec<FilesFor>> {
+        let _timer = metrics::query_timer("files_for");
 
-            fn urlify_string(i: &str) -> String {
-                i.replace(" ", "_")
-            }
+        let mut stmt = self.conn.prepare(
+            r"SELECT date, time, file_name, confidence, com_name
+             FROM detections
+             WHERE sci_name = ?
+             ORDER BY confidence DESC LIMIT 100",
+        )?;
 
-            let audio_url = || -> Result<String, rusqlite::Error> {
-                Ok(format!(
-                    "http://192.168.0.164/By_Date/{}/{}/{}",
-                    &date_string,
-                    urlify_string(&common_name),
-                    &file_name
-                ))
-            };
+        let entities = stmt.query_map([scientific_name], |row| {
+            let when = BirdDateAndTime::new(row.get(0)?, row.get(1)?)
+                .map_err(|_| rusqlite::Error::InvalidParameterName("DATE and TIME".into()))?;
 
-            let spectrogram_url =
-                || -> Result<String, rusqlite::Error> { Ok(format!("{}.png", audio_url()?)) };
+            let file_name: String = row.get(2)?;
+            // Recordings are filed under the name they were detected as.
+            let common_name: String = row.get(4)?;
 
-            let spectrogram_url = spectrogram_url()?;
-            let audio_url = audio_url()?;
+            let audio_url = recording_url(&when, &common_name, &file_name);
+            let spectrogram_url = format!("{}.png", audio_url);
             let when = when.into();
             let confidence = row.get(3)?;
 
@@ -406,48 +844,33 @@ impl BirdDb {
     }
 
     fn recently(&self) -> Result<Vec<Recently>> {
+        let _timer = metrics::query_timer("recently");
+
         let mut stmt = self.conn.prepare(
-            r"SELECT date, time, com_name, file_name, confidence
+            r"SELECT date, time, com_name, file_name, confidence, sci_name
              FROM detections
              WHERE datetime(date, time) >= datetime('now', '-24 hours')
              ORDER BY datetime(date, time) DESC",
         )?;
 
         let entities = stmt.query_map([], |row| {
-            let when = BirdDateAndTime::new(row.get(0)?, row.get(1)?).or_else(|_| {
-                Err(rusqlite::Error::InvalidParameterName(
-                    "DATE and TIME".into(),
-                ))
-            })?;
+            let when = BirdDateAndTime::new(row.get(0)?, row.get(1)?)
+                .map_err(|_| rusqlite::Error::InvalidParameterName("DATE and TIME".into()))?;
 
-            let date_string = when.local.format("%Y-%m-%d");
             let common_name: String = row.get(2)?;
             let file_name: String = row.get(3)?;
 
-            fn urlify_string(i: &str) -> String {
-                i.replace(" ", "_")
-            }
-
-            let audio_url = || -> Result<String, rusqlite::Error> {
-                Ok(format!(
-                    "http://192.168.0.164/By_Date/{}/{}/{}",
-                    &date_string,
-                    urlify_string(&common_name),
-                    &file_name
-                ))
-            };
-
-            let spectrogram_url =
-                || -> Result<String, rusqlite::Error> { Ok(format!("{}.png", audio_url()?)) };
-
-            let spectrogram_url = spectrogram_url()?;
-            let audio_url = audio_url()?;
+            let audio_url = recording_url(&when, &common_name, &file_name);
+            let spectrogram_url = format!("{}.png", audio_url);
             let when = when.into();
             let confidence = row.get(4)?;
+            let scientific_name: String = row.get(5)?;
 
             Ok(Recently {
                 when,
+                slug: species::slug(&scientific_name),
                 common_name,
+                scientific_name: row.get(5)?,
                 file_name,
                 confidence,
                 spectrogram_url,
@@ -477,6 +900,12 @@ fn get_flickr_api_key() -> Result<String> {
 pub enum Command {
     Serve,
     Publish(publish::Command),
+    Export(export::Command),
+    Import(import::Command),
+    Sync(sync::Command),
+    Alerts(alerts::Command),
+    Photos(photos::Command),
+    Phenology(phenology::Command),
 }
 
 #[derive(Parser)]
@@ -497,5 +926,164 @@ async fn main() -> Result<()> {
     match cli.command {
         Command::Serve => serve::execute().await,
         Command::Publish(cmd) => publish::execute(cmd).await,
+        Command::Export(cmd) => export::execute(cmd).await,
+        Command::Import(cmd) => import::execute(cmd).await,
+        Command::Sync(cmd) => sync::execute(cmd).await,
+        Command::Alerts(cmd) => alerts::execute(cmd).await,
+        Command::Photos(cmd) => photos::execute(cmd).await,
+        Command::Phenology(cmd) => phenology::execute(cmd).await,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A detection at a station in Seattle.
+    pub(crate) fn detection(
+        when: DateTime<Utc>,
+        scientific_name: &str,
+        common_name: &str,
+    ) -> Detection {
+        Detection {
+            when,
+            common_name: common_name.to_owned(),
+            scientific_name: scientific_name.to_owned(),
+            confidence: 0.9,
+            latitude: 47.6,
+            longitude: -122.3,
+            cutoff: 0.7,
+            week: 1,
+            sens: 1.0,
+            overlap: 0.0,
+            file_name: String::new(),
+        }
+    }
+
+    /// An in-memory BirdNET database holding the detections.
+    pub(crate) fn db(detections: &[Detection]) -> BirdDb {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r"CREATE TABLE detections (
+                Date DATE, Time TIME,
+                Sci_Name VARCHAR(100) NOT NULL, Com_Name VARCHAR(100) NOT NULL,
+                Confidence FLOAT,
+                Lat FLOAT, Lon FLOAT,
+                Cutoff FLOAT, Week INT, Sens FLOAT, Overlap FLOAT, File_Name VARCHAR(100) NOT NULL)",
+        )
+        .unwrap();
+
+        for d in detections {
+            let local = d.when.with_timezone(&Pacific);
+            conn.execute(
+                r"INSERT INTO detections VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                rusqlite::params![
+                    local.format("%Y-%m-%d").to_string(),
+                    local.format("%H:%M:%S").to_string(),
+                    d.scientific_name,
+                    d.common_name,
+                    d.confidence,
+                    d.latitude,
+                    d.longitude,
+                    d.cutoff,
+                    d.week,
+                    d.sens,
+                    d.overlap,
+                    d.file_name,
+                ],
+            )
+            .unwrap();
+        }
+
+        BirdDb { conn }
+    }
+
+    #[test]
+    fn weeks_start_on_january_first() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+
+        // ISO week 53 of 2020.
+        assert_eq!(week_of_year(date(2021, 1, 1)), 1);
+        assert_eq!(week_of_year(date(2021, 1, 7)), 1);
+        assert_eq!(week_of_year(date(2021, 1, 8)), 2);
+        // ISO week 1 of 2025.
+        assert_eq!(week_of_year(date(2024, 12, 30)), 53);
+        assert_eq!(week_of_year(date(2024, 12, 31)), 53);
+    }
+
+    #[test]
+    fn counts_encounters_in_aggregates() {
+        use chrono::TimeZone;
+
+        let at = |d, h, m| Utc.with_ymd_and_hms(2024, 5, d, h, m, 0).unwrap();
+        let db = db(&[
+            detection(at(1, 14, 0), "Turdus migratorius", "American Robin"),
+            detection(at(1, 14, 5), "Turdus migratorius", "American Robin"),
+            detection(at(1, 14, 15), "Turdus migratorius", "American Robin"),
+            detection(at(1, 14, 26), "Turdus migratorius", "American Robin"),
+            detection(at(1, 14, 26), "Corvus brachyrhynchos", "American Crow"),
+            // Still going past midnight at the station, counted the day it started.
+            detection(at(2, 6, 55), "Turdus migratorius", "American Robin"),
+            detection(at(2, 7, 1), "Turdus migratorius", "American Robin"),
+        ]);
+
+        let totals = |gap| {
+            db.by_common_name(gap)
+                .unwrap()
+                .into_iter()
+                .map(|r| (r.common_name, r.total))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            totals(None),
+            vec![("American Crow".into(), 1), ("American Robin".into(), 6)]
+        );
+        assert_eq!(
+            totals(Some(10)),
+            vec![("American Crow".into(), 1), ("American Robin".into(), 3)]
+        );
+        assert_eq!(
+            totals(Some(15)),
+            vec![("American Crow".into(), 1), ("American Robin".into(), 2)]
+        );
+
+        let mut daily = db
+            .by_day_and_common_name(Some(10))
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.when.date_naive().day(), r.common_name, r.total))
+            .collect::<Vec<_>>();
+        daily.sort();
+        assert_eq!(
+            daily,
+            vec![
+                (1, "American Crow".into(), 1),
+                (1, "American Robin".into(), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn summarizes_a_species() {
+        use chrono::TimeZone;
+
+        let first = Utc.with_ymd_and_hms(2024, 5, 1, 14, 0, 0).unwrap();
+        let last = Utc.with_ymd_and_hms(2024, 5, 3, 2, 30, 0).unwrap();
+        let db = db(&[
+            detection(first, "Corvus brachyrhynchos", "American Crow"),
+            detection(first, "Corvus brachyrhynchos", "American Crow"),
+            detection(last, "Corvus brachyrhynchos", "American Crow"),
+            detection(last, "Turdus migratorius", "American Robin"),
+        ]);
+
+        let stats = db.species_stats("Corvus brachyrhynchos").unwrap().unwrap();
+        assert_eq!(stats.scientific_name, "Corvus brachyrhynchos");
+        assert_eq!(stats.total, 3);
+        assert_eq!(stats.first_detection, first);
+        assert_eq!(stats.last_detection, last);
+        // The 3rd in UTC is still the 2nd at the station.
+        assert_eq!(stats.days_detected, 2);
+
+        assert!(db.species_stats("Snarkus snarkus").unwrap().is_none());
     }
 }
@@ -0,0 +1,277 @@
+use anyhow::Result;
+use axum::extract::{MatchedPath, Query, Request};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use prometheus::{
+    Encoder, Gauge, GaugeVec, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use crate::publish::Checkpoint;
+use crate::review::ReviewFilter;
+use crate::BirdDb;
+
+struct Metrics {
+    registry: Registry,
+    http_requests: HistogramVec,
+    db_queries: HistogramVec,
+    flickr_cache: IntCounterVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let registry = Registry::new_custom(Some("birbs".into()), None).expect("metrics registry");
+
+    let http_requests = HistogramVec::new(
+        HistogramOpts::new("http_request_duration_seconds", "HTTP request latency."),
+        &["method", "route", "status"],
+    )
+    .expect("http metric");
+    let db_queries = HistogramVec::new(
+        HistogramOpts::new("db_query_duration_seconds", "Database query latency.").buckets(vec![
+            0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+        ]),
+        &["query"],
+    )
+    .expect("db metric");
+    let flickr_cache = IntCounterVec::new(
+        Opts::new(
+            "flickr_cache_requests_total",
+            "Flickr requests by cache result.",
+        ),
+        &["result"],
+    )
+    .expect("flickr metric");
+
+    registry
+        .register(Box::new(http_requests.clone()))
+        .expect("register http metric");
+    registry
+        .register(Box::new(db_queries.clone()))
+        .expect("register db metric");
+    registry
+        .register(Box::new(flickr_cache.clone()))
+        .expect("register flickr metric");
+
+    Metrics {
+        registry,
+        http_requests,
+        db_queries,
+        flickr_cache,
+    }
+});
+
+/// Observes how long a query takes, when dropped.
+pub fn query_timer(query: &str) -> HistogramTimer {
+    METRICS.db_queries.with_label_values(&[query]).start_timer()
+}
+
+/// Counts a Flickr response by its `x-cache` header, `hit` or `miss`.
+pub fn flickr_response(response: &reqwest::Response) {
+    let result = response
+        .headers()
+        .get("x-cache")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_ascii_lowercase())
+        .unwrap_or_else(|| "miss".into());
+
+    METRICS.flickr_cache.with_label_values(&[&result]).inc();
+}
+
+/// Middleware recording the latency and status of every request, by route
+/// rather than path so species names don't explode the label set.
+pub async fn track_http(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".into());
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+
+    METRICS
+        .http_requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .observe(started.elapsed().as_secs_f64());
+
+    response
+}
+
+/// Detection and publisher metrics are read fresh on every scrape, so they're
+/// gathered into a registry of their own each time.
+fn gather_detections(registry: &Registry, db: &BirdDb) -> Result<()> {
+    let detections = IntGaugeVec::new(
+        Opts::new("detections", "Detections stored, by station and species."),
+        &["station", "species"],
+    )?;
+    let last_species = GaugeVec::new(
+        Opts::new(
+            "last_detection_timestamp_seconds",
+            "When each species was last detected, by station.",
+        ),
+        &["station", "species"],
+    )?;
+    let last_station = GaugeVec::new(
+        Opts::new(
+            "station_last_detection_timestamp_seconds",
+            "When anything was last detected, by station.",
+        ),
+        &["station"],
+    )?;
+
+    for total in db.species_totals()? {
+        let labels = [total.station.as_str(), total.common_name.as_str()];
+        let last = total.last_detection.timestamp() as f64;
+
+        detections
+            .with_label_values(&labels)
+            .set(total.total as i64);
+        last_species.with_label_values(&labels).set(last);
+
+        let station = last_station.with_label_values(&[&total.station]);
+        station.set(station.get().max(last));
+    }
+
+    registry.register(Box::new(detections))?;
+    registry.register(Box::new(last_species))?;
+    registry.register(Box::new(last_station))?;
+
+    Ok(())
+}
+
+fn gather_publisher(registry: &Registry) -> Result<()> {
+    let Some(checkpoint) = Checkpoint::load()? else {
+        return Ok(());
+    };
+
+    let lines = IntGauge::new(
+        "publisher_lines",
+        "Lines published since the publisher started.",
+    )?;
+    let lag = Gauge::new(
+        "publisher_lag_seconds",
+        "Time since the newest published detection.",
+    )?;
+    let updated = Gauge::new(
+        "publisher_checkpoint_age_seconds",
+        "Time since the publisher last saved its checkpoint.",
+    )?;
+
+    let now = Utc::now();
+    lines.set(checkpoint.lines as i64);
+    if let Some(last) = checkpoint.last_entry {
+        lag.set((now - last).num_milliseconds() as f64 / 1000.0);
+    }
+    if let Some(at) = checkpoint.updated {
+        updated.set((now - at).num_milliseconds() as f64 / 1000.0);
+    }
+
+    registry.register(Box::new(lines))?;
+    registry.register(Box::new(lag))?;
+    registry.register(Box::new(updated))?;
+
+    Ok(())
+}
+
+fn render(db: &BirdDb) -> Result<String> {
+    let scraped = Registry::new_custom(Some("birbs".into()), None)?;
+    gather_detections(&scraped, db)?;
+    gather_publisher(&scraped)?;
+
+    let mut families = METRICS.registry.gather();
+    families.extend(scraped.gather());
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&families, &mut buffer)?;
+
+    Ok(String::from_utf8(buffer)?)
+}
+
+#[axum_macros::debug_handler]
+pub async fn metrics(Query(reviews): Query<ReviewFilter>) -> Result<impl IntoResponse, StatusCode> {
+    let body = tokio::task::spawn_blocking(move || render(&reviews.db()?))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use axum::Router;
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::tests::{db, detection};
+
+    #[test]
+    fn renders_detections_by_station_and_species() {
+        let at = |hour| Utc.with_ymd_and_hms(2024, 5, 1, hour, 0, 0).unwrap();
+        let db = db(&[
+            detection(at(14), "Turdus migratorius", "American Robin"),
+            detection(at(15), "Turdus migratorius", "American Robin"),
+            detection(at(16), "Corvus brachyrhynchos", "American Crow"),
+        ]);
+
+        let rendered = render(&db).unwrap();
+
+        let station = crate::store::get_station();
+        for line in [
+            format!(
+                r#"birbs_detections{{species="American Robin",station="{}"}} 2"#,
+                station
+            ),
+            format!(
+                r#"birbs_last_detection_timestamp_seconds{{species="American Crow",station="{}"}} {}"#,
+                station,
+                at(16).timestamp()
+            ),
+            format!(
+                r#"birbs_station_last_detection_timestamp_seconds{{station="{}"}} {}"#,
+                station,
+                at(16).timestamp()
+            ),
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "{} in {}",
+                line,
+                rendered
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn labels_requests_by_route() {
+        let app = Router::new()
+            .route("/:common-name/daily.json", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn(track_http));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        for path in ["/American%20Robin/daily.json", "/no/such/path-12345"] {
+            reqwest::get(format!("http://{}{}", addr, path))
+                .await
+                .unwrap();
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&METRICS.registry.gather(), &mut buffer)
+            .unwrap();
+        let rendered = String::from_utf8(buffer).unwrap();
+
+        assert!(rendered.contains(r#"route="/:common-name/daily.json",status="200""#));
+        assert!(rendered.contains(r#"route="unmatched",status="404""#));
+        assert!(!rendered.contains("American"));
+        assert!(!rendered.contains("path-12345"));
+    }
+}
@@ -0,0 +1,768 @@
+use anyhow::{anyhow, Result};
+use axum::body::Bytes;
+use axum::http::{HeaderMap, Method, Uri};
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::alerts::Alert;
+use crate::{recording_url, species, BirdDateAndTime};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SinkConfig {
+    /// POSTs the alert as JSON, signed with HMAC-SHA256 in `X-Birbs-Signature` when there's a secret.
+    Webhook {
+        url: String,
+        secret: Option<String>,
+        template: Option<String>,
+        #[serde(default = "default_retries")]
+        retries: u32,
+    },
+    Ntfy {
+        url: String,
+        token: Option<String>,
+        title: Option<String>,
+        template: Option<String>,
+        #[serde(default = "default_retries")]
+        retries: u32,
+    },
+    Gotify {
+        url: String,
+        token: String,
+        priority: Option<u8>,
+        title: Option<String>,
+        template: Option<String>,
+        #[serde(default = "default_retries")]
+        retries: u32,
+    },
+    Email {
+        host: String,
+        port: Option<u16>,
+        username: Option<String>,
+        password: Option<String>,
+        #[serde(default)]
+        starttls: bool,
+        from: String,
+        to: Vec<String>,
+        subject: Option<String>,
+        template: Option<String>,
+        #[serde(default = "default_retries")]
+        retries: u32,
+    },
+    /// Runs `program` with templated `args` and the alert as JSON on stdin.
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+const DEFAULT_TITLE: &str = "birbs: {rule}";
+const DEFAULT_TEMPLATE: &str = "{message}\n\nPhoto: {photo_url}\nSpectrogram: {spectrogram_url}";
+
+pub trait Notifier: Send + Sync {
+    fn notify<'a>(&'a self, alert: &'a Notification) -> BoxFuture<'a, Result<()>>;
+}
+
+/// An alert along with the URLs and values templates can refer to.
+#[derive(Serialize, Debug, Clone)]
+pub struct Notification {
+    #[serde(flatten)]
+    pub alert: Alert,
+    pub photo_url: String,
+    pub spectrogram_url: String,
+    pub audio_url: String,
+}
+
+impl Notification {
+    pub fn new(alert: Alert, public_url: &str) -> Self {
+        let d = &alert.detection;
+        let audio_url = recording_url(
+            &BirdDateAndTime::from_utc(d.when),
+            &d.common_name,
+            &d.file_name,
+        );
+
+        Self {
+            photo_url: format!(
+                "{}/{}/photo.png",
+                public_url.trim_end_matches('/'),
+                species::slug(&d.scientific_name)
+            ),
+            spectrogram_url: format!("{}.png", audio_url),
+            audio_url,
+            alert,
+        }
+    }
+
+    /// Replaces `{rule}`, `{message}`, `{common_name}`, `{scientific_name}`,
+    /// `{confidence}`, `{when}`, `{photo_url}`, `{spectrogram_url}` and `{audio_url}`.
+    pub fn render(&self, template: &str) -> String {
+        let d = &self.alert.detection;
+
+        [
+            ("{rule}", self.alert.rule.clone()),
+            ("{message}", self.alert.message.clone()),
+            ("{common_name}", d.common_name.clone()),
+            ("{scientific_name}", d.scientific_name.clone()),
+            ("{confidence}", format!("{:.2}", d.confidence)),
+            (
+                "{when}",
+                BirdDateAndTime::from_utc(d.when).local.to_rfc3339(),
+            ),
+            ("{photo_url}", self.photo_url.clone()),
+            ("{spectrogram_url}", self.spectrogram_url.clone()),
+            ("{audio_url}", self.audio_url.clone()),
+        ]
+        .iter()
+        .fold(template.to_owned(), |text, (key, value)| {
+            text.replace(key, value)
+        })
+    }
+}
+
+async fn with_retries<F, Fut>(retries: u32, mut attempt: F) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<()>>,
+{
+    let mut tries = 0;
+
+    loop {
+        match attempt().await {
+            Ok(()) => return Ok(()),
+            Err(e) if tries < retries => {
+                tries += 1;
+                warn!(
+                    "notification failed, retrying ({}/{}): {:?}",
+                    tries, retries, e
+                );
+                tokio::time::sleep(Duration::from_secs(2u64.pow(tries))).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn ok_status(response: reqwest::Response) -> Result<()> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{}: {}",
+            status,
+            response.text().await.unwrap_or_default()
+        ))
+    }
+}
+
+struct Webhook {
+    http: reqwest::Client,
+    url: String,
+    secret: Option<String>,
+    template: String,
+    retries: u32,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    #[serde(flatten)]
+    notification: &'a Notification,
+    text: String,
+}
+
+impl Notifier for Webhook {
+    fn notify<'a>(&'a self, n: &'a Notification) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let body = serde_json::to_vec(&WebhookPayload {
+                notification: n,
+                text: n.render(&self.template),
+            })?;
+
+            let signature = match &self.secret {
+                Some(secret) => {
+                    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+                    mac.update(&body);
+                    Some(format!(
+                        "sha256={}",
+                        hex::encode(mac.finalize().into_bytes())
+                    ))
+                }
+                None => None,
+            };
+
+            with_retries(self.retries, || async {
+                let mut request = self
+                    .http
+                    .post(&self.url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body.clone());
+                if let Some(signature) = &signature {
+                    request = request.header("X-Birbs-Signature", signature);
+                }
+
+                ok_status(request.send().await?).await
+            })
+            .await
+        })
+    }
+}
+
+/// ntfy takes the message as the body and everything else as headers.
+struct Ntfy {
+    http: reqwest::Client,
+    url: String,
+    token: Option<String>,
+    title: String,
+    template: String,
+    retries: u32,
+}
+
+impl Notifier for Ntfy {
+    fn notify<'a>(&'a self, n: &'a Notification) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            with_retries(self.retries, || async {
+                let mut request = self
+                    .http
+                    .post(&self.url)
+                    .header("Title", n.render(&self.title))
+                    .header("Attach", &n.photo_url)
+                    .header("Click", &n.spectrogram_url)
+                    .header("Tags", "bird")
+                    .body(n.render(&self.template));
+                if let Some(token) = &self.token {
+                    request = request.bearer_auth(token);
+                }
+
+                ok_status(request.send().await?).await
+            })
+            .await
+        })
+    }
+}
+
+struct Gotify {
+    http: reqwest::Client,
+    url: String,
+    token: String,
+    priority: u8,
+    title: String,
+    template: String,
+    retries: u32,
+}
+
+impl Notifier for Gotify {
+    fn notify<'a>(&'a self, n: &'a Notification) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let url = format!("{}/message", self.url.trim_end_matches('/'));
+            let body = serde_json::json!({
+                "title": n.render(&self.title),
+                "message": n.render(&self.template),
+                "priority": self.priority,
+                "extras": {
+                    "client::notification": {
+                        "bigImageUrl": n.photo_url,
+                        "click": { "url": n.spectrogram_url },
+                    },
+                },
+            });
+
+            with_retries(self.retries, || async {
+                let request = self
+                    .http
+                    .post(&url)
+                    .header("X-Gotify-Key", &self.token)
+                    .json(&body);
+
+                ok_status(request.send().await?).await
+            })
+            .await
+        })
+    }
+}
+
+struct Email {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    from: String,
+    to: Vec<String>,
+    subject: String,
+    template: String,
+    retries: u32,
+}
+
+impl Notifier for Email {
+    fn notify<'a>(&'a self, n: &'a Notification) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut message = Message::builder()
+                .from(self.from.parse()?)
+                .subject(n.render(&self.subject));
+            for to in self.to.iter() {
+                message = message.to(to.parse()?);
+            }
+            let message = message.body(n.render(&self.template))?;
+
+            with_retries(self.retries, || async {
+                self.mailer.send(message.clone()).await?;
+                Ok(())
+            })
+            .await
+        })
+    }
+}
+
+struct CommandHook {
+    program: String,
+    args: Vec<String>,
+}
+
+impl Notifier for CommandHook {
+    fn notify<'a>(&'a self, n: &'a Notification) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut child = tokio::process::Command::new(&self.program)
+                .args(self.args.iter().map(|a| n.render(a)))
+                .stdin(std::process::Stdio::piped())
+                .spawn()?;
+
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(&serde_json::to_vec(n)?).await?;
+            }
+
+            let status = child.wait().await?;
+            if status.success() {
+                Ok(())
+            } else {
+                Err(anyhow!("{} exited with {}", self.program, status))
+            }
+        })
+    }
+}
+
+pub fn new_notifier(config: &SinkConfig) -> Result<Box<dyn Notifier>> {
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    let or_default =
+        |t: &Option<String>, default: &str| t.clone().unwrap_or_else(|| default.into());
+
+    Ok(match config {
+        SinkConfig::Webhook {
+            url,
+            secret,
+            template,
+            retries,
+        } => Box::new(Webhook {
+            http,
+            url: url.clone(),
+            secret: secret.clone(),
+            template: or_default(template, DEFAULT_TEMPLATE),
+            retries: *retries,
+        }),
+        SinkConfig::Ntfy {
+            url,
+            token,
+            title,
+            template,
+            retries,
+        } => Box::new(Ntfy {
+            http,
+            url: url.clone(),
+            token: token.clone(),
+            title: or_default(title, DEFAULT_TITLE),
+            template: or_default(template, DEFAULT_TEMPLATE),
+            retries: *retries,
+        }),
+        SinkConfig::Gotify {
+            url,
+            token,
+            priority,
+            title,
+            template,
+            retries,
+        } => Box::new(Gotify {
+            http,
+            url: url.clone(),
+            token: token.clone(),
+            priority: priority.unwrap_or(5),
+            title: or_default(title, DEFAULT_TITLE),
+            template: or_default(template, DEFAULT_TEMPLATE),
+            retries: *retries,
+        }),
+        SinkConfig::Email {
+            host,
+            port,
+            username,
+            password,
+            starttls,
+            from,
+            to,
+            subject,
+            template,
+            retries,
+        } => {
+            let mut mailer = if *starttls {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+            } else {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+            };
+            if let Some(port) = port {
+                mailer = mailer.port(*port);
+            }
+            if let (Some(username), Some(password)) = (username, password) {
+                mailer = mailer.credentials(Credentials::new(username.clone(), password.clone()));
+            }
+
+            Box::new(Email {
+                mailer: mailer.build(),
+                from: from.clone(),
+                to: to.clone(),
+                subject: or_default(subject, DEFAULT_TITLE),
+                template: or_default(template, DEFAULT_TEMPLATE),
+                retries: *retries,
+            })
+        }
+        SinkConfig::Command { program, args } => Box::new(CommandHook {
+            program: program.clone(),
+            args: args.clone(),
+        }),
+    })
+}
+
+pub fn new_notifiers(sinks: &[SinkConfig]) -> Result<Vec<Box<dyn Notifier>>> {
+    sinks.iter().map(new_notifier).collect()
+}
+
+/// Sends a notification through every sink concurrently, logging failures.
+pub async fn deliver(notifiers: Arc<Vec<Box<dyn Notifier>>>, notification: Notification) {
+    let sends = notifiers.iter().map(|n| n.notify(&notification));
+
+    for (i, result) in futures::future::join_all(sends)
+        .await
+        .into_iter()
+        .enumerate()
+    {
+        if let Err(e) = result {
+            warn!("sink {} failed: {:?}", i, e);
+        }
+    }
+}
+
+/// Points HTTP sinks at `http_port` and email at `smtp_port` on localhost,
+/// keeping paths so they're recognizable in the stand-in's output.
+pub fn redirect_to_stand_in(
+    sink: &SinkConfig,
+    http_port: u16,
+    smtp_port: u16,
+) -> Result<SinkConfig> {
+    let local = |url: &str| -> Result<String> {
+        let mut url = Url::parse(url)?;
+        url.set_scheme("http")
+            .map_err(|_| anyhow!("unable to change scheme"))?;
+        url.set_host(Some("127.0.0.1"))?;
+        url.set_port(Some(http_port))
+            .map_err(|_| anyhow!("unable to change port"))?;
+        Ok(url.to_string())
+    };
+
+    let mut sink = sink.clone();
+
+    match &mut sink {
+        SinkConfig::Webhook { url, .. }
+        | SinkConfig::Ntfy { url, .. }
+        | SinkConfig::Gotify { url, .. } => *url = local(url)?,
+        SinkConfig::Email {
+            host,
+            port,
+            username,
+            password,
+            starttls,
+            ..
+        } => {
+            *host = "127.0.0.1".into();
+            *port = Some(smtp_port);
+            *starttls = false;
+            *username = None;
+            *password = None;
+        }
+        SinkConfig::Command { .. } => {}
+    }
+
+    Ok(sink)
+}
+
+/// What a stand-in received, one HTTP request or email at a time.
+pub type Received = mpsc::UnboundedReceiver<String>;
+
+/// Accepts any HTTP request and prints it, returning the port it's listening on.
+pub async fn http_stand_in() -> Result<(u16, Received)> {
+    let (sender, received) = mpsc::unbounded_channel();
+
+    let record = |method: Method, uri: Uri, headers: HeaderMap, body: Bytes| async move {
+        let mut request = vec![format!("{} {}", method, uri)];
+        request.extend(
+            headers
+                .iter()
+                .map(|(name, value)| format!("{}: {}", name, value.to_str().unwrap_or_default())),
+        );
+        request.push(String::from_utf8_lossy(&body).into_owned());
+
+        for line in request.iter() {
+            info!("stand-in http: {}", line);
+        }
+        // Nobody listening is fine.
+        let _ = sender.send(request.join("\n"));
+
+        "ok"
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let app = axum::Router::new().fallback(record);
+
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    Ok((port, received))
+}
+
+/// Just enough SMTP to accept and print messages, returning the port it's listening on.
+pub async fn smtp_stand_in() -> Result<(u16, Received)> {
+    let (sender, received) = mpsc::unbounded_channel();
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+
+    async fn session(
+        stream: tokio::net::TcpStream,
+        sender: mpsc::UnboundedSender<String>,
+    ) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut data: Option<Vec<String>> = None;
+
+        writer.write_all(b"220 birbs stand-in\r\n").await?;
+
+        while let Some(line) = lines.next_line().await? {
+            info!("stand-in smtp: {}", line);
+
+            let reply: &[u8] = if let Some(message) = &mut data {
+                if line != "." {
+                    message.push(line);
+                    continue;
+                }
+                let _ = sender.send(message.join("\n"));
+                data = None;
+                b"250 ok\r\n"
+            } else {
+                match line
+                    .get(..4)
+                    .unwrap_or_default()
+                    .to_ascii_uppercase()
+                    .as_str()
+                {
+                    "DATA" => {
+                        data = Some(Vec::new());
+                        b"354 go ahead\r\n"
+                    }
+                    "QUIT" => {
+                        writer.write_all(b"221 bye\r\n").await?;
+                        return Ok(());
+                    }
+                    _ => b"250 ok\r\n",
+                }
+            };
+
+            writer.write_all(reply).await?;
+        }
+
+        Ok(())
+    }
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(session(stream, sender.clone()));
+        }
+    });
+
+    Ok((port, received))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::live::LiveDetection;
+
+    fn notification() -> Notification {
+        let alert = Alert {
+            rule: "crows".into(),
+            message: "American Crow detected".into(),
+            detection: LiveDetection {
+                when: Utc.with_ymd_and_hms(2024, 5, 1, 14, 0, 0).unwrap(),
+                common_name: "American Crow".into(),
+                scientific_name: "Corvus brachyrhynchos".into(),
+                confidence: 0.9,
+                file_name: "American_Crow-90-2024-05-01-birdnet-07:00:00.mp3".into(),
+            },
+        };
+
+        Notification::new(alert, "http://birbs.local/")
+    }
+
+    async fn send(sink: &str, http: u16, smtp: u16) {
+        let sink = toml::from_str(sink).unwrap();
+        let sink = redirect_to_stand_in(&sink, http, smtp).unwrap();
+
+        new_notifier(&sink)
+            .unwrap()
+            .notify(&notification())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn renders_templates() {
+        let n = notification();
+
+        assert_eq!(
+            n.render("{rule}: {common_name} ({scientific_name}) {confidence} at {when}"),
+            "crows: American Crow (Corvus brachyrhynchos) 0.90 at 2024-05-01T07:00:00-07:00"
+        );
+        assert_eq!(
+            n.spectrogram_url,
+            "http://192.168.0.164/By_Date/2024-05-01/American_Crow/American_Crow-90-2024-05-01-birdnet-07:00:00.mp3.png"
+        );
+    }
+
+    #[tokio::test]
+    async fn signs_webhooks() {
+        let (http, mut received) = http_stand_in().await.unwrap();
+
+        send(
+            r#"
+            kind = "webhook"
+            url = "https://example.com/birbs?station=home"
+            secret = "shh"
+            template = "{message}!"
+            "#,
+            http,
+            0,
+        )
+        .await;
+
+        let request = received.recv().await.unwrap();
+        let (head, body) = request.rsplit_once('\n').unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"shh").unwrap();
+        mac.update(body.as_bytes());
+
+        assert!(head.starts_with("POST /birbs?station=home\n"));
+        assert!(head.contains(&format!(
+            "x-birbs-signature: sha256={}",
+            hex::encode(mac.finalize().into_bytes())
+        )));
+        let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(payload["rule"], "crows");
+        assert_eq!(payload["text"], "American Crow detected!");
+    }
+
+    #[tokio::test]
+    async fn pushes_to_ntfy_and_gotify() {
+        let (http, mut received) = http_stand_in().await.unwrap();
+
+        send(
+            r#"
+            kind = "ntfy"
+            url = "https://ntfy.sh/birbs"
+            token = "secret"
+            template = "{common_name}"
+            "#,
+            http,
+            0,
+        )
+        .await;
+
+        let request = received.recv().await.unwrap();
+        assert!(request.starts_with("POST /birbs\n"));
+        assert!(request.contains("\ntitle: birbs: crows\n"));
+        assert!(request.contains("\nauthorization: Bearer secret\n"));
+        assert!(request.contains("\nattach: http://birbs.local/amecro/photo.png\n"));
+        assert!(request.ends_with("\nAmerican Crow"));
+
+        send(
+            r#"
+            kind = "gotify"
+            url = "https://gotify.example.com/"
+            token = "app"
+            priority = 8
+            "#,
+            http,
+            0,
+        )
+        .await;
+
+        let request = received.recv().await.unwrap();
+        let (head, body) = request.rsplit_once('\n').unwrap();
+        assert!(head.starts_with("POST /message\n"));
+        assert!(head.contains("\nx-gotify-key: app"));
+        let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(payload["title"], "birbs: crows");
+        assert_eq!(payload["priority"], 8);
+    }
+
+    #[tokio::test]
+    async fn emails() {
+        let (smtp, mut received) = smtp_stand_in().await.unwrap();
+
+        send(
+            r#"
+            kind = "email"
+            host = "smtp.example.com"
+            port = 587
+            starttls = true
+            username = "birbs"
+            password = "secret"
+            from = "birbs@example.com"
+            to = ["me@example.com", "you@example.com"]
+            subject = "{common_name}!"
+            template = "{message}"
+            "#,
+            0,
+            smtp,
+        )
+        .await;
+
+        let message = received.recv().await.unwrap();
+        assert!(message.contains("Subject: American Crow!"));
+        assert!(message.contains("To: me@example.com, you@example.com"));
+        assert!(message.contains("American Crow detected"));
+    }
+
+    #[tokio::test]
+    async fn runs_commands() {
+        let sink = toml::from_str(
+            r#"
+            kind = "command"
+            program = "sh"
+            args = ["-c", "test \"$0\" = 'American Crow' && grep -q crows", "{common_name}"]
+            "#,
+        )
+        .unwrap();
+
+        let notifier = new_notifier(&redirect_to_stand_in(&sink, 0, 0).unwrap()).unwrap();
+        notifier.notify(&notification()).await.unwrap();
+    }
+}
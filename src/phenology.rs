@@ -0,0 +1,274 @@
+use anyhow::Result;
+use axum::extract::Query;
+use axum::{http::StatusCode, Json};
+use chrono::{Datelike, NaiveDate};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::review::ReviewFilter;
+use crate::taxonomy::LangQuery;
+use crate::{species, week_of_year, BirdDb};
+
+fn default_min_confidence() -> f32 {
+    0.7
+}
+
+fn default_min_daily() -> u32 {
+    2
+}
+
+#[derive(Debug, Args, Deserialize, Clone)]
+pub struct Command {
+    #[arg(long, default_value_t = 0.7)]
+    #[serde(default = "default_min_confidence")]
+    min_confidence: f32,
+    /// Days with fewer detections of a species than this are ignored, one-off
+    /// false positives would otherwise make for very early arrivals.
+    #[arg(long, default_value_t = 2)]
+    #[serde(default = "default_min_daily")]
+    min_daily: u32,
+    /// Only report this year, still compared against the years before it.
+    #[arg(long)]
+    year: Option<i32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Season {
+    year: i32,
+    first: NaiveDate,
+    last: NaiveDate,
+    /// Days the species was detected enough to count.
+    days: u32,
+    detections: u32,
+    /// Week of the year with the most detections, the first starting January 1st.
+    peak_week: u32,
+    /// Days earlier (negative) or later than the average first detection in
+    /// prior years, when there are any.
+    first_vs_prior: Option<f64>,
+    last_vs_prior: Option<f64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Phenology {
+    slug: String,
+    common_name: String,
+    scientific_name: String,
+    years: Vec<Season>,
+}
+
+fn average(values: &[u32]) -> Option<f64> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().sum::<u32>() as f64 / values.len() as f64)
+    }
+}
+
+/// The day of the year the date falls on in a leap year, so the same date
+/// compares equal whether or not its year has a February 29th.
+fn day_of_year(date: NaiveDate) -> u32 {
+    NaiveDate::from_ymd_opt(2000, date.month(), date.day())
+        .expect("every date exists in a leap year")
+        .ordinal()
+}
+
+fn seasons(days: &BTreeMap<NaiveDate, u32>) -> Vec<Season> {
+    let mut years: BTreeMap<i32, Vec<(NaiveDate, u32)>> = BTreeMap::new();
+    for (date, detections) in days {
+        years
+            .entry(date.year())
+            .or_default()
+            .push((*date, *detections));
+    }
+
+    let mut firsts = Vec::new();
+    let mut lasts = Vec::new();
+    let mut seasons = Vec::new();
+    for (year, days) in years {
+        let first = days.first().expect("years have days").0;
+        let last = days.last().expect("years have days").0;
+
+        let mut weeks: BTreeMap<u32, u32> = BTreeMap::new();
+        for (date, detections) in &days {
+            *weeks.entry(week_of_year(*date)).or_default() += detections;
+        }
+        let peak_week = weeks
+            .iter()
+            .rev()
+            .max_by_key(|(_, detections)| **detections)
+            .map(|(week, _)| *week)
+            .expect("years have weeks");
+
+        seasons.push(Season {
+            year,
+            first,
+            last,
+            days: days.len() as u32,
+            detections: days.iter().map(|(_, d)| d).sum(),
+            peak_week,
+            first_vs_prior: average(&firsts).map(|a| day_of_year(first) as f64 - a),
+            last_vs_prior: average(&lasts).map(|a| day_of_year(last) as f64 - a),
+        });
+
+        firsts.push(day_of_year(first));
+        lasts.push(day_of_year(last));
+    }
+
+    seasons
+}
+
+pub fn phenology(db: &BirdDb, options: &Command) -> Result<Vec<Phenology>> {
+    let mut by_species: BTreeMap<String, (String, BTreeMap<NaiveDate, u32>)> = BTreeMap::new();
+    for daily in db.species_daily_counts(options.min_confidence)? {
+        if daily.detections < options.min_daily {
+            continue;
+        }
+
+        let (common_name, days) = by_species
+            .entry(daily.scientific_name)
+            .or_insert_with(|| (daily.common_name.clone(), BTreeMap::new()));
+        *common_name = daily.common_name;
+        days.insert(daily.date, daily.detections);
+    }
+
+    let mut report = by_species
+        .into_iter()
+        .map(|(scientific_name, (common_name, days))| Phenology {
+            slug: species::slug(&scientific_name),
+            common_name,
+            years: seasons(&days)
+                .into_iter()
+                .filter(|s| options.year.is_none_or(|y| s.year == y))
+                .collect(),
+            scientific_name,
+        })
+        .filter(|p| !p.years.is_empty())
+        .collect::<Vec<_>>();
+
+    report.sort_by(|a, b| a.common_name.cmp(&b.common_name));
+
+    Ok(report)
+}
+
+fn days(change: Option<f64>) -> String {
+    change
+        .map(|c| format!("{:+.0}", c))
+        .unwrap_or_else(|| "-".into())
+}
+
+pub async fn execute(cmd: Command) -> Result<()> {
+    let db = BirdDb::new()?;
+
+    println!(
+        "{:<30} {:>4} {:>10} {:>10} {:>5} {:>6} {:>4} {:>6} {:>6}",
+        "species", "year", "first", "last", "days", "count", "peak", "Δfirst", "Δlast"
+    );
+
+    for species in phenology(&db, &cmd)? {
+        for season in species.years {
+            println!(
+                "{:<30} {:>4} {:>10} {:>10} {:>5} {:>6} {:>4} {:>6} {:>6}",
+                species.common_name,
+                season.year,
+                season.first,
+                season.last,
+                season.days,
+                season.detections,
+                season.peak_week,
+                days(season.first_vs_prior),
+                days(season.last_vs_prior),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[axum_macros::debug_handler]
+pub async fn phenology_json(
+    Query(options): Query<Command>,
+    Query(reviews): Query<ReviewFilter>,
+    Query(lang): Query<LangQuery>,
+) -> Result<Json<Vec<Phenology>>, StatusCode> {
+    Ok(Json(
+        tokio::task::spawn_blocking(move || -> Result<Vec<Phenology>> {
+            let mut report = phenology(&reviews.db()?, &options)?;
+
+            if let Some(translation) = lang.translation() {
+                for species in report.iter_mut() {
+                    translation.localize(Some(&species.scientific_name), &mut species.common_name);
+                }
+                report.sort_by(|a, b| a.common_name.cmp(&b.common_name));
+            }
+
+            Ok(report)
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn days(days: &[((i32, u32, u32), u32)]) -> BTreeMap<NaiveDate, u32> {
+        days.iter()
+            .map(|((y, m, d), n)| (NaiveDate::from_ymd_opt(*y, *m, *d).unwrap(), *n))
+            .collect()
+    }
+
+    #[test]
+    fn seasons_by_year() {
+        let seasons = seasons(&days(&[
+            ((2023, 4, 10), 3),
+            ((2023, 4, 11), 5),
+            ((2023, 9, 1), 2),
+            ((2024, 4, 5), 4),
+            ((2024, 4, 6), 1),
+            ((2024, 8, 30), 9),
+        ]));
+
+        assert_eq!(seasons.len(), 2);
+
+        let first = &seasons[0];
+        assert_eq!(first.year, 2023);
+        assert_eq!(first.days, 3);
+        assert_eq!(first.detections, 10);
+        assert_eq!(first.peak_week, 15);
+        assert_eq!(first.first_vs_prior, None);
+
+        // By the calendar, not by day of the year, 2024 has a February 29th.
+        let second = &seasons[1];
+        assert_eq!(second.peak_week, 35);
+        assert_eq!(second.first_vs_prior, Some(-5.0));
+        assert_eq!(second.last_vs_prior, Some(-2.0));
+    }
+
+    #[test]
+    fn compares_the_same_date_across_leap_years() {
+        let seasons = seasons(&days(&[
+            ((2023, 3, 1), 2),
+            ((2023, 12, 31), 2),
+            ((2024, 3, 1), 2),
+            ((2024, 12, 31), 2),
+        ]));
+
+        assert_eq!(seasons[1].first_vs_prior, Some(0.0));
+        assert_eq!(seasons[1].last_vs_prior, Some(0.0));
+    }
+
+    #[test]
+    fn peak_weeks_start_on_january_first() {
+        // The 1st is in ISO week 53 of 2020, the week of the 4th is in week 1.
+        let seasons = seasons(&days(&[
+            ((2021, 1, 1), 5),
+            ((2021, 1, 4), 3),
+            ((2021, 12, 31), 1),
+        ]));
+
+        assert_eq!(seasons[0].peak_week, 1);
+    }
+}
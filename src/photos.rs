@@ -0,0 +1,768 @@
+use anyhow::{anyhow, Result};
+use clap::{Args, Subcommand};
+use image::imageops::FilterType;
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::providers::{self, ProviderPhoto, SpeciesImageProvider};
+use crate::species::{self, Lookup, Species};
+use crate::store::Store;
+use crate::{serve, BirdDb};
+
+#[derive(Debug, Args)]
+pub struct Command {
+    #[command(subcommand)]
+    command: PhotosCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PhotosCommand {
+    /// Lists the photos that could be chosen for a species, best first.
+    Candidates { species: String },
+    /// Uses a particular photo for a species.
+    Pin {
+        species: String,
+        photo_id: String,
+        #[arg(long, default_value = "flickr")]
+        source: String,
+    },
+    /// Uses one of our own photos for a species.
+    Upload {
+        species: String,
+        file: PathBuf,
+        #[command(flatten)]
+        attribution: Attribution,
+    },
+    /// Never uses a photo for a species again, choosing another if it's the current one.
+    Block {
+        species: String,
+        photo_id: String,
+        #[arg(long, default_value = "flickr")]
+        source: String,
+    },
+}
+
+pub async fn execute(cmd: Command) -> Result<()> {
+    let photos = PhotoCache::new(providers::new_providers(serve::new_http_client()))?;
+
+    match cmd.command {
+        PhotosCommand::Candidates { species } => {
+            for candidate in photos.candidates(&lookup(&species)?).await? {
+                println!("{}", serde_json::to_string(&candidate)?);
+            }
+        }
+        PhotosCommand::Pin {
+            species,
+            photo_id,
+            source,
+        } => {
+            let photo = photos.pin(&lookup(&species)?, &source, &photo_id).await?;
+            println!("{}", serde_json::to_string(&photo)?);
+        }
+        PhotosCommand::Upload {
+            species,
+            file,
+            attribution,
+        } => {
+            let content_type = match file
+                .extension()
+                .map(|e| e.to_string_lossy().to_lowercase())
+                .as_deref()
+            {
+                Some("jpg" | "jpeg") => "image/jpeg",
+                Some("png") => "image/png",
+                Some("webp") => "image/webp",
+                Some("gif") => "image/gif",
+                _ => return Err(anyhow!("unknown image type {:?}", file)),
+            };
+
+            let photo = photos.upload(
+                &lookup(&species)?,
+                &std::fs::read(&file)?,
+                content_type,
+                attribution,
+            )?;
+            println!("{}", serde_json::to_string(&photo)?);
+        }
+        PhotosCommand::Block {
+            species,
+            photo_id,
+            source,
+        } => photos.block(&lookup(&species)?, &source, &photo_id)?,
+    }
+
+    Ok(())
+}
+
+/// A species by slug or by name, like the routes take.
+fn lookup(identifier: &str) -> Result<Species> {
+    match species::lookup(&BirdDb::new()?, identifier)? {
+        Some(Lookup::Canonical(species) | Lookup::Renamed(species)) => Ok(species),
+        None => Err(anyhow!("no species {:?}", identifier)),
+    }
+}
+
+/// Where chosen photos are kept, along with their mapping when there's no
+/// birbs database configured.
+pub fn get_photos_directory() -> PathBuf {
+    std::env::var("BIRBS_PHOTOS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("photos"))
+}
+
+/// The photo chosen for a species and who to credit for it.
+#[derive(Serialize, Debug, Clone)]
+pub struct Photo {
+    pub common_name: String,
+    pub scientific_name: String,
+    pub source: String,
+    pub photo_id: String,
+    pub title: String,
+    pub owner: String,
+    pub owner_name: Option<String>,
+    pub license: Option<String>,
+    pub license_url: Option<String>,
+    pub source_url: String,
+    pub image_url: String,
+    #[serde(skip)]
+    pub file_name: String,
+    #[serde(skip)]
+    pub content_type: String,
+    /// Pinned or uploaded by hand rather than chosen from a search.
+    pub curated: bool,
+}
+
+/// Longest side in pixels, matching Flickr's `t`, `m`, default and `b` sizes.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Size {
+    Thumb,
+    Small,
+    Medium,
+    Large,
+}
+
+impl Size {
+    fn longest_side(self) -> u32 {
+        match self {
+            Size::Thumb => 100,
+            Size::Small => 240,
+            Size::Medium => 500,
+            Size::Large => 1024,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Size::Thumb => "thumb",
+            Size::Small => "small",
+            Size::Medium => "medium",
+            Size::Large => "large",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// JPEG, or PNG when that's what was uploaded.
+    #[default]
+    Original,
+    Webp,
+}
+
+fn extension(content_type: &str) -> Result<&'static str> {
+    Ok(match content_type {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/webp" => "webp",
+        "image/gif" => "gif",
+        _ => return Err(anyhow!("unsupported image type {:?}", content_type)),
+    })
+}
+
+/// Photos are kept under the species' slug and a hash of where they came
+/// from, so nothing from a request or a provider ends up in a path.
+fn photo_file_name(slug: &str, source: &str, photo_id: &str, extension: &str) -> String {
+    let readable = slug
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>();
+    let hash = Sha256::digest(format!("{}\0{}\0{}", slug, source, photo_id));
+
+    format!("{}-{}.{}", readable, hex::encode(&hash[..8]), extension)
+}
+
+/// Makes sure an uploaded image is one we can serve and resize, reading no
+/// more than its header.
+pub fn check_upload(bytes: &[u8], content_type: &str) -> Result<()> {
+    extension(content_type)?;
+
+    let format = image::ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .format();
+    if format != ImageFormat::from_mime_type(content_type) {
+        return Err(anyhow!("not a {:?} image", content_type));
+    }
+
+    image::ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_dimensions()?;
+
+    Ok(())
+}
+
+/// Writes beside the final file and renames over it, so readers never see a
+/// partial image. The temporary name is unique to the file and the writer,
+/// the same photo is often resized to several formats at once.
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    std::fs::write(&temporary, bytes)?;
+    if let Err(e) = std::fs::rename(&temporary, path) {
+        let _ = std::fs::remove_file(&temporary);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+fn stem(file_name: &str) -> &str {
+    file_name
+        .rsplit_once('.')
+        .map(|(stem, _)| stem)
+        .unwrap_or(file_name)
+}
+
+/// A photo that could be chosen for a species.
+#[derive(Serialize, Debug)]
+pub struct Candidate {
+    source: String,
+    photo_id: String,
+    title: String,
+    owner_name: Option<String>,
+    license: Option<String>,
+    image_url: String,
+    source_url: String,
+    chosen: bool,
+}
+
+/// Credit for an uploaded photo.
+#[derive(Deserialize, Args, Debug, Default)]
+pub struct Attribution {
+    #[arg(long, default_value = "")]
+    #[serde(default)]
+    pub title: String,
+    #[arg(long, default_value = "")]
+    #[serde(default)]
+    pub owner: String,
+    #[arg(long)]
+    pub license: Option<String>,
+    #[arg(long)]
+    pub license_url: Option<String>,
+    #[arg(long)]
+    pub source_url: Option<String>,
+}
+
+/// Chooses a photo for each species once and keeps it, so providers are only
+/// searched the first time a species is asked for.
+pub struct PhotoCache {
+    directory: PathBuf,
+    providers: Vec<Box<dyn SpeciesImageProvider>>,
+    choosing: Mutex<()>,
+}
+
+impl PhotoCache {
+    pub fn new(providers: Vec<Box<dyn SpeciesImageProvider>>) -> Result<Self> {
+        let directory = get_photos_directory();
+        std::fs::create_dir_all(&directory)?;
+
+        Ok(Self {
+            directory,
+            providers,
+            choosing: Mutex::new(()),
+        })
+    }
+
+    fn store(&self) -> Result<Store> {
+        Store::new().or_else(|_| Store::open(self.directory.join("photos.db")))
+    }
+
+    fn provider(&self, source: &str) -> Result<&dyn SpeciesImageProvider> {
+        self.providers
+            .iter()
+            .find(|p| p.name() == source)
+            .map(|p| p.as_ref())
+            .ok_or_else(|| anyhow!("photo provider {:?} isn't configured", source))
+    }
+
+    /// A provider's photos of a species, best first, without any that are blocked.
+    async fn search(
+        &self,
+        provider: &dyn SpeciesImageProvider,
+        species: &Species,
+    ) -> Result<Vec<ProviderPhoto>> {
+        let blocked = self
+            .store()?
+            .blocked_photos(&species.scientific_name, provider.name())?;
+
+        Ok(provider
+            .search(Some(&species.scientific_name), &species.common_name)
+            .await?
+            .into_iter()
+            .filter(|p| !blocked.contains(&p.id))
+            .collect())
+    }
+
+    /// The species' photo, choosing and downloading one if it hasn't been yet.
+    /// Providers are tried in order until one has a photo we can keep.
+    pub async fn photo(&self, species: &Species) -> Result<Option<Photo>> {
+        if let Some(photo) = self.store()?.photo(&species.scientific_name)? {
+            return Ok(Some(photo));
+        }
+
+        let _choosing = self.choosing.lock().await;
+
+        // Somebody else may have chosen one while we were waiting.
+        if let Some(photo) = self.store()?.photo(&species.scientific_name)? {
+            return Ok(Some(photo));
+        }
+
+        for provider in self.providers.iter() {
+            let photos = match self.search(provider.as_ref(), species).await {
+                Ok(photos) => photos,
+                Err(e) => {
+                    warn!(
+                        "searching {} for {:?}: {:?}",
+                        provider.name(),
+                        species.scientific_name,
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            if let Some(chosen) = photos.into_iter().next() {
+                let source_url = chosen.source_url.clone();
+                match self.save(provider.as_ref(), species, chosen, false).await {
+                    Ok(photo) => {
+                        info!(
+                            "chose {} for {:?}",
+                            photo.source_url, species.scientific_name
+                        );
+
+                        return Ok(Some(photo));
+                    }
+                    // Usually an image type we don't keep, like an SVG.
+                    Err(e) => warn!(
+                        "saving {} for {:?}: {:?}",
+                        source_url, species.scientific_name, e
+                    ),
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Every provider's photos of a species, in provider order, leaving out
+    /// any provider that can't be searched right now.
+    pub async fn candidates(&self, species: &Species) -> Result<Vec<Candidate>> {
+        let chosen = self
+            .store()?
+            .photo(&species.scientific_name)?
+            .map(|p| (p.source, p.photo_id));
+
+        let mut candidates = Vec::new();
+        for provider in self.providers.iter() {
+            let photos = match self.search(provider.as_ref(), species).await {
+                Ok(photos) => photos,
+                Err(e) => {
+                    warn!(
+                        "searching {} for {:?}: {:?}",
+                        provider.name(),
+                        species.scientific_name,
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            candidates.extend(photos.into_iter().map(|p| {
+                Candidate {
+                    chosen: chosen
+                        .as_ref()
+                        .is_some_and(|(source, id)| *source == p.source && *id == p.id),
+                    source: p.source,
+                    source_url: p.source_url,
+                    image_url: p.image_url,
+                    license: p.license,
+                    photo_id: p.id,
+                    title: p.title,
+                    owner_name: p.owner_name,
+                }
+            }));
+        }
+
+        Ok(candidates)
+    }
+
+    /// Uses a particular photo from a provider, whether or not it'd be chosen.
+    pub async fn pin(&self, species: &Species, source: &str, photo_id: &str) -> Result<Photo> {
+        let provider = self.provider(source)?;
+        let _choosing = self.choosing.lock().await;
+
+        let photo = provider
+            .photo(
+                Some(&species.scientific_name),
+                &species.common_name,
+                photo_id,
+            )
+            .await?;
+        let photo = self.save(provider, species, photo, true).await?;
+
+        info!(
+            "pinned {} for {:?}",
+            photo.source_url, species.scientific_name
+        );
+
+        Ok(photo)
+    }
+
+    pub fn upload(
+        &self,
+        species: &Species,
+        bytes: &[u8],
+        content_type: &str,
+        attribution: Attribution,
+    ) -> Result<Photo> {
+        check_upload(bytes, content_type)?;
+
+        let photo_id = hex::encode(&Sha256::digest(bytes)[..8]);
+        let file_name =
+            photo_file_name(&species.slug, "upload", &photo_id, extension(content_type)?);
+        self.write(&file_name, bytes)?;
+
+        let photo = Photo {
+            common_name: species.common_name.clone(),
+            scientific_name: species.scientific_name.clone(),
+            source: "upload".into(),
+            title: attribution.title,
+            owner: attribution.owner,
+            owner_name: None,
+            license: attribution.license,
+            license_url: attribution.license_url,
+            source_url: attribution.source_url.unwrap_or_default(),
+            image_url: String::new(),
+            photo_id,
+            file_name,
+            content_type: content_type.to_owned(),
+            curated: true,
+        };
+
+        self.replace(&photo)?;
+
+        info!(
+            "uploaded {} for {:?}",
+            photo.file_name, species.scientific_name
+        );
+
+        Ok(photo)
+    }
+
+    /// Blocks a photo for a species. When it's the current one, it's
+    /// forgotten so another is chosen the next time it's asked for.
+    pub fn block(&self, species: &Species, source: &str, photo_id: &str) -> Result<()> {
+        let store = self.store()?;
+        store.block_photo(&species.scientific_name, source, photo_id)?;
+
+        if let Some(current) = store.photo(&species.scientific_name)? {
+            if current.source == source && current.photo_id == photo_id {
+                store.forget_photo(&species.scientific_name)?;
+                self.remove(&current);
+            }
+        }
+
+        info!(
+            "blocked {} {} for {:?}",
+            source, photo_id, species.scientific_name
+        );
+
+        Ok(())
+    }
+
+    async fn save(
+        &self,
+        provider: &dyn SpeciesImageProvider,
+        species: &Species,
+        chosen: ProviderPhoto,
+        curated: bool,
+    ) -> Result<Photo> {
+        let image = provider.image(&chosen).await?;
+        let file_name = photo_file_name(
+            &species.slug,
+            &chosen.source,
+            &chosen.id,
+            extension(&image.content_type)?,
+        );
+        self.write(&file_name, &image.bytes)?;
+
+        let photo = Photo {
+            common_name: species.common_name.clone(),
+            scientific_name: species.scientific_name.clone(),
+            source: chosen.source,
+            photo_id: chosen.id,
+            title: chosen.title,
+            owner: chosen.owner,
+            owner_name: chosen.owner_name,
+            license: chosen.license,
+            license_url: chosen.license_url,
+            source_url: chosen.source_url,
+            image_url: chosen.image_url,
+            file_name,
+            content_type: image.content_type,
+            curated,
+        };
+
+        self.replace(&photo)?;
+
+        Ok(photo)
+    }
+
+    /// Saves the species' photo, removing the file of the one it replaces.
+    fn replace(&self, photo: &Photo) -> Result<()> {
+        let store = self.store()?;
+        let previous = store.photo(&photo.scientific_name)?;
+        store.save_photo(photo)?;
+
+        if let Some(previous) = previous.filter(|p| p.file_name != photo.file_name) {
+            self.remove(&previous);
+        }
+
+        Ok(())
+    }
+
+    /// Where a photo is kept, refusing anything that isn't a plain file name.
+    fn path(&self, file_name: &str) -> Result<PathBuf> {
+        let mut components = Path::new(file_name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Ok(self.directory.join(file_name)),
+            _ => Err(anyhow!("bad photo file name {:?}", file_name)),
+        }
+    }
+
+    pub fn image(&self, photo: &Photo) -> Result<Vec<u8>> {
+        std::fs::read(self.path(&photo.file_name)?)
+            .map_err(|e| anyhow!("reading {:?}: {}", photo.file_name, e))
+    }
+
+    fn write(&self, file_name: &str, bytes: &[u8]) -> Result<()> {
+        write_atomically(&self.path(file_name)?, bytes)
+    }
+
+    /// The photo at a size and format, resized from the original the first
+    /// time it's asked for and kept alongside it. Returns the content type too.
+    pub fn sized_image(
+        &self,
+        photo: &Photo,
+        size: Option<Size>,
+        format: Format,
+    ) -> Result<(String, Vec<u8>)> {
+        if size.is_none() && format == Format::Original {
+            return Ok((photo.content_type.clone(), self.image(photo)?));
+        }
+
+        let output = match (format, photo.content_type.as_str()) {
+            (Format::Webp, _) => ImageFormat::WebP,
+            (Format::Original, "image/png") => ImageFormat::Png,
+            (Format::Original, _) => ImageFormat::Jpeg,
+        };
+        self.path(&photo.file_name)?;
+        let path = self.directory.join("sizes").join(format!(
+            "{}-{}.{}",
+            stem(&photo.file_name),
+            size.map(|s| s.name()).unwrap_or("original"),
+            output.extensions_str()[0]
+        ));
+        let content_type = output.to_mime_type().to_owned();
+
+        if let Ok(bytes) = std::fs::read(&path) {
+            return Ok((content_type, bytes));
+        }
+
+        let mut image = image::load_from_memory(&self.image(photo)?)?;
+        if let Some(size) = size {
+            let longest = size.longest_side();
+            // Never scaled up, a small original is as large as it gets.
+            if image.width().max(image.height()) > longest {
+                image = image.resize(longest, longest, FilterType::Lanczos3);
+            }
+        }
+        if output == ImageFormat::Jpeg {
+            image = image.to_rgb8().into();
+        }
+
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, output)?;
+        let bytes = bytes.into_inner();
+
+        std::fs::create_dir_all(self.directory.join("sizes"))?;
+        write_atomically(&path, &bytes)?;
+
+        Ok((content_type, bytes))
+    }
+
+    fn remove(&self, photo: &Photo) {
+        let Ok(path) = self.path(&photo.file_name) else {
+            return;
+        };
+
+        // Only ever a stale file left behind if these fail.
+        let _ = std::fs::remove_file(path);
+
+        let prefix = format!("{}-", stem(&photo.file_name));
+        if let Ok(sizes) = std::fs::read_dir(self.directory.join("sizes")) {
+            for entry in sizes.flatten() {
+                if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_stay_in_the_directory() {
+        for (slug, source, id) in [
+            ("amecro", "flickr", "1234"),
+            ("../../etc/passwd", "upload", "../x"),
+            ("Crow/Raven", "flickr", "a\\b"),
+        ] {
+            let file_name = photo_file_name(slug, source, id, "jpg");
+            let mut components = Path::new(&file_name).components();
+
+            assert!(matches!(components.next(), Some(Component::Normal(_))));
+            assert!(components.next().is_none());
+            assert!(file_name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.'));
+        }
+
+        assert_ne!(
+            photo_file_name("amecro", "flickr", "1", "jpg"),
+            photo_file_name("amecro", "flickr", "2", "jpg")
+        );
+    }
+
+    #[test]
+    fn writes_replace_whole_files() {
+        let directory = std::env::temp_dir().join(format!("birbs-photos-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let jpeg = directory.join("crow-thumb.jpg");
+        let webp = directory.join("crow-thumb.webp");
+        write_atomically(&jpeg, b"jpeg").unwrap();
+        write_atomically(&webp, b"webp").unwrap();
+        write_atomically(&jpeg, b"jpeg again").unwrap();
+
+        assert_eq!(std::fs::read(&jpeg).unwrap(), b"jpeg again");
+        assert_eq!(std::fs::read(&webp).unwrap(), b"webp");
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 2);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn resizes_and_converts_photos() {
+        let directory =
+            std::env::temp_dir().join(format!("birbs-photo-sizes-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let cache = PhotoCache {
+            directory: directory.clone(),
+            providers: Vec::new(),
+            choosing: Mutex::new(()),
+        };
+
+        let mut png = Cursor::new(Vec::new());
+        image::RgbImage::new(300, 200)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let file_name = photo_file_name("amecro", "upload", "1", "png");
+        cache.write(&file_name, &png.into_inner()).unwrap();
+        let photo = Photo {
+            common_name: "American Crow".into(),
+            scientific_name: "Corvus brachyrhynchos".into(),
+            source: "upload".into(),
+            photo_id: "1".into(),
+            title: String::new(),
+            owner: String::new(),
+            owner_name: None,
+            license: None,
+            license_url: None,
+            source_url: String::new(),
+            image_url: String::new(),
+            file_name,
+            content_type: "image/png".into(),
+            curated: true,
+        };
+
+        let (content_type, thumb) = cache
+            .sized_image(&photo, Some(Size::Thumb), Format::Webp)
+            .unwrap();
+        let thumb = image::load_from_memory(&thumb).unwrap();
+        assert_eq!(content_type, "image/webp");
+        assert_eq!((thumb.width(), thumb.height()), (100, 67));
+
+        let (content_type, large) = cache
+            .sized_image(&photo, Some(Size::Large), Format::Original)
+            .unwrap();
+        let large = image::load_from_memory(&large).unwrap();
+        assert_eq!(content_type, "image/png");
+        assert_eq!((large.width(), large.height()), (300, 200));
+
+        cache.remove(&photo);
+        assert_eq!(
+            std::fs::read_dir(directory.join("sizes")).unwrap().count(),
+            0
+        );
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn checks_uploads() {
+        let mut png = Cursor::new(Vec::new());
+        image::RgbImage::new(2, 2)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+
+        assert!(check_upload(&png, "image/png").is_ok());
+        assert!(check_upload(&png, "image/jpeg").is_err());
+        assert!(check_upload(&png, "text/plain").is_err());
+        assert!(check_upload(b"nope", "image/png").is_err());
+    }
+}
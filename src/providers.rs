@@ -0,0 +1,655 @@
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use reqwest_middleware::ClientWithMiddleware;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::warn;
+
+use crate::flickr::FlickrClient;
+use crate::get_flickr_api_key;
+
+/// Which providers to search for species photos and in what order, comma
+/// separated. Providers that aren't configured, like Flickr without an API
+/// key, are skipped.
+pub fn get_photo_providers() -> String {
+    std::env::var("BIRBS_PHOTO_PROVIDERS").unwrap_or_else(|_| "flickr,inaturalist,wikimedia".into())
+}
+
+fn get_inaturalist_api_url() -> String {
+    std::env::var("INATURALIST_API_URL").unwrap_or_else(|_| "https://api.inaturalist.org/v1".into())
+}
+
+fn get_wikimedia_api_url() -> String {
+    std::env::var("WIKIMEDIA_API_URL")
+        .unwrap_or_else(|_| "https://commons.wikimedia.org/w/api.php".into())
+}
+
+/// A photo a provider could supply for a species, and who to credit for it.
+#[derive(Debug, Clone)]
+pub struct ProviderPhoto {
+    pub source: String,
+    pub id: String,
+    pub title: String,
+    pub owner: String,
+    pub owner_name: Option<String>,
+    pub license: Option<String>,
+    pub license_url: Option<String>,
+    /// The photo's own page, where attribution should link to.
+    pub source_url: String,
+    pub image_url: String,
+}
+
+pub struct Image {
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+
+pub trait SpeciesImageProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Photos of a species, best first.
+    fn search<'a>(
+        &'a self,
+        scientific_name: Option<&'a str>,
+        common_name: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<ProviderPhoto>>>;
+
+    /// A particular photo of a species, by the provider's id for it.
+    fn photo<'a>(
+        &'a self,
+        scientific_name: Option<&'a str>,
+        common_name: &'a str,
+        id: &'a str,
+    ) -> BoxFuture<'a, Result<ProviderPhoto>> {
+        Box::pin(async move {
+            self.search(scientific_name, common_name)
+                .await?
+                .into_iter()
+                .find(|p| p.id == id)
+                .ok_or_else(|| anyhow!("no {} photo {} of {}", self.name(), id, common_name))
+        })
+    }
+
+    fn image<'a>(&'a self, photo: &'a ProviderPhoto) -> BoxFuture<'a, Result<Image>>;
+}
+
+pub async fn download(http: &ClientWithMiddleware, url: &str) -> Result<Image> {
+    let response = http.get(url).send().await?.error_for_status()?;
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .unwrap_or("image/jpeg")
+        .trim()
+        .to_owned();
+
+    Ok(Image {
+        content_type,
+        bytes: response.bytes().await?.into(),
+    })
+}
+
+/// The configured providers, in the order they should be tried.
+pub fn new_providers(http: ClientWithMiddleware) -> Vec<Box<dyn SpeciesImageProvider>> {
+    get_photo_providers()
+        .split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| -> Option<Box<dyn SpeciesImageProvider>> {
+            match name {
+                "flickr" => match get_flickr_api_key() {
+                    Ok(key) => Some(Box::new(FlickrClient::new(&key, http.clone()))),
+                    Err(_) => {
+                        warn!("no FLICKR_API_KEY, skipping flickr photos");
+                        None
+                    }
+                },
+                "inaturalist" => Some(Box::new(INaturalist {
+                    http: http.clone(),
+                    base_url: get_inaturalist_api_url(),
+                })),
+                "wikimedia" => Some(Box::new(Wikimedia {
+                    http: http.clone(),
+                    base_url: get_wikimedia_api_url(),
+                })),
+                _ => {
+                    warn!("unknown photo provider {:?}", name);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// iNaturalist's license codes, `cc-by-nc` and friends, as names and deeds.
+/// Photos have been licensed under more than one version, which is only known
+/// when iNaturalist links the deed, otherwise it's left off.
+fn creative_commons(code: &str, license_url: Option<&str>) -> (String, String) {
+    if code == "cc0" {
+        return (
+            "CC0 1.0".into(),
+            "https://creativecommons.org/publicdomain/zero/1.0/".into(),
+        );
+    }
+
+    let kind = code.trim_start_matches("cc-");
+    let version = license_url
+        .and_then(|url| {
+            url.trim_end_matches('/')
+                .rsplit_once(&format!("/licenses/{}/", kind))
+        })
+        .map(|(_, version)| version)
+        .filter(|v| !v.is_empty() && v.chars().all(|c| c.is_ascii_digit() || c == '.'));
+
+    match version {
+        Some(version) => (
+            format!("CC {} {}", kind.to_uppercase(), version),
+            format!("https://creativecommons.org/licenses/{}/{}/", kind, version),
+        ),
+        None => (
+            format!("CC {}", kind.to_uppercase()),
+            format!("https://creativecommons.org/licenses/{}/", kind),
+        ),
+    }
+}
+
+/// Taxon photos curated by iNaturalist's community.
+pub struct INaturalist {
+    http: ClientWithMiddleware,
+    base_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TaxaPayload {
+    results: Vec<Taxon>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Taxon {
+    id: u64,
+    #[serde(default)]
+    taxon_photos: Vec<TaxonPhoto>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TaxonPhoto {
+    photo: INaturalistPhoto,
+}
+
+#[derive(Deserialize, Debug)]
+struct INaturalistPhoto {
+    id: u64,
+    license_code: Option<String>,
+    #[serde(default)]
+    license_url: Option<String>,
+    attribution: String,
+    url: Option<String>,
+    medium_url: Option<String>,
+    large_url: Option<String>,
+    native_page_url: Option<String>,
+}
+
+impl INaturalist {
+    async fn taxon(&self, name: &str) -> Result<Option<Taxon>> {
+        let taxa = self
+            .http
+            .get(format!("{}/taxa", self.base_url))
+            .query(&[("q", name), ("rank", "species"), ("per_page", "1")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<TaxaPayload>()
+            .await?;
+
+        let Some(taxon) = taxa.results.into_iter().next() else {
+            return Ok(None);
+        };
+
+        // Only the full taxon has all its photos rather than the default one.
+        let taxa = self
+            .http
+            .get(format!("{}/taxa/{}", self.base_url, taxon.id))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<TaxaPayload>()
+            .await?;
+
+        Ok(taxa.results.into_iter().next())
+    }
+}
+
+impl SpeciesImageProvider for INaturalist {
+    fn name(&self) -> &'static str {
+        "inaturalist"
+    }
+
+    fn search<'a>(
+        &'a self,
+        scientific_name: Option<&'a str>,
+        common_name: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<ProviderPhoto>>> {
+        Box::pin(async move {
+            let taxon = match scientific_name {
+                Some(scientific_name) => self.taxon(scientific_name).await?,
+                None => None,
+            };
+            let taxon = match taxon {
+                Some(taxon) => taxon,
+                None => match self.taxon(common_name).await? {
+                    Some(taxon) => taxon,
+                    None => return Ok(Vec::new()),
+                },
+            };
+
+            Ok(taxon
+                .taxon_photos
+                .into_iter()
+                .map(|t| t.photo)
+                .filter_map(|p| {
+                    // Photos without a license code are all rights reserved.
+                    let (license, license_url) =
+                        creative_commons(p.license_code.as_deref()?, p.license_url.as_deref());
+                    let image_url = p
+                        .large_url
+                        .or(p.medium_url)
+                        .or(p.url.map(|u| u.replace("/square.", "/large.")))?;
+
+                    Some(ProviderPhoto {
+                        source: "inaturalist".into(),
+                        id: p.id.to_string(),
+                        title: common_name.to_owned(),
+                        owner: p.attribution.clone(),
+                        owner_name: Some(p.attribution),
+                        license: Some(license),
+                        license_url: Some(license_url),
+                        source_url: p.native_page_url.unwrap_or_else(|| {
+                            format!("https://www.inaturalist.org/photos/{}", p.id)
+                        }),
+                        image_url,
+                    })
+                })
+                .collect())
+        })
+    }
+
+    fn image<'a>(&'a self, photo: &'a ProviderPhoto) -> BoxFuture<'a, Result<Image>> {
+        Box::pin(download(&self.http, &photo.image_url))
+    }
+}
+
+/// Freely licensed files on Wikimedia Commons.
+pub struct Wikimedia {
+    http: ClientWithMiddleware,
+    base_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikimediaPayload {
+    query: Option<WikimediaQuery>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikimediaQuery {
+    pages: HashMap<String, WikimediaPage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikimediaPage {
+    pageid: u64,
+    title: String,
+    index: u32,
+    #[serde(default)]
+    imageinfo: Vec<ImageInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ImageInfo {
+    url: String,
+    thumburl: Option<String>,
+    descriptionurl: String,
+    #[serde(default)]
+    extmetadata: HashMap<String, MetadataValue>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MetadataValue {
+    value: serde_json::Value,
+}
+
+impl ImageInfo {
+    fn metadata(&self, key: &str) -> Option<String> {
+        match &self.extmetadata.get(key)?.value {
+            serde_json::Value::String(s) => Some(strip_html(s)),
+            _ => None,
+        }
+    }
+}
+
+/// Commons artist and description metadata is HTML, usually a link.
+fn strip_html(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+
+    decode_entities(text.trim())
+}
+
+/// Named entities common in Commons metadata and numeric ones, anything else
+/// is left as it is.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest[1..].find(';').map(|end| &rest[1..=end]);
+        let c = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(|n| n.parse()))
+                .and_then(|n| n.ok())
+                .and_then(char::from_u32),
+        });
+
+        match (entity, c) {
+            (Some(entity), Some(c)) => {
+                decoded.push(c);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+
+    decoded
+}
+
+impl Wikimedia {
+    async fn search_files(&self, query: &str) -> Result<Vec<ProviderPhoto>> {
+        let payload = self
+            .http
+            .get(&self.base_url)
+            .query(&[
+                ("action", "query"),
+                ("format", "json"),
+                ("generator", "search"),
+                ("gsrsearch", &format!("\"{}\" filetype:bitmap", query)),
+                ("gsrnamespace", "6"),
+                ("gsrlimit", "20"),
+                ("prop", "imageinfo"),
+                ("iiprop", "url|extmetadata"),
+                ("iiurlwidth", "1024"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<WikimediaPayload>()
+            .await?;
+
+        let mut pages = payload
+            .query
+            .map(|q| q.pages.into_values().collect::<Vec<_>>())
+            .unwrap_or_default();
+        pages.sort_by_key(|p| p.index);
+
+        Ok(pages
+            .into_iter()
+            .filter_map(|page| {
+                let info = page.imageinfo.into_iter().next()?;
+                // Everything on Commons is meant to be free, but skip anything
+                // that doesn't say how.
+                let license = info.metadata("LicenseShortName")?;
+                let artist = info.metadata("Artist").unwrap_or_default();
+
+                Some(ProviderPhoto {
+                    source: "wikimedia".into(),
+                    id: page.pageid.to_string(),
+                    title: page.title.trim_start_matches("File:").to_owned(),
+                    owner: artist.clone(),
+                    owner_name: Some(artist).filter(|a| !a.is_empty()),
+                    license: Some(license),
+                    license_url: info.metadata("LicenseUrl"),
+                    source_url: info.descriptionurl,
+                    image_url: info.thumburl.unwrap_or(info.url),
+                })
+            })
+            .collect())
+    }
+}
+
+impl SpeciesImageProvider for Wikimedia {
+    fn name(&self) -> &'static str {
+        "wikimedia"
+    }
+
+    fn search<'a>(
+        &'a self,
+        scientific_name: Option<&'a str>,
+        common_name: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<ProviderPhoto>>> {
+        Box::pin(async move {
+            let photos = match scientific_name {
+                Some(scientific_name) => self.search_files(scientific_name).await?,
+                None => Vec::new(),
+            };
+            if !photos.is_empty() {
+                return Ok(photos);
+            }
+
+            self.search_files(common_name).await
+        })
+    }
+
+    fn image<'a>(&'a self, photo: &'a ProviderPhoto) -> BoxFuture<'a, Result<Image>> {
+        Box::pin(download(&self.http, &photo.image_url))
+    }
+}
+
+/// Serves recorded provider responses locally.
+#[cfg(test)]
+pub(crate) mod testing {
+    use axum::http::header;
+    use axum::response::IntoResponse;
+    use axum::Router;
+    use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
+    use tokio::net::TcpListener;
+
+    pub fn json(body: &'static str) -> impl IntoResponse {
+        ([(header::CONTENT_TYPE, "application/json")], body)
+    }
+
+    pub fn http() -> ClientWithMiddleware {
+        ClientBuilder::new(reqwest::Client::new()).build()
+    }
+
+    /// Base URL of the router, serving in the background.
+    pub async fn serve(router: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        format!("http://{}", addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::Query;
+    use axum::routing::get;
+    use axum::Router;
+
+    use super::testing::{http, json, serve};
+    use super::*;
+
+    async fn inaturalist() -> INaturalist {
+        let router = Router::new()
+            .route(
+                "/taxa",
+                get(|Query(query): Query<HashMap<String, String>>| async move {
+                    match query.get("q").map(|q| q.as_str()) {
+                        Some("Corvus brachyrhynchos" | "American Crow") => json(include_str!(
+                            "../tests/fixtures/inaturalist-taxa-search.json"
+                        )),
+                        _ => json(r#"{"total_results":0,"results":[]}"#),
+                    }
+                }),
+            )
+            .route(
+                "/taxa/8021",
+                get(|| async { json(include_str!("../tests/fixtures/inaturalist-taxon.json")) }),
+            );
+
+        INaturalist {
+            http: http(),
+            base_url: serve(router).await,
+        }
+    }
+
+    async fn wikimedia() -> Wikimedia {
+        let router = Router::new().route(
+            "/",
+            get(|Query(query): Query<HashMap<String, String>>| async move {
+                match query.get("gsrsearch").map(|q| q.as_str()) {
+                    Some("\"Corvus brachyrhynchos\" filetype:bitmap") => {
+                        json(include_str!("../tests/fixtures/wikimedia-search.json"))
+                    }
+                    _ => json(r#"{"batchcomplete":""}"#),
+                }
+            }),
+        );
+
+        Wikimedia {
+            http: http(),
+            base_url: serve(router).await,
+        }
+    }
+
+    #[tokio::test]
+    async fn inaturalist_photos_are_licensed() {
+        let inaturalist = inaturalist().await;
+
+        let photos = inaturalist
+            .search(Some("Corvus brachyrhynchos"), "American Crow")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            photos.iter().map(|p| p.id.as_str()).collect::<Vec<_>>(),
+            vec!["69453", "3310442", "4452113"]
+        );
+        assert_eq!(photos[0].license.as_deref(), Some("CC BY-NC 4.0"));
+        assert_eq!(
+            photos[0].license_url.as_deref(),
+            Some("https://creativecommons.org/licenses/by-nc/4.0/")
+        );
+        assert_eq!(
+            photos[0].image_url,
+            "https://inaturalist-open-data.s3.amazonaws.com/photos/69453/large.jpg"
+        );
+        // Without a deed to go by, there's no telling which version it is.
+        assert_eq!(photos[1].license.as_deref(), Some("CC BY-SA"));
+        assert_eq!(
+            photos[1].license_url.as_deref(),
+            Some("https://creativecommons.org/licenses/by-sa/")
+        );
+        assert_eq!(photos[2].license.as_deref(), Some("CC0 1.0"));
+        assert_eq!(
+            photos[2].image_url,
+            "https://inaturalist-open-data.s3.amazonaws.com/photos/4452113/large.jpeg"
+        );
+        assert_eq!(
+            photos[2].source_url,
+            "https://www.inaturalist.org/photos/4452113"
+        );
+    }
+
+    #[tokio::test]
+    async fn inaturalist_falls_back_to_common_names() {
+        let inaturalist = inaturalist().await;
+
+        let photos = inaturalist
+            .search(Some("Corvus renamedus"), "American Crow")
+            .await
+            .unwrap();
+        assert_eq!(photos.len(), 3);
+
+        let photos = inaturalist.search(None, "Snark").await.unwrap();
+        assert!(photos.is_empty());
+    }
+
+    #[tokio::test]
+    async fn wikimedia_photos_are_licensed() {
+        let wikimedia = wikimedia().await;
+
+        let photos = wikimedia
+            .search(Some("Corvus brachyrhynchos"), "American Crow")
+            .await
+            .unwrap();
+
+        // In search order, without the file that doesn't say how it's licensed.
+        assert_eq!(
+            photos.iter().map(|p| p.id.as_str()).collect::<Vec<_>>(),
+            vec!["8120034", "31243785"]
+        );
+        assert_eq!(photos[0].license.as_deref(), Some("Public domain"));
+        assert_eq!(photos[0].owner_name, None);
+        assert_eq!(
+            photos[0].image_url,
+            "https://upload.wikimedia.org/wikipedia/commons/0/0b/American_Crow_%28Corvus_brachyrhynchos%29.jpg"
+        );
+        assert_eq!(photos[1].title, "Corvus brachyrhynchos 30196.JPG");
+        assert_eq!(photos[1].owner_name.as_deref(), Some("Walter Siegmund"));
+        assert_eq!(photos[1].license.as_deref(), Some("CC BY-SA 3.0"));
+        assert!(photos[1].image_url.contains("/thumb/"));
+    }
+
+    #[tokio::test]
+    async fn wikimedia_falls_back_to_common_names() {
+        let wikimedia = wikimedia().await;
+
+        let photos = wikimedia
+            .search(None, "Corvus brachyrhynchos")
+            .await
+            .unwrap();
+        assert_eq!(photos.len(), 2);
+
+        let photos = wikimedia
+            .search(Some("Corvus renamedus"), "Snark")
+            .await
+            .unwrap();
+        assert!(photos.is_empty());
+    }
+
+    #[test]
+    fn strips_html() {
+        assert_eq!(
+            strip_html(r#" <a href="//commons.wikimedia.org/wiki/User:X">X &amp; Y</a> "#),
+            "X & Y"
+        );
+        assert_eq!(
+            strip_html("Jos&#233; &quot;Pepe&quot; &#x4e2d; &bogus; AT&T"),
+            "José \"Pepe\" 中 &bogus; AT&T"
+        );
+    }
+}
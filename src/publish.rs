@@ -1,22 +1,31 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
 use clap::Args;
 use futures::stream;
 use influxdb2::models::DataPoint;
 use influxdb2::Client;
 use itertools::Itertools;
-use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use rumqttc::{AsyncClient, Event as MqttEvent, MqttOptions, Outgoing, QoS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::{self, BufRead};
-use std::io::{BufReader, Seek};
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
+use std::time::Duration;
+use tracing::{info, warn};
 
+use crate::store::get_station;
 use crate::BirdDateAndTime;
 
 #[derive(Debug, Args)]
 pub struct Command {
     #[arg(short, long)]
     watch: bool,
+    /// Publish to MQTT rather than InfluxDB.
+    #[arg(long)]
+    mqtt: bool,
     file: String,
 }
 
@@ -28,16 +37,105 @@ where
     Ok(io::BufReader::new(file).lines())
 }
 
+/// Blocks, calling `changed` for every change notify reports to `path`.
+pub fn watch_path(path: &Path, mut changed: impl FnMut(&Event) -> Result<()>) -> Result<()> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = RecommendedWatcher::new(tx, Config::default())?;
+    watcher.watch(path, RecursiveMode::NonRecursive)?;
+
+    for res in rx {
+        match res {
+            Ok(event) => changed(&event)?,
+            Err(error) => warn!("watching {:?}: {:?}", path, error),
+        }
+    }
+
+    Ok(())
+}
+
 pub async fn execute(cmd: Command) -> Result<()> {
     let log = BirdLog::new(cmd.file);
 
-    if cmd.watch {
+    if cmd.mqtt {
+        let mut mqtt = MqttPublisher::connect().await?;
+        let mut checkpoint = Checkpoint::default();
+
+        if cmd.watch {
+            // Carry on from today's count after a restart.
+            mqtt.seed(&log.entries()?);
+
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            let watching = tokio::task::spawn_blocking(move || {
+                log.watch_lines(|line| {
+                    match log.parse_entry(&line) {
+                        Ok(entry) => tx.send(entry)?,
+                        Err(e) => warn!("skipping {:?}: {:?}", line, e),
+                    }
+                    Ok(())
+                })
+            });
+
+            // Checked every minute so today's counts start over at midnight
+            // even when nothing is detected.
+            let mut ticks = tokio::time::interval(Duration::from_secs(60));
+
+            loop {
+                tokio::select! {
+                    entry = rx.recv() => match entry {
+                        Some(entry) => {
+                            mqtt.publish(&entry).await?;
+                            checkpoint.record(&entry)?;
+                        }
+                        None => break,
+                    },
+                    _ = ticks.tick() => mqtt.roll_over(Utc::now()).await?,
+                }
+            }
+
+            watching.await??;
+        } else {
+            for entry in log.entries()? {
+                mqtt.publish(&entry).await?;
+                checkpoint.record(&entry)?;
+            }
+        }
+
+        mqtt.disconnect().await
+    } else if cmd.watch {
         log.watch()
     } else {
         log.publish_all().await
     }
 }
 
+/// Whole, non-blank lines appended since `pos`, which is moved past them. A
+/// line still being written is left for next time.
+fn read_appended(f: &mut File, pos: &mut u64) -> Result<Vec<String>> {
+    let len = f.metadata()?.len();
+    if len < *pos {
+        // Truncated, so start over from the top.
+        *pos = 0;
+    }
+    if len == *pos {
+        return Ok(Vec::new());
+    }
+
+    f.seek(SeekFrom::Start(*pos))?;
+    let mut appended = Vec::new();
+    f.take(len - *pos).read_to_end(&mut appended)?;
+
+    let Some(end) = appended.iter().rposition(|b| *b == b'\n') else {
+        return Ok(Vec::new());
+    };
+    *pos += end as u64 + 1;
+
+    Ok(String::from_utf8_lossy(&appended[..end])
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.to_owned())
+        .collect())
+}
+
 pub struct BirdLog {
     path: String,
 }
@@ -48,38 +146,31 @@ impl BirdLog {
     }
 
     pub fn watch(&self) -> Result<()> {
+        self.watch_lines(|line| {
+            info!("> {:?}", line);
+            Ok(())
+        })
+    }
+
+    /// Blocks, calling `added` with every line appended to the log.
+    pub fn watch_lines(&self, mut added: impl FnMut(String) -> Result<()>) -> Result<()> {
         let mut f = std::fs::File::open(&self.path)?;
         let mut pos = std::fs::metadata(&self.path)?.len();
 
-        let (tx, rx) = std::sync::mpsc::channel();
-        let mut watcher = RecommendedWatcher::new(tx, Config::default())?;
-        watcher.watch(self.path.as_ref(), RecursiveMode::NonRecursive)?;
-
-        for res in rx {
-            match res {
-                Ok(_event) => {
-                    if f.metadata()?.len() == pos {
-                        continue;
-                    }
-
-                    f.seek(std::io::SeekFrom::Start(pos + 1))?;
-
-                    pos = f.metadata()?.len();
-
-                    let reader = BufReader::new(&f);
-                    for line in reader.lines() {
-                        println!("> {:?}", line.unwrap());
-                    }
-                }
-                Err(error) => println!("{error:?}"),
+        watch_path(self.path.as_ref(), |_event| {
+            for line in read_appended(&mut f, &mut pos)? {
+                added(line)?;
             }
-        }
 
-        Ok(())
+            Ok(())
+        })
     }
 
-    fn parse_entry(&self, line: String) -> Result<LogEntry> {
+    fn parse_entry(&self, line: &str) -> Result<LogEntry> {
         let fields = line.split(";").collect_vec();
+        if fields.len() < 5 {
+            return Err(anyhow!("expected at least 5 fields"));
+        }
 
         let date: NaiveDate = fields[0].parse()?;
         let time: NaiveTime = fields[1].parse()?;
@@ -96,6 +187,21 @@ impl BirdLog {
         })
     }
 
+    /// Every entry after the header, skipping lines that don't parse.
+    pub fn entries(&self) -> Result<Vec<LogEntry>> {
+        let mut entries = Vec::new();
+        for line in read_lines(&self.path)?.skip(1) {
+            let line = line?;
+            match self.parse_entry(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) if !line.trim().is_empty() => warn!("skipping {:?}: {:?}", line, e),
+                Err(_) => {}
+            }
+        }
+
+        Ok(entries)
+    }
+
     pub async fn publish_all(&self) -> Result<()> {
         let lines = read_lines(&self.path)?;
 
@@ -103,18 +209,19 @@ impl BirdLog {
         let org = std::env::var("INFLUXDB_ORG").unwrap();
         let token = std::env::var("INFLUXDB_TOKEN").unwrap();
         let client = Client::new(host, org, token);
+        let mut checkpoint = Checkpoint::default();
 
         if true {
-            for line in lines.skip(1) {
-                if let Ok(line) = line {
-                    let entry = self.parse_entry(line)?;
+            for line in lines.skip(1).flatten() {
+                let entry = self.parse_entry(&line)?;
 
-                    println!("{:?}", entry);
+                info!("{:?}", entry);
 
-                    let dp: DataPoint = entry.into();
+                checkpoint.record(&entry)?;
 
-                    client.write("home", stream::iter(vec![dp])).await?;
-                }
+                let dp: DataPoint = entry.into();
+
+                client.write("home", stream::iter(vec![dp])).await?;
             }
         } else {
             let start = NaiveDate::from_ymd_opt(2020, 1, 1)
@@ -140,8 +247,7 @@ impl BirdLog {
     }
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
+#[derive(Debug, Serialize, Clone)]
 pub struct LogEntry {
     date_time: DateTime<Utc>,
     common_name: String,
@@ -149,26 +255,493 @@ pub struct LogEntry {
     confidence: f64,
 }
 
+pub fn get_publish_checkpoint() -> Option<String> {
+    std::env::var("BIRBS_PUBLISH_CHECKPOINT").ok()
+}
+
+/// How far a publisher has got, saved after every entry when
+/// `BIRBS_PUBLISH_CHECKPOINT` is set so `serve` can report on it.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Checkpoint {
+    /// Lines published since the publisher started.
+    pub lines: u64,
+    /// When the newest published entry was detected.
+    pub last_entry: Option<DateTime<Utc>>,
+    pub updated: Option<DateTime<Utc>>,
+}
+
+impl Checkpoint {
+    pub fn load() -> Result<Option<Self>> {
+        let Some(path) = get_publish_checkpoint() else {
+            return Ok(None);
+        };
+
+        match std::fs::read_to_string(path) {
+            Ok(json) => Ok(Some(serde_json::from_str(&json)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn record(&mut self, entry: &LogEntry) -> Result<()> {
+        self.lines += 1;
+        self.last_entry = Some(entry.date_time);
+        self.updated = Some(Utc::now());
+
+        if let Some(path) = get_publish_checkpoint() {
+            // Written alongside and renamed so readers never see half a file.
+            let temporary = format!("{}.tmp", path);
+            std::fs::write(&temporary, serde_json::to_vec(self)?)?;
+            std::fs::rename(temporary, path)?;
+        }
+
+        Ok(())
+    }
+}
+
 #[allow(dead_code)]
 pub struct InfluxLineProtocol(String);
 
-impl Into<DataPoint> for LogEntry {
-    fn into(self) -> DataPoint {
+impl From<LogEntry> for DataPoint {
+    fn from(entry: LogEntry) -> Self {
         DataPoint::builder("birds")
             .tag("station", "backyard")
-            .field(self.common_name, self.confidence)
+            .field(entry.common_name, entry.confidence)
             .build()
             .expect("Error building data point")
     }
 }
 
-impl Into<InfluxLineProtocol> for LogEntry {
-    fn into(self) -> InfluxLineProtocol {
+impl From<LogEntry> for InfluxLineProtocol {
+    fn from(entry: LogEntry) -> Self {
         InfluxLineProtocol(format!(
             "birds,station=backyard {}={} {}",
-            self.common_name.replace(" ", "\\ "),
-            self.confidence,
-            self.date_time.timestamp_nanos_opt().unwrap()
+            entry.common_name.replace(" ", "\\ "),
+            entry.confidence,
+            entry.date_time.timestamp_nanos_opt().unwrap()
         ))
     }
 }
+
+fn get_mqtt_host() -> String {
+    std::env::var("MQTT_HOST").unwrap_or_else(|_| "localhost".into())
+}
+
+fn get_mqtt_port() -> u16 {
+    std::env::var("MQTT_PORT")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(1883)
+}
+
+fn get_mqtt_topic(station: &str) -> String {
+    std::env::var("MQTT_TOPIC").unwrap_or_else(|_| format!("birbs/{}", station))
+}
+
+fn get_mqtt_discovery_prefix() -> String {
+    std::env::var("MQTT_DISCOVERY_PREFIX").unwrap_or_else(|_| "homeassistant".into())
+}
+
+/// Retained on `{topic}/state`, what the Home Assistant sensors read from.
+#[derive(Serialize, Debug)]
+struct MqttState<'a> {
+    last_species: &'a str,
+    last_scientific_name: &'a str,
+    last_confidence: f64,
+    last_detection: DateTime<Utc>,
+    detections_today: u32,
+    species_today: usize,
+}
+
+/// Publishes every entry to `{topic}/detection` and keeps a retained state
+/// and Home Assistant discovery configs up to date.
+pub struct MqttPublisher {
+    client: AsyncClient,
+    events: tokio::task::JoinHandle<()>,
+    station: String,
+    topic: String,
+    today: Option<NaiveDate>,
+    detections_today: u32,
+    species_today: HashSet<String>,
+    last: Option<LogEntry>,
+}
+
+impl MqttPublisher {
+    pub async fn connect() -> Result<Self> {
+        let station = get_station();
+        let topic = get_mqtt_topic(&station);
+
+        Self::connect_to(&get_mqtt_host(), get_mqtt_port(), station, topic).await
+    }
+
+    async fn connect_to(host: &str, port: u16, station: String, topic: String) -> Result<Self> {
+        let mut options = MqttOptions::new(format!("birbs-{}", station), host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let (Ok(username), Ok(password)) = (
+            std::env::var("MQTT_USERNAME"),
+            std::env::var("MQTT_PASSWORD"),
+        ) {
+            options.set_credentials(username, password);
+        }
+
+        let (client, mut eventloop) = AsyncClient::new(options, 100);
+        let events = tokio::spawn(async move {
+            // Keep polling after asking to disconnect so queued messages are
+            // flushed, until the broker closes the connection.
+            let mut disconnecting = false;
+            loop {
+                match eventloop.poll().await {
+                    Ok(MqttEvent::Outgoing(Outgoing::Disconnect)) => disconnecting = true,
+                    Ok(_) => {}
+                    Err(_) if disconnecting => break,
+                    Err(e) => {
+                        warn!("mqtt: {:?}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+
+        let publisher = Self {
+            client,
+            events,
+            topic,
+            station,
+            today: None,
+            detections_today: 0,
+            species_today: HashSet::new(),
+            last: None,
+        };
+
+        publisher.discovery().await?;
+
+        Ok(publisher)
+    }
+
+    async fn discovery(&self) -> Result<()> {
+        let device_id = format!("birbs_{}", self.station);
+        let sensors = [
+            (
+                "last_species",
+                "Last species",
+                "{{ value_json.last_species }}",
+                None,
+            ),
+            (
+                "detections_today",
+                "Detections today",
+                "{{ value_json.detections_today }}",
+                Some("detections"),
+            ),
+            (
+                "species_today",
+                "Species today",
+                "{{ value_json.species_today }}",
+                Some("species"),
+            ),
+        ];
+
+        for (object_id, name, template, unit) in sensors {
+            let mut config = serde_json::json!({
+                "name": name,
+                "unique_id": format!("{}_{}", device_id, object_id),
+                "state_topic": format!("{}/state", self.topic),
+                "value_template": template,
+                "icon": "mdi:bird",
+                "device": {
+                    "identifiers": [device_id],
+                    "name": format!("birbs {}", self.station),
+                    "manufacturer": "birbs",
+                },
+            });
+            if let Some(unit) = unit {
+                config["unit_of_measurement"] = unit.into();
+                config["state_class"] = "measurement".into();
+            }
+
+            self.client
+                .publish(
+                    format!(
+                        "{}/sensor/{}/{}/config",
+                        get_mqtt_discovery_prefix(),
+                        device_id,
+                        object_id
+                    ),
+                    QoS::AtLeastOnce,
+                    true,
+                    serde_json::to_vec(&config)?,
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts the counts over when `when` is on another day, returning whether it was.
+    fn start_day(&mut self, when: DateTime<Utc>) -> bool {
+        let date = BirdDateAndTime::from_utc(when).local.date_naive();
+        if self.today == Some(date) {
+            return false;
+        }
+
+        self.today = Some(date);
+        self.detections_today = 0;
+        self.species_today.clear();
+        true
+    }
+
+    fn count(&mut self, entry: &LogEntry) {
+        self.start_day(entry.date_time);
+        self.detections_today += 1;
+        self.species_today.insert(entry.scientific_name.clone());
+    }
+
+    /// Counts today's entries already in the log without publishing them.
+    pub fn seed(&mut self, entries: &[LogEntry]) {
+        let today = BirdDateAndTime::from_utc(Utc::now()).local.date_naive();
+        for entry in entries {
+            if BirdDateAndTime::from_utc(entry.date_time)
+                .local
+                .date_naive()
+                == today
+            {
+                self.count(entry);
+            }
+        }
+    }
+
+    pub async fn publish(&mut self, entry: &LogEntry) -> Result<()> {
+        self.count(entry);
+
+        self.client
+            .publish(
+                format!("{}/detection", self.topic),
+                QoS::AtLeastOnce,
+                false,
+                serde_json::to_vec(entry)?,
+            )
+            .await?;
+
+        self.last = Some(entry.clone());
+        self.publish_state().await
+    }
+
+    /// Republishes the state with zero counts once the day is over, otherwise
+    /// yesterday's would show until the next detection.
+    pub async fn roll_over(&mut self, now: DateTime<Utc>) -> Result<()> {
+        if self.today.is_none() || !self.start_day(now) {
+            return Ok(());
+        }
+
+        self.publish_state().await
+    }
+
+    async fn publish_state(&self) -> Result<()> {
+        let Some(entry) = &self.last else {
+            return Ok(());
+        };
+
+        let state = MqttState {
+            last_species: &entry.common_name,
+            last_scientific_name: &entry.scientific_name,
+            last_confidence: entry.confidence,
+            last_detection: entry.date_time,
+            detections_today: self.detections_today,
+            species_today: self.species_today.len(),
+        };
+
+        self.client
+            .publish(
+                format!("{}/state", self.topic),
+                QoS::AtLeastOnce,
+                true,
+                serde_json::to_vec(&state)?,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Waits for everything queued to be sent.
+    pub async fn disconnect(self) -> Result<()> {
+        self.client.disconnect().await?;
+        self.events.await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use rumqttc::{ConnAck, ConnectReturnCode, Packet, PubAck};
+    use std::io::Write;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const LINE: &str =
+        "2024-05-01;06:01:00;Turdus migratorius;American Robin;0.91;47.6;-122.3;0.7;18;1.25;0.0";
+
+    fn temporary_log(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("birbs-{}-{}.txt", name, std::process::id()));
+        std::fs::write(&path, "Date;Time;Sci_Name;Com_Name;Confidence\n").unwrap();
+        path
+    }
+
+    #[test]
+    fn parses_entries() {
+        let log = BirdLog::new(String::new());
+        let entry = log.parse_entry(LINE).unwrap();
+
+        assert_eq!(entry.common_name, "American Robin");
+        assert_eq!(entry.scientific_name, "Turdus migratorius");
+        assert_eq!(entry.confidence, 0.91);
+        assert_eq!(entry.date_time.to_rfc3339(), "2024-05-01T13:01:00+00:00");
+    }
+
+    #[test]
+    fn rejects_short_lines() {
+        let log = BirdLog::new(String::new());
+
+        assert!(log.parse_entry("").is_err());
+        assert!(log.parse_entry("2024-05-01;06:01:00").is_err());
+    }
+
+    #[test]
+    fn reads_whole_appended_lines() {
+        let path = temporary_log("appended");
+        let mut f = File::open(&path).unwrap();
+        let mut pos = f.metadata().unwrap().len();
+
+        let mut log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        write!(log, "{}\n\n2024-05-01;06:0", LINE).unwrap();
+        assert_eq!(read_appended(&mut f, &mut pos).unwrap(), vec![LINE]);
+
+        writeln!(log, "2:00;Corvus brachyrhynchos;American Crow;0.8").unwrap();
+        assert_eq!(
+            read_appended(&mut f, &mut pos).unwrap(),
+            vec!["2024-05-01;06:02:00;Corvus brachyrhynchos;American Crow;0.8"]
+        );
+        assert!(read_appended(&mut f, &mut pos).unwrap().is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn entries_skip_bad_lines() {
+        let path = temporary_log("entries");
+        let mut log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        write!(log, "{}\n\nnot a detection\n{}\n", LINE, LINE).unwrap();
+
+        let entries = BirdLog::new(path.to_string_lossy().into())
+            .entries()
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Just enough of a broker to accept one client and keep what it publishes.
+    async fn broker(listener: TcpListener) -> Vec<(String, bool, Vec<u8>)> {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buffer = BytesMut::new();
+        let mut published = Vec::new();
+
+        loop {
+            let packet = match rumqttc::mqttbytes::v4::read(&mut buffer, 1024 * 1024) {
+                Ok(packet) => packet,
+                Err(rumqttc::mqttbytes::Error::InsufficientBytes(_)) => {
+                    if socket.read_buf(&mut buffer).await.unwrap() == 0 {
+                        return published;
+                    }
+                    continue;
+                }
+                Err(e) => panic!("{:?}", e),
+            };
+
+            let mut reply = BytesMut::new();
+            match packet {
+                Packet::Connect(_) => {
+                    ConnAck::new(ConnectReturnCode::Success, false)
+                        .write(&mut reply)
+                        .unwrap();
+                }
+                Packet::Publish(publish) => {
+                    PubAck::new(publish.pkid).write(&mut reply).unwrap();
+                    published.push((publish.topic, publish.retain, publish.payload.to_vec()));
+                }
+                Packet::Disconnect => return published,
+                _ => {}
+            }
+            socket.write_all(&reply).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn publishes_detections_and_state() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = tokio::spawn(broker(listener));
+
+        let entry = |common_name: &str, scientific_name: &str| LogEntry {
+            date_time: Utc::now(),
+            common_name: common_name.into(),
+            scientific_name: scientific_name.into(),
+            confidence: 0.9,
+        };
+
+        let mut mqtt =
+            MqttPublisher::connect_to("127.0.0.1", port, "test".into(), "birbs/test".into())
+                .await
+                .unwrap();
+        mqtt.seed(&[
+            entry("American Robin", "Turdus migratorius"),
+            LogEntry {
+                date_time: Utc::now() - chrono::Duration::days(2),
+                ..entry("Steller's Jay", "Cyanocitta stelleri")
+            },
+        ]);
+        mqtt.publish(&entry("American Crow", "Corvus brachyrhynchos"))
+            .await
+            .unwrap();
+        mqtt.roll_over(Utc::now()).await.unwrap();
+        mqtt.roll_over(Utc::now() + chrono::Duration::days(1))
+            .await
+            .unwrap();
+        mqtt.disconnect().await.unwrap();
+
+        let published = broker.await.unwrap();
+        let topics = published.iter().map(|p| p.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/sensor/birbs_test/last_species/config",
+                "homeassistant/sensor/birbs_test/detections_today/config",
+                "homeassistant/sensor/birbs_test/species_today/config",
+                "birbs/test/detection",
+                "birbs/test/state",
+                "birbs/test/state",
+            ]
+        );
+
+        let (_, retained, state) = &published[4];
+        let state: serde_json::Value = serde_json::from_slice(state).unwrap();
+        assert!(retained);
+        assert_eq!(state["last_species"], "American Crow");
+        assert_eq!(state["detections_today"], 2);
+        assert_eq!(state["species_today"], 2);
+
+        let (_, _, state) = &published[5];
+        let state: serde_json::Value = serde_json::from_slice(state).unwrap();
+        assert_eq!(state["last_species"], "American Crow");
+        assert_eq!(state["detections_today"], 0);
+        assert_eq!(state["species_today"], 0);
+    }
+}
@@ -0,0 +1,182 @@
+use anyhow::Result;
+use axum::extract::{Path, Query};
+use axum::{http::StatusCode, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::species::{self, Species};
+use crate::{recording_url, store, BirdDateAndTime, BirdDb};
+
+const DEFAULT_QUEUE_LENGTH: usize = 50;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Confirmed,
+    /// A false positive.
+    Rejected,
+    Unreviewed,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Confirmed => "confirmed",
+            Status::Rejected => "rejected",
+            Status::Unreviewed => "unreviewed",
+        }
+    }
+}
+
+/// Lets the aggregate endpoints leave out false positives.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+pub struct ReviewFilter {
+    #[serde(default)]
+    pub exclude_rejected: bool,
+}
+
+impl ReviewFilter {
+    pub fn db(&self) -> Result<BirdDb> {
+        let db = BirdDb::new()?;
+        if self.exclude_rejected {
+            db.exclude_rejected()
+        } else {
+            Ok(db)
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Review {
+    status: Status,
+}
+
+#[axum_macros::debug_handler]
+pub async fn review_detection(
+    Path(file_name): Path<String>,
+    Json(review): Json<Review>,
+) -> Result<StatusCode, StatusCode> {
+    if file_name.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let found = tokio::task::spawn_blocking(move || {
+        store::Store::new()?.review_file(&file_name, review.status)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if found {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SpanReview {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    status: Status,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SpanReviewed {
+    /// Detections of the species in the span, including any reviewed on
+    /// their own which keep their own status.
+    detections: usize,
+}
+
+#[axum_macros::debug_handler]
+pub async fn review_species(
+    Species {
+        scientific_name, ..
+    }: Species,
+    Json(review): Json<SpanReview>,
+) -> Result<Json<SpanReviewed>, StatusCode> {
+    if review.end < review.start {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let detections = tokio::task::spawn_blocking(move || {
+        store::Store::new()?.review_span(&scientific_name, review.start, review.end, review.status)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(SpanReviewed { detections }))
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueOrder {
+    #[default]
+    LeastConfident,
+    /// Species detected the fewest times, the likeliest false positives.
+    Rarest,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct QueueQuery {
+    #[serde(default)]
+    sort: QueueOrder,
+    min_confidence: Option<f32>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Queued {
+    when: DateTime<Utc>,
+    slug: String,
+    common_name: String,
+    scientific_name: String,
+    confidence: f32,
+    file_name: String,
+    audio_url: String,
+    spectrogram_url: String,
+    /// Every detection of the species, reviewed or not.
+    species_detections: u64,
+}
+
+fn queue(query: &QueueQuery) -> Result<Vec<Queued>> {
+    let queued = store::Store::new()?.review_queue(
+        query.sort,
+        query.min_confidence.unwrap_or(0.0),
+        query.limit.unwrap_or(DEFAULT_QUEUE_LENGTH),
+    )?;
+
+    Ok(queued
+        .into_iter()
+        .map(|(detection, species_detections)| {
+            let audio_url = recording_url(
+                &BirdDateAndTime::from_utc(detection.when),
+                &detection.common_name,
+                &detection.file_name,
+            );
+
+            Queued {
+                when: detection.when,
+                slug: species::slug(&detection.scientific_name),
+                common_name: detection.common_name,
+                scientific_name: detection.scientific_name,
+                confidence: detection.confidence,
+                file_name: detection.file_name,
+                spectrogram_url: format!("{}.png", audio_url),
+                audio_url,
+                species_detections,
+            }
+        })
+        .collect())
+}
+
+#[axum_macros::debug_handler]
+pub async fn queue_json(Query(query): Query<QueueQuery>) -> Result<Json<Vec<Queued>>, StatusCode> {
+    Ok(Json(
+        tokio::task::spawn_blocking(move || queue(&query))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    ))
+}
@@ -1,40 +1,69 @@
 use anyhow::Result;
-use axum::extract::Path;
-use axum::{http::Method, routing::get, Extension, Router};
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, FromRequestParts};
+use axum::extract::{Path, Query, Request};
+use axum::http::header;
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+use axum::http::Uri;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::{http::Method, routing::get, routing::post, Extension, Router};
 use axum::{http::StatusCode, Json};
+use chrono::NaiveDate;
 use http_cache::{CACacheManager, CacheMode, HttpCache, HttpCacheOptions};
 use http_cache_reqwest::Cache;
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::net::TcpListener;
 use tokio::signal::{self};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tower_http::{
     cors::{Any, CorsLayer},
     trace::{DefaultMakeSpan, TraceLayer},
 };
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
-    flickr, get_flickr_api_key, BirdDb, Daily, DetectionsByCommonName,
-    DetectionsByTimeAndCommonName, DetectionsSummary, FilesFor, Hourly, Recently,
+    alerts, chorus, diversity, encounters, export, health, heatmap, live, metrics, phenology,
+    photos, providers, review, species, store, sync, taxonomy, BirdDb, Daily,
+    DetectionsByCommonName, DetectionsByTimeAndCommonName, DetectionsSummary, FilesFor, Hourly,
+    Recently, SpeciesStats,
 };
+use encounters::CountQuery;
+use review::ReviewFilter;
+use species::{Lookup, Species};
+use taxonomy::LangQuery;
 
-struct AppState {}
+struct AppState {
+    photos: photos::PhotoCache,
+}
 
 pub async fn execute() -> Result<()> {
+    if store::get_store_database().is_ok() {
+        match tokio::task::spawn_blocking(sync::sync_once).await? {
+            Ok(report) => info!("{:?}", report),
+            Err(e) => warn!("sync failed: {:?}", e),
+        }
+
+        if let Some(period) = sync::get_sync_interval() {
+            tokio::spawn(sync::run_every(period));
+        }
+    }
+
     let db = BirdDb::new()?;
 
     let _detections = db.detections()?;
-    let _by_common_name = db.by_common_name()?;
-    let _by_day_and_common_name = db.by_day_and_common_name()?;
+    let _by_common_name = db.by_common_name(None)?;
+    let _by_day_and_common_name = db.by_day_and_common_name(None)?;
     let _common_name_to_scientific_name = db.common_name_to_scientific_name()?;
-    let _files_for = db.files_for("American Crow")?;
-    let _hourly = db.hourly_detections("American Crow")?;
-    let _daily = db.daily_detections("American Crow")?;
+    let _files_for = db.files_for("Corvus brachyrhynchos")?;
+    let _hourly = db.hourly_detections("Corvus brachyrhynchos")?;
+    let _daily = db.daily_detections("Corvus brachyrhynchos")?;
     let _recently = db.recently()?;
 
     // let flickr = flickr::FlickrClient::new(&get_flickr_api_key()?);
@@ -42,14 +71,59 @@ pub async fn execute() -> Result<()> {
     // use futures::future;
     // let _photos = future::try_join_all(photos.iter().map(|p| flickr.image(p))).await?;
 
-    let app_state = Arc::new(AppState {});
+    let app_state = Arc::new(AppState {
+        photos: photos::PhotoCache::new(providers::new_providers(new_http_client()))?,
+    });
+
+    let live = Arc::new(live::Live::new(live::get_replay_size()));
+    let watching = live.clone();
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = live::watch_database(watching) {
+            warn!("live detections stopped: {:?}", e);
+        }
+    });
+
+    match alerts::AlertsConfig::load_if_present(&alerts::get_alerts_config()) {
+        Ok(Some(config)) => {
+            let live = live.clone();
+            tokio::spawn(async move {
+                if let Err(e) = alerts::run(config, live).await {
+                    warn!("alerts stopped: {:?}", e);
+                }
+            });
+        }
+        Ok(None) => {}
+        Err(e) => warn!("alerts disabled: {:?}", e),
+    }
 
     let cors = CorsLayer::new()
-        // allow `GET` and `POST` when accessing the resource
-        .allow_methods([Method::GET, Method::POST])
+        // only `GET` from other origins, curation is done from here
+        .allow_methods([Method::GET])
         // allow requests from any origin
         .allow_origin(Any);
 
+    if get_admin_token().is_none() {
+        if allow_unauthenticated() {
+            warn!("BIRBS_ALLOW_UNAUTHENTICATED set, curation and review are open to anyone who can reach us");
+        } else {
+            warn!("BIRBS_ADMIN_TOKEN unset, curation and review are disabled");
+        }
+    }
+
+    let curation = Router::new()
+        .route("/:species/photo/pin", post(pin_photo))
+        .route(
+            "/:species/photo/upload",
+            post(upload_photo).layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES)),
+        )
+        .route("/:species/photo/block", post(block_photo))
+        .route(
+            "/review/detections/:file_name",
+            post(review::review_detection),
+        )
+        .route("/:species/review", post(review::review_species))
+        .route_layer(axum::middleware::from_fn(require_admin_token));
+
     let app = Router::new()
         .route("/", get(|| async { "hello, world!" }))
         .route(
@@ -59,16 +133,44 @@ pub async fn execute() -> Result<()> {
         .route("/recently.json", get(recently))
         .route("/by-common-name.json", get(by_common_name))
         .route("/by-day-and-common-name.json", get(by_day_and_common_name))
-        .route("/:common-name/files.json", get(files_for))
-        .route("/:common-name/hourly.json", get(hourly_for))
-        .route("/:common-name/daily.json", get(daily_for))
-        .route("/:common-name/photo.png", get(photo_for))
+        .route("/heatmap.json", get(heatmap::heatmap_json))
+        .route("/sun.json", get(chorus::sun_json))
+        .route("/encounters.json", get(encounters::encounters_json))
+        .route("/first-song.json", get(chorus::first_song_json))
+        .route("/phenology.json", get(phenology::phenology_json))
+        .route("/diversity.json", get(diversity::diversity_json))
+        .route("/accumulation.json", get(diversity::accumulation_json))
+        .route("/review/queue.json", get(review::queue_json))
+        .route("/:species/files.json", get(files_for))
+        .route("/:species/hourly.json", get(hourly_for))
+        .route("/:species/daily.json", get(daily_for))
+        .route("/:species/info.json", get(info_for))
+        .route("/:species/heatmap.json", get(heatmap::species_heatmap_json))
+        .route("/:species/sun.json", get(chorus::species_sun_json))
+        .route(
+            "/:species/encounters.json",
+            get(encounters::species_encounters_json),
+        )
+        .route("/:species/photo.png", get(photo_for))
+        .route("/:species/photo.json", get(photo_json_for))
+        .route("/:species/photo/candidates.json", get(photo_candidates_for))
+        .route("/export/ebird.csv", get(export_ebird))
+        .route("/export/dwca.zip", get(export_dwca))
+        .route("/live/sse", get(live::sse))
+        .route("/live/ws", get(live::ws))
+        .route("/metrics", get(metrics::metrics))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/status.json", get(health::status_json))
+        .merge(curation)
         .layer(cors)
+        .layer(axum::middleware::from_fn(metrics::track_http))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::default().include_headers(false)),
         )
-        .layer(Extension(app_state));
+        .layer(Extension(app_state))
+        .layer(Extension(live));
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 3100));
     info!("listening on {:?}", addr);
@@ -110,47 +212,242 @@ async fn shutdown_signal() {
 }
 
 #[axum_macros::debug_handler]
-async fn common_name_to_scientific_name() -> Result<Json<HashMap<String, String>>, StatusCode> {
-    let db = BirdDb::new().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(Json(
-        db.common_name_to_scientific_name()
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
-    ))
+async fn common_name_to_scientific_name(
+    Query(lang): Query<LangQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<Json<HashMap<String, String>>, StatusCode> {
+    let db = reviews
+        .db()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let names = db
+        .common_name_to_scientific_name()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(match lang.translation() {
+        Some(translation) => names
+            .into_iter()
+            .map(|(mut common_name, scientific_name)| {
+                translation.localize(Some(&scientific_name), &mut common_name);
+                (common_name, scientific_name)
+            })
+            .collect(),
+        None => names,
+    }))
+}
+
+/// The species path segment swapped for a slug, keeping the rest of the URL.
+fn renamed_uri(uri: &Uri, slug: &str) -> String {
+    let mut segments = uri.path().split('/').collect::<Vec<_>>();
+    if let Some(segment) = segments.get_mut(1) {
+        *segment = slug;
+    }
+
+    match uri.query() {
+        Some(query) => format!("{}?{}", segments.join("/"), query),
+        None => segments.join("/"),
+    }
+}
+
+/// Routes take a species by slug, old links by common or scientific name are
+/// redirected to the slug.
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Species {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path(identifier) = Path::<String>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| e.into_response())?;
+
+        let found =
+            tokio::task::spawn_blocking(move || species::lookup(&BirdDb::new()?, &identifier))
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+
+        match found {
+            Some(Lookup::Canonical(species)) => Ok(species),
+            Some(Lookup::Renamed(species)) => {
+                Err(Redirect::temporary(&renamed_uri(&parts.uri, &species.slug)).into_response())
+            }
+            None => Err(StatusCode::NOT_FOUND.into_response()),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SpeciesOrder {
+    #[default]
+    Alphabetical,
+    Taxonomic,
+}
+
+#[derive(Deserialize)]
+struct SortQuery {
+    #[serde(default)]
+    sort: SpeciesOrder,
 }
 
 #[axum_macros::debug_handler]
-async fn by_common_name() -> Result<Json<Vec<DetectionsByCommonName>>, StatusCode> {
-    let db = BirdDb::new().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(Json(
-        db.by_common_name()
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
-    ))
+async fn by_common_name(
+    Query(query): Query<SortQuery>,
+    Query(lang): Query<LangQuery>,
+    Query(count): Query<CountQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<Json<Vec<DetectionsByCommonName>>, StatusCode> {
+    let db = reviews
+        .db()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut species = db
+        .by_common_name(count.encounter_gap())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if let Some(translation) = lang.translation() {
+        for row in species.iter_mut() {
+            translation.localize(Some(&row.scientific_name), &mut row.common_name);
+        }
+    }
+
+    let taxonomy = taxonomy::taxonomy();
+    species.sort_by(|a, b| match query.sort {
+        SpeciesOrder::Alphabetical => a.common_name.cmp(&b.common_name),
+        SpeciesOrder::Taxonomic => taxonomy.compare(
+            (&a.scientific_name, &a.common_name),
+            (&b.scientific_name, &b.common_name),
+        ),
+    });
+
+    Ok(Json(species))
 }
 
 #[axum_macros::debug_handler]
-async fn by_day_and_common_name() -> Result<Json<Vec<DetectionsByTimeAndCommonName>>, StatusCode> {
-    let db = BirdDb::new().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(Json(
-        db.by_day_and_common_name()
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
-    ))
+async fn by_day_and_common_name(
+    Query(query): Query<SortQuery>,
+    Query(lang): Query<LangQuery>,
+    Query(count): Query<CountQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<Json<Vec<DetectionsByTimeAndCommonName>>, StatusCode> {
+    let db = reviews
+        .db()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut species = db
+        .by_day_and_common_name(count.encounter_gap())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if let Some(translation) = lang.translation() {
+        for row in species.iter_mut() {
+            translation.localize(Some(&row.scientific_name), &mut row.common_name);
+        }
+    }
+
+    let taxonomy = taxonomy::taxonomy();
+    species.sort_by(|a, b| {
+        a.when.cmp(&b.when).then_with(|| match query.sort {
+            SpeciesOrder::Alphabetical => a.common_name.cmp(&b.common_name),
+            SpeciesOrder::Taxonomic => taxonomy.compare(
+                (&a.scientific_name, &a.common_name),
+                (&b.scientific_name, &b.common_name),
+            ),
+        })
+    });
+
+    Ok(Json(species))
+}
+
+#[derive(Serialize)]
+struct SpeciesInfo {
+    slug: String,
+    common_name: String,
+    scientific_name: String,
+    taxonomy: Option<taxonomy::Taxon>,
+    /// The species' name in other languages, by language code.
+    names: BTreeMap<String, String>,
+    station: Option<SpeciesStats>,
+}
+
+#[axum_macros::debug_handler]
+async fn info_for(
+    species: Species,
+    Query(lang): Query<LangQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<Json<SpeciesInfo>, StatusCode> {
+    let db = reviews
+        .db()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let station = db
+        .species_stats(&species.scientific_name)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let taxonomy = taxonomy::taxonomy();
+    let mut common_name = species.common_name;
+    if let Some(translation) = lang.translation() {
+        translation.localize(Some(&species.scientific_name), &mut common_name);
+    }
+
+    Ok(Json(SpeciesInfo {
+        slug: species.slug,
+        common_name,
+        taxonomy: taxonomy
+            .by_scientific_name(&species.scientific_name)
+            .cloned(),
+        names: taxonomy.names(&species.scientific_name),
+        scientific_name: species.scientific_name,
+        station,
+    }))
 }
 
 #[axum_macros::debug_handler]
-async fn hourly_for(Path(common_name): Path<String>) -> Result<Json<Vec<Hourly>>, StatusCode> {
-    let db = BirdDb::new().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+async fn hourly_for(
+    Species {
+        scientific_name, ..
+    }: Species,
+    Query(count): Query<CountQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<Json<Vec<Hourly>>, StatusCode> {
+    let db = reviews
+        .db()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Some(gap) = count.encounter_gap() {
+        let detections = db
+            .species_detections(&scientific_name)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok(Json(encounters::hourly(&encounters::encounters(
+            &detections,
+            gap,
+        ))));
+    }
+
     let detections = db
-        .hourly_detections(&common_name)
+        .hourly_detections(&scientific_name)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     Ok(Json(detections))
 }
 
 #[axum_macros::debug_handler]
-async fn daily_for(Path(common_name): Path<String>) -> Result<Json<Vec<Daily>>, StatusCode> {
-    let db = BirdDb::new().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+async fn daily_for(
+    Species {
+        scientific_name, ..
+    }: Species,
+    Query(count): Query<CountQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<Json<Vec<Daily>>, StatusCode> {
+    let db = reviews
+        .db()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Some(gap) = count.encounter_gap() {
+        let detections = db
+            .species_detections(&scientific_name)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok(Json(encounters::daily(&encounters::encounters(
+            &detections,
+            gap,
+        ))));
+    }
+
     let detections = db
-        .daily_detections(&common_name)
+        .daily_detections(&scientific_name)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     Ok(Json(detections))
@@ -163,13 +460,20 @@ struct FilesResponse {
 }
 
 #[axum_macros::debug_handler]
-async fn files_for(Path(common_name): Path<String>) -> Result<Json<FilesResponse>, StatusCode> {
-    let db = BirdDb::new().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+async fn files_for(
+    Species {
+        scientific_name, ..
+    }: Species,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<Json<FilesResponse>, StatusCode> {
+    let db = reviews
+        .db()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let detections = db
-        .summarize_detections(&common_name)
+        .summarize_detections(&scientific_name)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let files = db
-        .files_for(&common_name)
+        .files_for(&scientific_name)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     let files = check_files_available(files)
@@ -185,12 +489,23 @@ struct RecentlyResponse {
 }
 
 #[axum_macros::debug_handler]
-async fn recently() -> Result<Json<RecentlyResponse>, StatusCode> {
-    let db = BirdDb::new().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let detections = db
+async fn recently(
+    Query(lang): Query<LangQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<Json<RecentlyResponse>, StatusCode> {
+    let db = reviews
+        .db()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut detections = db
         .recently()
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    if let Some(translation) = lang.translation() {
+        for detection in detections.iter_mut() {
+            translation.localize(Some(&detection.scientific_name), &mut detection.common_name);
+        }
+    }
+
     let detections = if false {
         check_recentlies_available(detections)
             .await
@@ -202,41 +517,317 @@ async fn recently() -> Result<Json<RecentlyResponse>, StatusCode> {
     Ok(Json(RecentlyResponse { detections }))
 }
 
-fn new_http_client() -> ClientWithMiddleware {
-    return ClientBuilder::new(reqwest::Client::new())
+#[derive(Deserialize)]
+struct EbirdQuery {
+    start: NaiveDate,
+    end: NaiveDate,
+    interval: Option<u32>,
+    gap: Option<u32>,
+    min_confidence: Option<f32>,
+    exclude: Option<String>,
+    location: Option<String>,
+    state: Option<String>,
+    country: Option<String>,
+}
+
+impl From<EbirdQuery> for export::EbirdOptions {
+    fn from(query: EbirdQuery) -> Self {
+        Self {
+            start: query.start,
+            end: query.end,
+            interval: query.interval,
+            gap: query.gap.unwrap_or(10),
+            min_confidence: query.min_confidence.unwrap_or(0.7),
+            exclude: query
+                .exclude
+                .map(|e| e.split(',').map(|s| s.trim().to_owned()).collect())
+                .unwrap_or_default(),
+            location: query.location.unwrap_or_else(|| "birbs".into()),
+            state: query.state.unwrap_or_default(),
+            country: query.country.unwrap_or_default(),
+            output: None,
+        }
+    }
+}
+
+#[axum_macros::debug_handler]
+async fn export_ebird(
+    Query(query): Query<EbirdQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let db = reviews
+        .db()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut csv = Vec::new();
+    export::write_ebird(&db, &query.into(), &mut csv)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(([(header::CONTENT_TYPE, "text/csv")], csv))
+}
+
+#[derive(Deserialize)]
+struct DwcaQuery {
+    start: NaiveDate,
+    end: NaiveDate,
+    min_confidence: Option<f32>,
+    title: Option<String>,
+    creator: Option<String>,
+    dataset_id: Option<String>,
+}
+
+impl From<DwcaQuery> for export::DwcaOptions {
+    fn from(query: DwcaQuery) -> Self {
+        Self {
+            start: query.start,
+            end: query.end,
+            min_confidence: query.min_confidence.unwrap_or(0.7),
+            title: query
+                .title
+                .unwrap_or_else(|| "birbs BirdNET detections".into()),
+            creator: query.creator.unwrap_or_else(|| "birbs".into()),
+            dataset_id: query.dataset_id.unwrap_or_else(|| "birbs".into()),
+            output: "dwca.zip".into(),
+        }
+    }
+}
+
+#[axum_macros::debug_handler]
+async fn export_dwca(
+    Query(query): Query<DwcaQuery>,
+    Query(reviews): Query<ReviewFilter>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let db = reviews
+        .db()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let archive = export::write_dwca(&db, &query.into(), std::io::Cursor::new(Vec::new()))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"dwca.zip\"",
+            ),
+        ],
+        archive.into_inner(),
+    ))
+}
+
+pub fn new_http_client() -> ClientWithMiddleware {
+    ClientBuilder::new(reqwest::Client::new())
         .with(Cache(HttpCache {
             mode: CacheMode::ForceCache,
             manager: CACacheManager::default(),
             options: HttpCacheOptions::default(),
         }))
-        .build();
+        .build()
 }
 
-async fn photo_for(Path(common_name): Path<String>) -> Result<Vec<u8>, StatusCode> {
-    let flickr = flickr::FlickrClient::new(
-        &get_flickr_api_key().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
-        new_http_client(),
-    );
-    let mut photos = flickr
-        .search(&common_name)
+async fn chosen_photo(state: &AppState, species: &Species) -> Result<photos::Photo, StatusCode> {
+    state
+        .photos
+        .photo(species)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|e| {
+            warn!("choosing photo for {:?}: {:?}", species.scientific_name, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Deserialize)]
+struct PhotoQuery {
+    size: Option<photos::Size>,
+    #[serde(default)]
+    format: photos::Format,
+}
+
+#[axum_macros::debug_handler]
+async fn photo_for(
+    Extension(state): Extension<Arc<AppState>>,
+    species: Species,
+    Query(query): Query<PhotoQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let photo = chosen_photo(&state, &species).await?;
+    let etag = format!(
+        "\"{}-{}-{:?}-{:?}\"",
+        photo.source, photo.photo_id, query.size, query.format
+    );
+
+    let (content_type, image) = tokio::task::spawn_blocking(move || {
+        state.photos.sized_image(&photo, query.size, query.format)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|e| {
+        warn!("resizing photo for {:?}: {:?}", species.scientific_name, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CACHE_CONTROL, "public, max-age=86400".to_owned()),
+            (header::ETAG, etag),
+        ],
+        image,
+    ))
+}
+
+#[axum_macros::debug_handler]
+async fn photo_json_for(
+    Extension(state): Extension<Arc<AppState>>,
+    species: Species,
+    Query(lang): Query<LangQuery>,
+) -> Result<Json<photos::Photo>, StatusCode> {
+    let mut photo = chosen_photo(&state, &species).await?;
+    if let Some(translation) = lang.translation() {
+        translation.localize(Some(&photo.scientific_name), &mut photo.common_name);
+    }
+
+    Ok(Json(photo))
+}
 
-    match photos.pop() {
-        Some(photo) => Ok(flickr
-            .image(&photo)
+#[axum_macros::debug_handler]
+async fn photo_candidates_for(
+    Extension(state): Extension<Arc<AppState>>,
+    species: Species,
+) -> Result<Json<Vec<photos::Candidate>>, StatusCode> {
+    Ok(Json(
+        state
+            .photos
+            .candidates(&species)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    ))
+}
+
+#[derive(Deserialize)]
+struct PinPhoto {
+    photo_id: String,
+    source: Option<String>,
+}
+
+#[axum_macros::debug_handler]
+async fn pin_photo(
+    Extension(state): Extension<Arc<AppState>>,
+    species: Species,
+    Json(pin): Json<PinPhoto>,
+) -> Result<Json<photos::Photo>, StatusCode> {
+    Ok(Json(
+        state
+            .photos
+            .pin(
+                &species,
+                pin.source.as_deref().unwrap_or("flickr"),
+                &pin.photo_id,
+            )
             .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?),
-        None => Err(StatusCode::NOT_FOUND),
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    ))
+}
+
+fn get_admin_token() -> Option<String> {
+    std::env::var("BIRBS_ADMIN_TOKEN")
+        .ok()
+        .filter(|t| !t.is_empty())
+}
+
+/// Without a token, changes are only allowed when that's asked for explicitly.
+fn allow_unauthenticated() -> bool {
+    std::env::var("BIRBS_ALLOW_UNAUTHENTICATED").is_ok_and(|v| !v.is_empty() && v != "0")
+}
+
+/// Whether a request may make changes, given the token it needs.
+fn authorized(headers: &HeaderMap, token: Option<&str>, allow_unauthenticated: bool) -> bool {
+    let Some(token) = token else {
+        return allow_unauthenticated;
+    };
+
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    bearer == Some(token)
+}
+
+/// Changes need `Authorization: Bearer $BIRBS_ADMIN_TOKEN`, and are refused
+/// when there's no token unless `BIRBS_ALLOW_UNAUTHENTICATED` is set.
+async fn require_admin_token(request: Request, next: Next) -> Response {
+    if !authorized(
+        request.headers(),
+        get_admin_token().as_deref(),
+        allow_unauthenticated(),
+    ) {
+        return StatusCode::UNAUTHORIZED.into_response();
     }
+
+    next.run(request).await
+}
+
+const MAX_UPLOAD_BYTES: usize = 20 * 1024 * 1024;
+
+/// The image is the request body, attribution comes from the query.
+#[axum_macros::debug_handler]
+async fn upload_photo(
+    Extension(state): Extension<Arc<AppState>>,
+    species: Species,
+    Query(attribution): Query<photos::Attribution>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<photos::Photo>, StatusCode> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .ok_or(StatusCode::UNSUPPORTED_MEDIA_TYPE)?;
+
+    let content_type = content_type.to_owned();
+
+    photos::check_upload(&body, &content_type).map_err(|_| StatusCode::UNSUPPORTED_MEDIA_TYPE)?;
+
+    Ok(Json(
+        tokio::task::spawn_blocking(move || {
+            state
+                .photos
+                .upload(&species, &body, &content_type, attribution)
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    ))
+}
+
+#[derive(Deserialize)]
+struct BlockPhoto {
+    photo_id: String,
+    source: Option<String>,
+}
+
+#[axum_macros::debug_handler]
+async fn block_photo(
+    Extension(state): Extension<Arc<AppState>>,
+    species: Species,
+    Json(block): Json<BlockPhoto>,
+) -> Result<StatusCode, StatusCode> {
+    tokio::task::spawn_blocking(move || {
+        state.photos.block(
+            &species,
+            block.source.as_deref().unwrap_or("flickr"),
+            &block.photo_id,
+        )
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::NO_CONTENT)
 }
 
 async fn head_url(url: &str) -> bool {
     match new_http_client().head(url).send().await {
-        Ok(r) => match r.status() {
-            StatusCode::OK => true,
-            _ => false,
-        },
+        Ok(r) => matches!(r.status(), StatusCode::OK),
         Err(_) => false,
     }
 }
@@ -251,8 +842,8 @@ async fn check_recentlies_available(files: Vec<Recently>) -> Result<Vec<Recently
     use tokio_stream::{self as stream};
 
     const CONCURRENT_REQUESTS: usize = 5;
-    Ok(stream::iter(files.into_iter())
-        .map(|row| check_recently_available(row))
+    Ok(stream::iter(files)
+        .map(check_recently_available)
         .buffered(CONCURRENT_REQUESTS)
         .collect::<Vec<_>>()
         .await)
@@ -268,9 +859,41 @@ async fn check_files_available(files: Vec<FilesFor>) -> Result<Vec<FilesFor>> {
     use tokio_stream::{self as stream};
 
     const CONCURRENT_REQUESTS: usize = 5;
-    Ok(stream::iter(files.into_iter())
-        .map(|row| check_file_available(row))
+    Ok(stream::iter(files)
+        .map(check_file_available)
         .buffered(CONCURRENT_REQUESTS)
         .collect::<Vec<_>>()
         .await)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curation_needs_the_admin_token() {
+        let mut headers = HeaderMap::new();
+
+        assert!(!authorized(&headers, None, false));
+        assert!(authorized(&headers, None, true));
+        assert!(!authorized(&headers, Some("secret"), true));
+
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert!(!authorized(&headers, Some("secret"), false));
+
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(authorized(&headers, Some("secret"), false));
+    }
+
+    #[test]
+    fn renames_only_the_species_segment() {
+        let uri = "/Crow/photo.png?size=thumb&lang=es".parse().unwrap();
+        assert_eq!(
+            renamed_uri(&uri, "amecro"),
+            "/amecro/photo.png?size=thumb&lang=es"
+        );
+
+        let uri = "/American%20Crow/info.json".parse().unwrap();
+        assert_eq!(renamed_uri(&uri, "amecro"), "/amecro/info.json");
+    }
+}
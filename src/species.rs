@@ -0,0 +1,205 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{taxonomy, BirdDb};
+
+/// The identifier a species goes by in URLs: its eBird code when the taxonomy
+/// knows it, otherwise its scientific name as `genus-species`. Either
+/// survives BirdNET relabelling a common name and neither needs escaping.
+pub fn slug(scientific_name: &str) -> String {
+    match taxonomy::taxonomy().by_scientific_name(scientific_name) {
+        Some(taxon) => taxon.species_code.clone(),
+        None => scientific_name
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join("-")
+            .to_lowercase(),
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Species {
+    pub slug: String,
+    /// The name detections were most recently stored under.
+    pub common_name: String,
+    pub scientific_name: String,
+}
+
+impl Species {
+    fn new(common_name: &str, scientific_name: &str) -> Self {
+        Self {
+            slug: slug(scientific_name),
+            common_name: common_name.to_owned(),
+            scientific_name: scientific_name.to_owned(),
+        }
+    }
+}
+
+pub enum Lookup {
+    /// Found by its slug.
+    Canonical(Species),
+    /// Found by one of its names, and should be redirected to its slug.
+    Renamed(Species),
+}
+
+/// Names are read again after this long even without new detections, in case
+/// older ones were relabelled.
+const NAMES_TTL: Duration = Duration::from_secs(300);
+
+/// The names detected in each database, along with its newest row when they
+/// were read.
+type CachedNames = (i64, Instant, Arc<Vec<(String, String)>>);
+
+static NAMES: LazyLock<Mutex<HashMap<String, CachedNames>>> = LazyLock::new(Default::default);
+
+/// Every species route looks its species up, so the names detected are only
+/// read again once there's been a new detection.
+fn species_names(db: &BirdDb) -> Result<Arc<Vec<(String, String)>>> {
+    let Some(path) = db.conn.path().filter(|p| !p.is_empty()).map(str::to_owned) else {
+        return Ok(Arc::new(db.species_names()?));
+    };
+
+    let newest = db.newest_rowid()?;
+    if let Some((rowid, at, names)) = NAMES.lock().expect("species names lock").get(&path) {
+        if *rowid == newest && at.elapsed() < NAMES_TTL {
+            return Ok(names.clone());
+        }
+    }
+
+    let names = Arc::new(db.species_names()?);
+    NAMES
+        .lock()
+        .expect("species names lock")
+        .insert(path, (newest, Instant::now(), names.clone()));
+
+    Ok(names)
+}
+
+/// Old links use common names, sometimes with underscores for spaces.
+fn same_name(a: &str, b: &str) -> bool {
+    a.replace('_', " ")
+        .eq_ignore_ascii_case(&b.replace('_', " "))
+}
+
+/// Finds a species by slug, common name or scientific name. Names BirdNET has
+/// since relabelled still find the species they were detected as.
+pub fn lookup(db: &BirdDb, identifier: &str) -> Result<Option<Lookup>> {
+    let taxonomy = taxonomy::taxonomy();
+    let names = species_names(db)?;
+
+    // Oldest first, so the newest name for each species wins.
+    let mut by_scientific_name = HashMap::new();
+    for (common_name, scientific_name) in names.iter() {
+        by_scientific_name.insert(scientific_name.as_str(), common_name.as_str());
+    }
+
+    let detected = |scientific_name: &str| {
+        by_scientific_name
+            .get(scientific_name)
+            .map(|common_name| Species::new(common_name, scientific_name))
+    };
+
+    if let Some(species) = by_scientific_name
+        .iter()
+        .map(|(scientific_name, common_name)| Species::new(common_name, scientific_name))
+        .find(|s| s.slug == identifier)
+    {
+        return Ok(Some(Lookup::Canonical(species)));
+    }
+
+    if let Some(taxon) = taxonomy.by_species_code(identifier) {
+        let species = detected(&taxon.scientific_name)
+            .unwrap_or_else(|| Species::new(&taxon.common_name, &taxon.scientific_name));
+        return Ok(Some(Lookup::Canonical(species)));
+    }
+
+    if let Some(species) = names
+        .iter()
+        .find(|(common_name, scientific_name)| {
+            same_name(common_name, identifier) || same_name(scientific_name, identifier)
+        })
+        .and_then(|(_, scientific_name)| detected(scientific_name))
+    {
+        return Ok(Some(Lookup::Renamed(species)));
+    }
+
+    Ok(taxonomy
+        .by_common_name(&identifier.replace('_', " "))
+        .or_else(|| taxonomy.by_scientific_name(&identifier.replace('_', " ")))
+        .map(|taxon| {
+            detected(&taxon.scientific_name)
+                .unwrap_or_else(|| Species::new(&taxon.common_name, &taxon.scientific_name))
+        })
+        .map(Lookup::Renamed))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::tests::{db, detection};
+
+    #[test]
+    fn slugs_are_ebird_codes() {
+        assert_eq!(slug("Corvus brachyrhynchos"), "amecro");
+        assert_eq!(slug("Strix unknownia (hybrid)"), "strix-unknownia-hybrid");
+    }
+
+    fn found(db: &BirdDb, identifier: &str) -> Option<(bool, String, String)> {
+        lookup(db, identifier).unwrap().map(|found| match found {
+            Lookup::Canonical(s) => (true, s.slug, s.common_name),
+            Lookup::Renamed(s) => (false, s.slug, s.common_name),
+        })
+    }
+
+    #[test]
+    fn looks_species_up_by_slug_or_name() {
+        let at = |day| Utc.with_ymd_and_hms(2024, 5, day, 14, 0, 0).unwrap();
+        let db = db(&[
+            detection(at(1), "Corvus brachyrhynchos", "Common Crow"),
+            detection(at(2), "Corvus brachyrhynchos", "American Crow"),
+            detection(at(3), "Strix unknownia", "Mystery Owl"),
+        ]);
+
+        let canonical =
+            |slug: &str, common_name: &str| Some((true, slug.to_owned(), common_name.to_owned()));
+        let renamed =
+            |slug: &str, common_name: &str| Some((false, slug.to_owned(), common_name.to_owned()));
+
+        assert_eq!(found(&db, "amecro"), canonical("amecro", "American Crow"));
+        assert_eq!(
+            found(&db, "strix-unknownia"),
+            canonical("strix-unknownia", "Mystery Owl")
+        );
+        // In the taxonomy but never detected.
+        assert_eq!(found(&db, "amerob"), canonical("amerob", "American Robin"));
+
+        assert_eq!(
+            found(&db, "American_Crow"),
+            renamed("amecro", "American Crow")
+        );
+        assert_eq!(
+            found(&db, "Common Crow"),
+            renamed("amecro", "American Crow")
+        );
+        assert_eq!(
+            found(&db, "corvus brachyrhynchos"),
+            renamed("amecro", "American Crow")
+        );
+        assert_eq!(
+            found(&db, "Mystery_Owl"),
+            renamed("strix-unknownia", "Mystery Owl")
+        );
+        assert_eq!(
+            found(&db, "White-crowned Sparrow"),
+            renamed("whcspa", "White-crowned Sparrow")
+        );
+
+        assert_eq!(found(&db, "snark"), None);
+    }
+}
@@ -0,0 +1,921 @@
+use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rusqlite::{params, Connection, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tracing::info;
+
+use crate::photos::Photo;
+use crate::review::{QueueOrder, Status};
+use crate::{detection_from_row, BirdDateAndTime, Detection};
+
+type Migration = fn(&Transaction) -> Result<()>;
+
+/// Applied in order, the database's `user_version` is the number already applied.
+const MIGRATIONS: &[Migration] = &[
+    create_detections,
+    timestamps_species_and_indexes,
+    sync_state,
+    photos,
+    photo_curation,
+    species_by_scientific_name,
+    photos_by_scientific_name,
+    reviews,
+];
+
+/// Mirrors the columns of BirdNET-Pi's `detections` table so the same queries
+/// work against either, with a station and source column for imported rows.
+fn create_detections(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        r"CREATE TABLE IF NOT EXISTS detections (
+            id INTEGER PRIMARY KEY,
+            station TEXT NOT NULL,
+            date TEXT NOT NULL,
+            time TEXT NOT NULL,
+            sci_name TEXT NOT NULL,
+            com_name TEXT NOT NULL,
+            confidence REAL NOT NULL,
+            lat REAL NOT NULL,
+            lon REAL NOT NULL,
+            cutoff REAL NOT NULL,
+            week INTEGER NOT NULL,
+            sens REAL NOT NULL,
+            overlap REAL NOT NULL,
+            file_name TEXT NOT NULL,
+            source TEXT NOT NULL,
+            UNIQUE (station, date, time, sci_name)
+        );",
+    )?;
+
+    Ok(())
+}
+
+/// BirdNET-Pi only stores local dates and times and has no indexes, so every
+/// per-species query was a full table scan.
+fn timestamps_species_and_indexes(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        r"ALTER TABLE detections ADD COLUMN utc TEXT;
+
+        CREATE TABLE species (
+            sci_name TEXT PRIMARY KEY,
+            com_name TEXT NOT NULL
+        );
+
+        INSERT OR IGNORE INTO species (sci_name, com_name)
+            SELECT sci_name, com_name FROM detections GROUP BY sci_name;
+
+        CREATE INDEX detections_com_name_date ON detections (com_name, date);
+        CREATE INDEX detections_date ON detections (date);
+        CREATE INDEX detections_utc ON detections (utc);
+        CREATE INDEX detections_date_time ON detections (datetime(date, time));",
+    )?;
+
+    let rows = {
+        let mut stmt = tx.prepare(r"SELECT id, date, time FROM detections")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()?
+    };
+
+    let mut stmt = tx.prepare(r"UPDATE detections SET utc = ? WHERE id = ?")?;
+    for (id, date, time) in rows {
+        let when = BirdDateAndTime::new(date, time)?;
+        stmt.execute(params![utc_column(when.utc), id])?;
+    }
+
+    Ok(())
+}
+
+/// Tracks how far each station has been copied from BirdNET-Pi, and which rows
+/// BirdNET-Pi has since pruned but we've kept.
+fn sync_state(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        r"ALTER TABLE detections ADD COLUMN upstream_rowid INTEGER;
+        ALTER TABLE detections ADD COLUMN pruned_at TEXT;
+
+        CREATE INDEX detections_station_upstream_rowid ON detections (station, upstream_rowid);
+
+        CREATE TABLE sync_state (
+            station TEXT PRIMARY KEY,
+            upstream_rowid INTEGER NOT NULL,
+            utc TEXT,
+            synced_at TEXT NOT NULL
+        );",
+    )?;
+
+    Ok(())
+}
+
+/// The photo chosen for each species, the image itself is kept on disk.
+fn photos(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        r"CREATE TABLE photos (
+            com_name TEXT PRIMARY KEY,
+            source TEXT NOT NULL,
+            photo_id TEXT NOT NULL,
+            title TEXT NOT NULL,
+            owner TEXT NOT NULL,
+            owner_name TEXT,
+            license TEXT,
+            license_url TEXT,
+            source_url TEXT NOT NULL,
+            image_url TEXT NOT NULL,
+            file_name TEXT NOT NULL,
+            content_type TEXT NOT NULL,
+            chosen_at TEXT NOT NULL
+        );",
+    )?;
+
+    Ok(())
+}
+
+/// Photos picked or uploaded by hand, and ones never to be chosen again.
+fn photo_curation(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        r"ALTER TABLE photos ADD COLUMN curated INTEGER NOT NULL DEFAULT 0;
+
+        CREATE TABLE blocked_photos (
+            com_name TEXT NOT NULL,
+            source TEXT NOT NULL,
+            photo_id TEXT NOT NULL,
+            blocked_at TEXT NOT NULL,
+            PRIMARY KEY (com_name, source, photo_id)
+        );",
+    )?;
+
+    Ok(())
+}
+
+/// Routes find species by scientific name now, common names change.
+fn species_by_scientific_name(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        r"CREATE INDEX detections_sci_name_date ON detections (sci_name, date);
+        DROP INDEX detections_com_name_date;",
+    )?;
+
+    Ok(())
+}
+
+/// Photos and blocks follow a species through BirdNET renaming it. Any whose
+/// common name isn't in the species table can't be matched up, so they're
+/// dropped and a photo's chosen again.
+fn photos_by_scientific_name(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        r"CREATE TABLE species_photos (
+            sci_name TEXT PRIMARY KEY,
+            com_name TEXT NOT NULL,
+            source TEXT NOT NULL,
+            photo_id TEXT NOT NULL,
+            title TEXT NOT NULL,
+            owner TEXT NOT NULL,
+            owner_name TEXT,
+            license TEXT,
+            license_url TEXT,
+            source_url TEXT NOT NULL,
+            image_url TEXT NOT NULL,
+            file_name TEXT NOT NULL,
+            content_type TEXT NOT NULL,
+            chosen_at TEXT NOT NULL,
+            curated INTEGER NOT NULL DEFAULT 0
+        );
+
+        INSERT OR IGNORE INTO species_photos
+            SELECT species.sci_name, photos.com_name, source, photo_id, title, owner,
+                owner_name, license, license_url, source_url, image_url, file_name,
+                content_type, chosen_at, curated
+            FROM photos JOIN species ON species.com_name = photos.com_name;
+
+        DROP TABLE photos;
+        ALTER TABLE species_photos RENAME TO photos;
+
+        CREATE TABLE species_blocked_photos (
+            sci_name TEXT NOT NULL,
+            source TEXT NOT NULL,
+            photo_id TEXT NOT NULL,
+            blocked_at TEXT NOT NULL,
+            PRIMARY KEY (sci_name, source, photo_id)
+        );
+
+        INSERT OR IGNORE INTO species_blocked_photos
+            SELECT species.sci_name, source, photo_id, blocked_at
+            FROM blocked_photos JOIN species ON species.com_name = blocked_photos.com_name;
+
+        DROP TABLE blocked_photos;
+        ALTER TABLE species_blocked_photos RENAME TO blocked_photos;",
+    )?;
+
+    Ok(())
+}
+
+/// Detections marked as real or as false positives, either one recording at a
+/// time or every detection of a species over a span of time. BirdNET-Pi names
+/// each recording after the species, date and time it was detected at, so a
+/// file name is a single detection.
+fn reviews(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        r"CREATE TABLE reviews (
+            id INTEGER PRIMARY KEY,
+            file_name TEXT UNIQUE,
+            sci_name TEXT,
+            start TEXT,
+            end TEXT,
+            status TEXT NOT NULL,
+            reviewed_at TEXT NOT NULL
+        );
+
+        CREATE INDEX reviews_sci_name_start ON reviews (sci_name, start);
+        CREATE INDEX detections_file_name ON detections (file_name);",
+    )?;
+
+    Ok(())
+}
+
+/// A detection's review status, `d` being the detection. Its own review wins,
+/// then the newest span covering it.
+const REVIEW_STATUS: &str = r"COALESCE(
+    (SELECT r.status FROM reviews r WHERE r.file_name = d.file_name),
+    (SELECT r.status FROM reviews r
+        WHERE r.sci_name = d.sci_name AND d.utc BETWEEN r.start AND r.end
+        ORDER BY r.id DESC LIMIT 1),
+    'unreviewed')";
+
+/// Hides rejected detections from everything read through the connection
+/// afterwards, by shadowing `detections` with a temporary view of the rest.
+/// BirdNET-Pi's database has nothing to hide.
+pub fn exclude_rejected(conn: &Connection) -> Result<()> {
+    let reviewed: bool = conn.query_row(
+        r"SELECT COUNT(*) > 0 FROM main.sqlite_master WHERE type = 'table' AND name = 'reviews'",
+        [],
+        |row| row.get(0),
+    )?;
+
+    if reviewed {
+        conn.execute_batch(&format!(
+            r"CREATE TEMP VIEW IF NOT EXISTS detections AS
+            SELECT * FROM main.detections d WHERE {} != 'rejected'",
+            REVIEW_STATUS
+        ))?;
+    }
+
+    Ok(())
+}
+
+fn utc_column(utc: DateTime<Utc>) -> String {
+    utc.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let applied: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", version + 1)?;
+        tx.commit()?;
+
+        info!("migrated birbs database to version {}", version + 1);
+    }
+
+    Ok(())
+}
+
+pub fn get_store_database() -> Result<String> {
+    Ok(std::env::var("BIRBS_DB")?)
+}
+
+/// Station detections synced from the local BirdNET-Pi are recorded under.
+pub fn get_station() -> String {
+    std::env::var("BIRBS_STATION").unwrap_or_else(|_| "backyard".into())
+}
+
+/// How far a station has been copied from BirdNET-Pi.
+#[derive(Debug, Clone, Default)]
+pub struct SyncState {
+    pub upstream_rowid: i64,
+    pub utc: Option<DateTime<Utc>>,
+}
+
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    pub fn new() -> Result<Self> {
+        Self::open(get_store_database()?)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn)?;
+
+        Ok(Self { conn })
+    }
+
+    pub fn into_connection(self) -> Connection {
+        self.conn
+    }
+
+    pub fn sync_state(&self, station: &str) -> Result<Option<SyncState>> {
+        let mut stmt = self
+            .conn
+            .prepare(r"SELECT upstream_rowid, utc FROM sync_state WHERE station = ?")?;
+
+        let state = stmt
+            .query_map([station], |row| {
+                let utc: Option<String> = row.get(1)?;
+                Ok(SyncState {
+                    upstream_rowid: row.get(0)?,
+                    utc: utc.and_then(|u| {
+                        NaiveDateTime::parse_from_str(&u, "%Y-%m-%d %H:%M:%S")
+                            .ok()
+                            .map(|u| u.and_utc())
+                    }),
+                })
+            })?
+            .next()
+            .transpose()?;
+
+        Ok(state)
+    }
+
+    /// Stores detections copied from BirdNET-Pi along with their upstream
+    /// rowids, adopting any matching rows stored before rowids were tracked,
+    /// and advances the station's high-water mark in the same transaction.
+    pub fn upsert_synced(
+        &mut self,
+        station: &str,
+        rows: &[(i64, Detection)],
+        state: &SyncState,
+    ) -> Result<usize> {
+        let tx = self.conn.transaction()?;
+        let mut inserted = 0;
+
+        {
+            let mut existing = tx.prepare(
+                r"UPDATE detections SET upstream_rowid = ?, pruned_at = NULL
+                WHERE station = ? AND date = ? AND time = ? AND sci_name = ?",
+            )?;
+            let mut stmt = tx.prepare(
+                r"INSERT INTO detections
+                    (station, date, time, sci_name, com_name, confidence,
+                     lat, lon, cutoff, week, sens, overlap, file_name, source,
+                     utc, upstream_rowid)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 'birdnet-pi', ?, ?)",
+            )?;
+            let mut species = tx.prepare(
+                r"INSERT INTO species (sci_name, com_name) VALUES (?, ?)
+                ON CONFLICT (sci_name) DO UPDATE SET com_name = excluded.com_name",
+            )?;
+
+            for (rowid, d) in rows {
+                let local = BirdDateAndTime::from_utc(d.when).local;
+                let date = local.format("%Y-%m-%d").to_string();
+                let time = local.format("%H:%M:%S").to_string();
+
+                if existing.execute(params![rowid, station, date, time, d.scientific_name])? == 0 {
+                    inserted += stmt.execute(params![
+                        station,
+                        date,
+                        time,
+                        d.scientific_name,
+                        d.common_name,
+                        d.confidence,
+                        d.latitude,
+                        d.longitude,
+                        d.cutoff,
+                        d.week,
+                        d.sens,
+                        d.overlap,
+                        d.file_name,
+                        utc_column(d.when),
+                        rowid,
+                    ])?;
+                }
+                species.execute(params![d.scientific_name, d.common_name])?;
+            }
+
+            tx.execute(
+                r"INSERT INTO sync_state (station, upstream_rowid, utc, synced_at)
+                VALUES (?, ?, ?, ?)
+                ON CONFLICT (station) DO UPDATE SET
+                    upstream_rowid = excluded.upstream_rowid,
+                    utc = excluded.utc,
+                    synced_at = excluded.synced_at",
+                params![
+                    station,
+                    state.upstream_rowid,
+                    state.utc.map(utc_column),
+                    utc_column(Utc::now())
+                ],
+            )?;
+        }
+
+        tx.commit()?;
+
+        Ok(inserted)
+    }
+
+    /// How many synced rows up to the given upstream rowid are still present
+    /// upstream as far as we know.
+    pub fn synced_count(&self, station: &str, through: i64) -> Result<usize> {
+        Ok(self.conn.query_row(
+            r"SELECT COUNT(*) FROM detections
+            WHERE station = ? AND upstream_rowid <= ? AND pruned_at IS NULL",
+            params![station, through],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Synced rows still present upstream as far as we know, as `(id, upstream_rowid, date)`
+    /// ordered by upstream rowid.
+    pub fn synced_rows(&self, station: &str) -> Result<Vec<(i64, i64, String)>> {
+        let mut stmt = self.conn.prepare(
+            r"SELECT id, upstream_rowid, date FROM detections
+            WHERE station = ? AND upstream_rowid IS NOT NULL AND pruned_at IS NULL
+            ORDER BY upstream_rowid",
+        )?;
+
+        let rows = stmt.query_map([station], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+
+        rows.into_iter()
+            .map(|row| Ok(row?))
+            .collect::<Result<Vec<_>>>()
+    }
+
+    /// Keeps rows BirdNET-Pi aged out, and drops the ones somebody deleted there.
+    pub fn apply_upstream_removals(&mut self, pruned: &[i64], deleted: &[i64]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+
+        {
+            let now = utc_column(Utc::now());
+            let mut prune = tx.prepare(r"UPDATE detections SET pruned_at = ? WHERE id = ?")?;
+            for id in pruned {
+                prune.execute(params![now, id])?;
+            }
+
+            let mut delete = tx.prepare(r"DELETE FROM detections WHERE id = ?")?;
+            for id in deleted {
+                delete.execute([id])?;
+            }
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Inserts detections for a station, skipping any already stored for the
+    /// same station, local date and time and species. Returns how many were new.
+    pub fn insert_detections(
+        &mut self,
+        station: &str,
+        source: &str,
+        detections: &[Detection],
+    ) -> Result<usize> {
+        let tx = self.conn.transaction()?;
+        let mut inserted = 0;
+
+        {
+            let mut stmt = tx.prepare(
+                r"INSERT OR IGNORE INTO detections
+                    (station, date, time, sci_name, com_name, confidence,
+                     lat, lon, cutoff, week, sens, overlap, file_name, source,
+                     utc)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )?;
+            let mut species = tx.prepare(
+                r"INSERT INTO species (sci_name, com_name) VALUES (?, ?)
+                ON CONFLICT (sci_name) DO UPDATE SET com_name = excluded.com_name",
+            )?;
+
+            for d in detections {
+                let local = BirdDateAndTime::from_utc(d.when).local;
+
+                inserted += stmt.execute(params![
+                    station,
+                    local.format("%Y-%m-%d").to_string(),
+                    local.format("%H:%M:%S").to_string(),
+                    d.scientific_name,
+                    d.common_name,
+                    d.confidence,
+                    d.latitude,
+                    d.longitude,
+                    d.cutoff,
+                    d.week,
+                    d.sens,
+                    d.overlap,
+                    d.file_name,
+                    source,
+                    utc_column(d.when),
+                ])?;
+                species.execute(params![d.scientific_name, d.common_name])?;
+            }
+        }
+
+        tx.commit()?;
+
+        Ok(inserted)
+    }
+
+    pub fn photo(&self, scientific_name: &str) -> Result<Option<Photo>> {
+        let mut stmt = self.conn.prepare(
+            r"SELECT com_name, source, photo_id, title, owner, owner_name, license,
+                license_url, source_url, image_url, file_name, content_type, curated,
+                sci_name
+            FROM photos WHERE sci_name = ?",
+        )?;
+
+        let photo = stmt
+            .query_map([scientific_name], |row| {
+                Ok(Photo {
+                    common_name: row.get(0)?,
+                    scientific_name: row.get(13)?,
+                    source: row.get(1)?,
+                    photo_id: row.get(2)?,
+                    title: row.get(3)?,
+                    owner: row.get(4)?,
+                    owner_name: row.get(5)?,
+                    license: row.get(6)?,
+                    license_url: row.get(7)?,
+                    source_url: row.get(8)?,
+                    image_url: row.get(9)?,
+                    file_name: row.get(10)?,
+                    content_type: row.get(11)?,
+                    curated: row.get(12)?,
+                })
+            })?
+            .next()
+            .transpose()?;
+
+        Ok(photo)
+    }
+
+    pub fn save_photo(&self, photo: &Photo) -> Result<()> {
+        self.conn.execute(
+            r"INSERT OR REPLACE INTO photos
+                (sci_name, com_name, source, photo_id, title, owner, owner_name, license,
+                 license_url, source_url, image_url, file_name, content_type, curated,
+                 chosen_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                photo.scientific_name,
+                photo.common_name,
+                photo.source,
+                photo.photo_id,
+                photo.title,
+                photo.owner,
+                photo.owner_name,
+                photo.license,
+                photo.license_url,
+                photo.source_url,
+                photo.image_url,
+                photo.file_name,
+                photo.content_type,
+                photo.curated,
+                utc_column(Utc::now()),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn forget_photo(&self, scientific_name: &str) -> Result<()> {
+        self.conn
+            .execute(r"DELETE FROM photos WHERE sci_name = ?", [scientific_name])?;
+
+        Ok(())
+    }
+
+    pub fn block_photo(&self, scientific_name: &str, source: &str, photo_id: &str) -> Result<()> {
+        self.conn.execute(
+            r"INSERT OR IGNORE INTO blocked_photos (sci_name, source, photo_id, blocked_at)
+            VALUES (?, ?, ?, ?)",
+            params![scientific_name, source, photo_id, utc_column(Utc::now())],
+        )?;
+
+        Ok(())
+    }
+
+    /// Ids of the photos blocked for a species from the given source.
+    pub fn blocked_photos(&self, scientific_name: &str, source: &str) -> Result<HashSet<String>> {
+        let mut stmt = self
+            .conn
+            .prepare(r"SELECT photo_id FROM blocked_photos WHERE sci_name = ? AND source = ?")?;
+
+        let rows = stmt.query_map([scientific_name, source], |row| row.get(0))?;
+
+        rows.into_iter()
+            .map(|row| Ok(row?))
+            .collect::<Result<HashSet<_>>>()
+    }
+
+    pub fn common_name_to_scientific_name(&self) -> Result<HashMap<String, String>> {
+        let mut stmt = self
+            .conn
+            .prepare(r"SELECT com_name, sci_name FROM species")?;
+
+        let res = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        res.into_iter()
+            .map(|row| Ok(row?))
+            .collect::<Result<HashMap<_, _>>>()
+    }
+
+    /// Marks a single detection by its recording, returning false when there's
+    /// no such recording. Unreviewed clears the mark, leaving any span's.
+    pub fn review_file(&self, file_name: &str, status: Status) -> Result<bool> {
+        let known: bool = self.conn.query_row(
+            r"SELECT COUNT(*) > 0 FROM detections WHERE file_name = ?",
+            [file_name],
+            |row| row.get(0),
+        )?;
+        if !known {
+            return Ok(false);
+        }
+
+        match status {
+            Status::Unreviewed => {
+                self.conn
+                    .execute(r"DELETE FROM reviews WHERE file_name = ?", [file_name])?;
+            }
+            _ => {
+                self.conn.execute(
+                    r"INSERT INTO reviews (file_name, status, reviewed_at) VALUES (?, ?, ?)
+                    ON CONFLICT (file_name) DO UPDATE SET
+                        status = excluded.status,
+                        reviewed_at = excluded.reviewed_at",
+                    params![file_name, status.as_str(), utc_column(Utc::now())],
+                )?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Marks every detection of a species between two times, inclusive. Spans
+    /// stack, so marking one unreviewed undoes older spans it overlaps.
+    pub fn review_span(
+        &self,
+        scientific_name: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        status: Status,
+    ) -> Result<usize> {
+        self.conn.execute(
+            r"INSERT INTO reviews (sci_name, start, end, status, reviewed_at)
+            VALUES (?, ?, ?, ?, ?)",
+            params![
+                scientific_name,
+                utc_column(start),
+                utc_column(end),
+                status.as_str(),
+                utc_column(Utc::now())
+            ],
+        )?;
+
+        Ok(self.conn.query_row(
+            r"SELECT COUNT(*) FROM detections WHERE sci_name = ? AND utc BETWEEN ? AND ?",
+            params![scientific_name, utc_column(start), utc_column(end)],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Detections nobody has reviewed yet whose recordings are still around,
+    /// along with how many times each one's species has been detected.
+    pub fn review_queue(
+        &self,
+        order: QueueOrder,
+        min_confidence: f32,
+        limit: usize,
+    ) -> Result<Vec<(Detection, u64)>> {
+        let order = match order {
+            QueueOrder::LeastConfident => "d.confidence, t.total",
+            QueueOrder::Rarest => "t.total, d.confidence",
+        };
+
+        let mut stmt = self.conn.prepare(&format!(
+            r"WITH totals AS (
+                SELECT sci_name, COUNT(*) AS total FROM detections GROUP BY sci_name
+            )
+            SELECT d.date, d.time, d.sci_name, d.com_name, d.confidence, d.lat, d.lon,
+                d.cutoff, d.week, d.sens, d.overlap, d.file_name, t.total
+            FROM detections d JOIN totals t ON t.sci_name = d.sci_name
+            WHERE d.pruned_at IS NULL AND d.file_name != '' AND d.confidence >= ?
+                AND {} = 'unreviewed'
+            ORDER BY {}, d.utc DESC
+            LIMIT ?",
+            REVIEW_STATUS, order
+        ))?;
+
+        let rows = stmt.query_map(params![min_confidence, limit], |row| {
+            Ok((detection_from_row(row)?, row.get(12)?))
+        })?;
+
+        rows.into_iter()
+            .map(|row| Ok(row?))
+            .collect::<Result<Vec<_>>>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::tests::detection;
+    use crate::BirdDb;
+
+    #[test]
+    fn migrates_and_keeps_species_current() {
+        let mut store = Store::open(":memory:").unwrap();
+        let when = Utc.with_ymd_and_hms(2024, 5, 1, 14, 0, 0).unwrap();
+        let detections = [
+            detection(when, "Turdus migratorius", "American Robin"),
+            detection(when, "Turdus migratorius", "American Robin"),
+            detection(when, "Corvus brachyrhynchos", "Crow"),
+            detection(when, "Corvus brachyrhynchos", "American Crow"),
+        ];
+
+        assert_eq!(
+            store
+                .insert_detections("home", "test", &detections)
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            store.common_name_to_scientific_name().unwrap(),
+            HashMap::from([
+                ("American Robin".into(), "Turdus migratorius".into()),
+                ("American Crow".into(), "Corvus brachyrhynchos".into()),
+            ])
+        );
+
+        let (version, date, time, utc): (usize, String, String, String) = store
+            .conn
+            .query_row(
+                r"SELECT (SELECT user_version FROM pragma_user_version), date, time, utc
+                FROM detections LIMIT 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        assert_eq!(
+            (date.as_str(), time.as_str(), utc.as_str()),
+            ("2024-05-01", "07:00:00", "2024-05-01 14:00:00")
+        );
+    }
+
+    #[test]
+    fn remembers_the_chosen_photo() {
+        let store = Store::open(":memory:").unwrap();
+        let mut photo = Photo {
+            common_name: "American Robin".into(),
+            scientific_name: "Turdus migratorius".into(),
+            source: "flickr".into(),
+            photo_id: "1".into(),
+            title: "Robin".into(),
+            owner: "owner".into(),
+            owner_name: Some("Owner".into()),
+            license: Some("CC BY 2.0".into()),
+            license_url: Some("https://creativecommons.org/licenses/by/2.0/".into()),
+            source_url: "https://www.flickr.com/photos/owner/1".into(),
+            image_url: "https://live.staticflickr.com/1/1_a_b.jpg".into(),
+            file_name: "flickr-1.jpg".into(),
+            content_type: "image/jpeg".into(),
+            curated: false,
+        };
+
+        assert!(store.photo("Turdus migratorius").unwrap().is_none());
+
+        store.save_photo(&photo).unwrap();
+        photo.photo_id = "2".into();
+        photo.file_name = "flickr-2.jpg".into();
+        store.save_photo(&photo).unwrap();
+
+        let saved = store.photo("Turdus migratorius").unwrap().unwrap();
+        assert_eq!(
+            (saved.photo_id.as_str(), saved.file_name.as_str()),
+            ("2", "flickr-2.jpg")
+        );
+        assert_eq!(saved.license_url, photo.license_url);
+        assert!(store.photo("Corvus brachyrhynchos").unwrap().is_none());
+
+        store
+            .block_photo("Turdus migratorius", "flickr", "1")
+            .unwrap();
+        store.forget_photo("Turdus migratorius").unwrap();
+        assert!(store.photo("Turdus migratorius").unwrap().is_none());
+        assert_eq!(
+            store
+                .blocked_photos("Turdus migratorius", "flickr")
+                .unwrap(),
+            HashSet::from(["1".to_owned()])
+        );
+        assert!(store
+            .blocked_photos("Corvus brachyrhynchos", "flickr")
+            .unwrap()
+            .is_empty());
+    }
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 14, minute, 0).unwrap()
+    }
+
+    /// Three robins and a crow.
+    fn store() -> Store {
+        let mut store = Store::open(":memory:").unwrap();
+        let detections = [
+            (0, "Turdus migratorius", "American Robin", "robin-1.mp3"),
+            (2, "Corvus brachyrhynchos", "American Crow", "crow-1.mp3"),
+            (5, "Turdus migratorius", "American Robin", "robin-2.mp3"),
+            (10, "Turdus migratorius", "American Robin", "robin-3.mp3"),
+        ]
+        .map(|(minute, scientific_name, common_name, file_name)| {
+            let mut detection = detection(at(minute), scientific_name, common_name);
+            detection.file_name = file_name.to_owned();
+            detection
+        });
+        store
+            .insert_detections("home", "test", &detections)
+            .unwrap();
+
+        store
+    }
+
+    fn file_names(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT file_name FROM detections ORDER BY utc")
+            .unwrap();
+        let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    #[test]
+    fn excludes_rejected_detections() {
+        let store = store();
+        assert!(store.review_file("crow-1.mp3", Status::Rejected).unwrap());
+        assert!(!store.review_file("missing.mp3", Status::Rejected).unwrap());
+        assert_eq!(
+            store
+                .review_span("Turdus migratorius", at(4), at(11), Status::Rejected)
+                .unwrap(),
+            2
+        );
+        // A recording's own review wins over the span's.
+        assert!(store.review_file("robin-3.mp3", Status::Confirmed).unwrap());
+
+        let db = BirdDb {
+            conn: store.into_connection(),
+        }
+        .exclude_rejected()
+        .unwrap();
+
+        assert_eq!(file_names(&db.conn), vec!["robin-1.mp3", "robin-3.mp3"]);
+        let stored: usize = db
+            .conn
+            .query_row("SELECT COUNT(*) FROM main.detections", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, 4);
+    }
+
+    #[test]
+    fn newer_spans_undo_older_ones() {
+        let store = store();
+        store
+            .review_span("Turdus migratorius", at(0), at(10), Status::Rejected)
+            .unwrap();
+        store
+            .review_span("Turdus migratorius", at(4), at(6), Status::Unreviewed)
+            .unwrap();
+
+        exclude_rejected(&store.conn).unwrap();
+
+        assert_eq!(file_names(&store.conn), vec!["crow-1.mp3", "robin-2.mp3"]);
+    }
+
+    #[test]
+    fn queues_unreviewed_detections() {
+        let store = store();
+        store.review_file("robin-1.mp3", Status::Confirmed).unwrap();
+
+        let queued = |order| {
+            store
+                .review_queue(order, 0.0, 10)
+                .unwrap()
+                .into_iter()
+                .map(|(d, total)| (d.file_name, total))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            queued(QueueOrder::Rarest),
+            vec![
+                ("crow-1.mp3".to_owned(), 1),
+                ("robin-3.mp3".to_owned(), 3),
+                ("robin-2.mp3".to_owned(), 3)
+            ]
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Where the sun's upper limb touches the horizon, allowing for refraction.
+const SUNRISE_ZENITH: f64 = 90.833;
+const CIVIL_TWILIGHT_ZENITH: f64 = 96.0;
+
+/// The sun's day at a place. Rise and set are missing when the sun doesn't
+/// cross the horizon, which only happens far north or south.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct SunTimes {
+    pub civil_dawn: Option<DateTime<Utc>>,
+    pub sunrise: Option<DateTime<Utc>>,
+    pub solar_noon: DateTime<Utc>,
+    pub sunset: Option<DateTime<Utc>>,
+    pub civil_dusk: Option<DateTime<Utc>>,
+}
+
+/// NOAA's solar calculator, good to a minute or so for dates near the present.
+/// The date is the local one, so events can land on a neighbouring UTC day.
+pub fn sun_times(date: NaiveDate, latitude: f64, longitude: f64) -> SunTimes {
+    let epoch = NaiveDate::from_ymd_opt(2000, 1, 1).expect("valid epoch");
+    // Julian day at local noon, which is what the sunrise equation is about.
+    let julian_day = 2451545.0 + (date - epoch).num_days() as f64 - longitude / 360.0;
+    let t = (julian_day - 2451545.0) / 36525.0;
+
+    let mean_longitude = (280.46646 + t * (36000.76983 + t * 0.0003032)).rem_euclid(360.0);
+    let mean_anomaly = 357.52911 + t * (35999.05029 - 0.0001537 * t);
+    let eccentricity = 0.016708634 - t * (0.000042037 + 0.0000001267 * t);
+
+    let m = mean_anomaly.to_radians();
+    let center = m.sin() * (1.914602 - t * (0.004817 + 0.000014 * t))
+        + (2.0 * m).sin() * (0.019993 - 0.000101 * t)
+        + (3.0 * m).sin() * 0.000289;
+    let omega = (125.04 - 1934.136 * t).to_radians();
+    let apparent_longitude =
+        (mean_longitude + center - 0.00569 - 0.00478 * omega.sin()).to_radians();
+
+    let mean_obliquity =
+        23.0 + (26.0 + (21.448 - t * (46.815 + t * (0.00059 - t * 0.001813))) / 60.0) / 60.0;
+    let obliquity = (mean_obliquity + 0.00256 * omega.cos()).to_radians();
+    let declination = (obliquity.sin() * apparent_longitude.sin()).asin();
+
+    let l = mean_longitude.to_radians();
+    let y = (obliquity / 2.0).tan().powi(2);
+    let equation_of_time = 4.0
+        * (y * (2.0 * l).sin() - 2.0 * eccentricity * m.sin()
+            + 4.0 * eccentricity * y * m.sin() * (2.0 * l).cos()
+            - 0.5 * y * y * (4.0 * l).sin()
+            - 1.25 * eccentricity * eccentricity * (2.0 * m).sin())
+        .to_degrees();
+
+    // Minutes after midnight UTC.
+    let noon = 720.0 - 4.0 * longitude - equation_of_time;
+    let midnight = date.and_hms_opt(0, 0, 0).expect("valid midnight").and_utc();
+    let at = |minutes: f64| midnight + Duration::seconds((minutes * 60.0).round() as i64);
+
+    let lat = latitude.to_radians();
+    let hour_angle = |zenith: f64| {
+        let cos = zenith.to_radians().cos() / (lat.cos() * declination.cos())
+            - lat.tan() * declination.tan();
+        (-1.0..=1.0).contains(&cos).then(|| cos.acos().to_degrees())
+    };
+    let before = |zenith| hour_angle(zenith).map(|h| at(noon - 4.0 * h));
+    let after = |zenith| hour_angle(zenith).map(|h| at(noon + 4.0 * h));
+
+    SunTimes {
+        civil_dawn: before(CIVIL_TWILIGHT_ZENITH),
+        sunrise: before(SUNRISE_ZENITH),
+        solar_noon: at(noon),
+        sunset: after(SUNRISE_ZENITH),
+        civil_dusk: after(CIVIL_TWILIGHT_ZENITH),
+    }
+}
+
+/// Remembers sun times by day and place, detections share a handful of both.
+#[derive(Default)]
+pub struct Almanac {
+    days: HashMap<(NaiveDate, i32, i32), SunTimes>,
+}
+
+impl Almanac {
+    pub fn day(&mut self, date: NaiveDate, latitude: f64, longitude: f64) -> SunTimes {
+        // A hundredth of a degree is about a kilometre, seconds of sunrise.
+        let place = (
+            (latitude * 100.0).round() as i32,
+            (longitude * 100.0).round() as i32,
+        );
+
+        *self
+            .days
+            .entry((date, place.0, place.1))
+            .or_insert_with(|| sun_times(date, latitude, longitude))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn near(actual: Option<DateTime<Utc>>, expected: DateTime<Utc>) -> bool {
+        actual.is_some_and(|a| (a - expected).num_seconds().abs() <= 120)
+    }
+
+    #[test]
+    fn seattle_midsummer() {
+        let sun = sun_times(NaiveDate::from_ymd_opt(2024, 6, 20).unwrap(), 47.6, -122.3);
+
+        // 05:11 and 21:10 PDT, from NOAA's calculator.
+        assert!(near(
+            sun.sunrise,
+            Utc.with_ymd_and_hms(2024, 6, 20, 12, 11, 0).unwrap()
+        ));
+        assert!(near(
+            sun.sunset,
+            Utc.with_ymd_and_hms(2024, 6, 21, 4, 10, 0).unwrap()
+        ));
+        assert!(sun.civil_dawn < sun.sunrise);
+        assert!(sun.civil_dusk > sun.sunset);
+    }
+
+    #[test]
+    fn midnight_sun() {
+        let sun = sun_times(NaiveDate::from_ymd_opt(2024, 6, 20).unwrap(), 78.2, 15.6);
+
+        assert!(sun.sunrise.is_none());
+        assert!(sun.sunset.is_none());
+    }
+}
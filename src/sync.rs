@@ -0,0 +1,222 @@
+use anyhow::Result;
+use clap::Args;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::store::{get_station, Store};
+use crate::BirdDb;
+
+#[derive(Debug, Args)]
+pub struct Command {
+    /// Keep syncing, waiting this many seconds between runs.
+    #[arg(short, long)]
+    watch: Option<u64>,
+}
+
+pub async fn execute(cmd: Command) -> Result<()> {
+    match cmd.watch {
+        Some(seconds) => run_every(Duration::from_secs(seconds)).await,
+        None => {
+            let report = sync_once()?;
+            info!("{:?}", report);
+            Ok(())
+        }
+    }
+}
+
+/// How often `serve` syncs in the background, when at all.
+pub fn get_sync_interval() -> Option<Duration> {
+    std::env::var("BIRBS_SYNC_INTERVAL")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(Duration::from_secs)
+}
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub copied: usize,
+    pub inserted: usize,
+    pub pruned: usize,
+    pub deleted: usize,
+}
+
+pub async fn run_every(period: Duration) -> Result<()> {
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        match tokio::task::spawn_blocking(sync_once).await? {
+            Ok(report) => info!("{:?}", report),
+            Err(e) => warn!("sync failed: {:?}", e),
+        }
+    }
+}
+
+pub fn sync_once() -> Result<SyncReport> {
+    let mut store = Store::new()?;
+    let birdnet = BirdDb::birdnet()?;
+
+    sync(&mut store, &birdnet, &get_station())
+}
+
+/// Copies rows added to BirdNET-Pi since the last sync, then reconciles rows
+/// that have disappeared upstream. Anything older than BirdNET-Pi's oldest
+/// remaining date was pruned and is kept, anything newer was deleted by hand
+/// and is deleted here too.
+pub fn sync(store: &mut Store, birdnet: &BirdDb, station: &str) -> Result<SyncReport> {
+    const BATCH_SIZE: usize = 1000;
+
+    let mut report = SyncReport::default();
+    let mut state = store.sync_state(station)?.unwrap_or_default();
+
+    let newest_upstream = birdnet.newest_rowid()?;
+
+    if newest_upstream < state.upstream_rowid {
+        // The table was rebuilt, start over and let rows be matched up by
+        // date, time and species instead.
+        warn!(
+            "birdnet-pi rowids went backwards ({} < {}), resyncing",
+            newest_upstream, state.upstream_rowid
+        );
+        state.upstream_rowid = 0;
+    }
+
+    loop {
+        let rows = birdnet.detections_after(state.upstream_rowid, BATCH_SIZE)?;
+        let Some((last, _)) = rows.last() else {
+            break;
+        };
+
+        state.upstream_rowid = *last;
+        state.utc = rows.iter().map(|(_, d)| d.when).max().max(state.utc);
+
+        report.inserted += store.upsert_synced(station, &rows, &state)?;
+        report.copied += rows.len();
+    }
+
+    // Rowids only grow, so while as many rows remain upstream as we have
+    // copied there is nothing to reconcile.
+    let remaining = birdnet.count_through(state.upstream_rowid)?;
+    if remaining == store.synced_count(station, state.upstream_rowid)? {
+        return Ok(report);
+    }
+
+    let oldest = birdnet.oldest_date()?;
+    let mut pruned = Vec::new();
+    let mut deleted = Vec::new();
+
+    for rows in store.synced_rows(station)?.chunks(BATCH_SIZE) {
+        let upstream = birdnet.rowids_between(rows[0].1, rows[rows.len() - 1].1)?;
+
+        for (id, rowid, date) in rows {
+            if *rowid > newest_upstream || upstream.contains(rowid) {
+                continue;
+            }
+
+            match &oldest {
+                Some(oldest) if date >= oldest => deleted.push(*id),
+                _ => pruned.push(*id),
+            }
+        }
+    }
+
+    store.apply_upstream_removals(&pruned, &deleted)?;
+
+    report.pruned = pruned.len();
+    report.deleted = deleted.len();
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::tests::{db, detection};
+
+    #[test]
+    fn syncs_new_rows_once() {
+        let at = |hour, scientific_name, common_name| {
+            detection(
+                Utc.with_ymd_and_hms(2024, 5, 1, hour, 0, 0).unwrap(),
+                scientific_name,
+                common_name,
+            )
+        };
+        let birdnet = db(&[
+            at(14, "Turdus migratorius", "American Robin"),
+            at(15, "Corvus brachyrhynchos", "American Crow"),
+        ]);
+        let mut store = Store::open(":memory:").unwrap();
+        let rowids = |store: &Store| {
+            store
+                .synced_rows("home")
+                .unwrap()
+                .into_iter()
+                .map(|(_, rowid, _)| rowid)
+                .collect::<Vec<_>>()
+        };
+
+        let report = sync(&mut store, &birdnet, "home").unwrap();
+        assert_eq!((report.copied, report.inserted), (2, 2));
+        assert_eq!(rowids(&store), vec![1, 2]);
+
+        let report = sync(&mut store, &birdnet, "home").unwrap();
+        assert_eq!((report.copied, report.inserted), (0, 0));
+        assert_eq!(rowids(&store), vec![1, 2]);
+
+        birdnet
+            .conn
+            .execute_batch(
+                r"INSERT INTO detections SELECT * FROM detections WHERE rowid = 2;
+                UPDATE detections SET Time = '09:00:00' WHERE rowid = 3;",
+            )
+            .unwrap();
+
+        let report = sync(&mut store, &birdnet, "home").unwrap();
+        assert_eq!((report.copied, report.inserted), (1, 1));
+        assert_eq!(rowids(&store), vec![1, 2, 3]);
+        assert_eq!(store.sync_state("home").unwrap().unwrap().upstream_rowid, 3);
+    }
+
+    #[test]
+    fn keeps_pruned_rows_and_drops_deleted_ones() {
+        let at = |day, scientific_name, common_name| {
+            detection(
+                Utc.with_ymd_and_hms(2024, 5, day, 14, 0, 0).unwrap(),
+                scientific_name,
+                common_name,
+            )
+        };
+        let birdnet = db(&[
+            at(1, "Turdus migratorius", "American Robin"),
+            at(2, "Corvus brachyrhynchos", "American Crow"),
+            at(3, "Turdus migratorius", "American Robin"),
+            at(4, "Corvus brachyrhynchos", "American Crow"),
+        ]);
+        let mut store = Store::open(":memory:").unwrap();
+        sync(&mut store, &birdnet, "home").unwrap();
+
+        birdnet
+            .conn
+            .execute_batch(r"DELETE FROM detections WHERE rowid IN (1, 3)")
+            .unwrap();
+
+        let report = sync(&mut store, &birdnet, "home").unwrap();
+        assert_eq!((report.pruned, report.deleted), (1, 1));
+        assert_eq!(
+            store
+                .synced_rows("home")
+                .unwrap()
+                .into_iter()
+                .map(|(_, rowid, _)| rowid)
+                .collect::<Vec<_>>(),
+            vec![2, 4]
+        );
+
+        let report = sync(&mut store, &birdnet, "home").unwrap();
+        assert_eq!((report.pruned, report.deleted), (0, 0));
+    }
+}
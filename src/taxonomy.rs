@@ -0,0 +1,314 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::sync::LazyLock;
+use tracing::{info, warn};
+
+/// A handful of local species in the eBird taxonomy's CSV layout, used unless
+/// the full export is configured.
+const BUNDLED_TAXONOMY: &str = include_str!("../data/taxonomy.csv");
+
+/// Alternate names, in BirdNET's `Scientific name_Common name` label format.
+/// Only these languages are bundled, and only for the bundled taxonomy's species.
+const BUNDLED_LABELS: &[(&str, &str)] = &[
+    ("es", include_str!("../data/labels/es.txt")),
+    ("fr", include_str!("../data/labels/fr.txt")),
+];
+
+/// The full eBird/Clements taxonomy CSV, as downloaded from the eBird API.
+pub fn get_taxonomy_file() -> Option<String> {
+    std::env::var("BIRBS_TAXONOMY").ok()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Taxon {
+    #[serde(rename(deserialize = "SCIENTIFIC_NAME"))]
+    pub scientific_name: String,
+    #[serde(rename(deserialize = "COMMON_NAME"))]
+    pub common_name: String,
+    #[serde(rename(deserialize = "SPECIES_CODE"))]
+    pub species_code: String,
+    #[serde(rename(deserialize = "CATEGORY"))]
+    pub category: String,
+    /// eBird's sequence number, missing from the bundled taxonomy.
+    #[serde(rename(deserialize = "TAXON_ORDER"))]
+    pub taxon_order: Option<f64>,
+    #[serde(rename(deserialize = "ORDER"))]
+    pub order: String,
+    #[serde(rename(deserialize = "FAMILY_COM_NAME"))]
+    pub family_common_name: String,
+    #[serde(rename(deserialize = "FAMILY_SCI_NAME"))]
+    pub family_scientific_name: String,
+}
+
+pub struct Taxonomy {
+    taxa: Vec<Taxon>,
+    by_scientific_name: HashMap<String, usize>,
+    by_common_name: HashMap<String, usize>,
+    by_species_code: HashMap<String, usize>,
+    /// Alternate names by scientific name, then language.
+    names: HashMap<String, BTreeMap<String, String>>,
+}
+
+static TAXONOMY: LazyLock<Taxonomy> = LazyLock::new(Taxonomy::load);
+
+pub fn taxonomy() -> &'static Taxonomy {
+    &TAXONOMY
+}
+
+fn read_taxa(reader: impl Read) -> Result<Vec<Taxon>> {
+    let mut reader = csv::Reader::from_reader(reader);
+    Ok(reader.deserialize().collect::<Result<Vec<Taxon>, _>>()?)
+}
+
+fn read_labels(labels: &str) -> impl Iterator<Item = (&str, &str)> {
+    labels
+        .lines()
+        .filter_map(|line| line.trim().split_once('_'))
+}
+
+impl Taxonomy {
+    fn load() -> Self {
+        let taxa = match get_taxonomy_file() {
+            Some(path) => match std::fs::File::open(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(read_taxa)
+            {
+                Ok(taxa) => {
+                    info!("{} taxa from {}", taxa.len(), path);
+                    Some(taxa)
+                }
+                Err(e) => {
+                    warn!("taxonomy {}: {:#}, using bundled taxonomy", path, e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let taxa = taxa
+            .unwrap_or_else(|| read_taxa(BUNDLED_TAXONOMY.as_bytes()).expect("bundled taxonomy"));
+
+        Self::new(taxa)
+    }
+
+    fn new(taxa: Vec<Taxon>) -> Self {
+        let mut by_scientific_name = HashMap::new();
+        let mut by_common_name = HashMap::new();
+        let mut by_species_code = HashMap::new();
+        for (i, taxon) in taxa.iter().enumerate() {
+            by_scientific_name
+                .entry(taxon.scientific_name.clone())
+                .or_insert(i);
+            by_common_name.entry(taxon.common_name.clone()).or_insert(i);
+            by_species_code
+                .entry(taxon.species_code.clone())
+                .or_insert(i);
+        }
+
+        let mut names: HashMap<String, BTreeMap<String, String>> = HashMap::new();
+        for (language, labels) in BUNDLED_LABELS {
+            for (scientific_name, name) in read_labels(labels) {
+                names
+                    .entry(scientific_name.to_owned())
+                    .or_default()
+                    .insert((*language).to_owned(), name.to_owned());
+            }
+        }
+
+        Self {
+            taxa,
+            by_scientific_name,
+            by_common_name,
+            by_species_code,
+            names,
+        }
+    }
+
+    pub fn by_scientific_name(&self, scientific_name: &str) -> Option<&Taxon> {
+        self.by_scientific_name
+            .get(scientific_name)
+            .map(|i| &self.taxa[*i])
+    }
+
+    pub fn by_common_name(&self, common_name: &str) -> Option<&Taxon> {
+        self.by_common_name.get(common_name).map(|i| &self.taxa[*i])
+    }
+
+    pub fn by_species_code(&self, species_code: &str) -> Option<&Taxon> {
+        self.by_species_code
+            .get(species_code)
+            .map(|i| &self.taxa[*i])
+    }
+
+    /// A species' name in another language, if there's a label for it.
+    pub fn translate(&self, language: &str, scientific_name: &str) -> Option<&str> {
+        self.names
+            .get(scientific_name)?
+            .get(language)
+            .map(|n| n.as_str())
+    }
+
+    /// Names for a species in other languages, keyed by language code.
+    pub fn names(&self, scientific_name: &str) -> BTreeMap<String, String> {
+        self.names.get(scientific_name).cloned().unwrap_or_default()
+    }
+
+    /// Orders species the way a field guide would, anything without a taxon
+    /// order last and alphabetically. That's everything with the bundled
+    /// taxonomy, which doesn't have eBird's numbers.
+    pub fn compare(&self, a: (&str, &str), b: (&str, &str)) -> Ordering {
+        let order = |(scientific_name, _): (&str, &str)| {
+            self.by_scientific_name(scientific_name)
+                .and_then(|t| t.taxon_order)
+        };
+
+        match (order(a), order(b)) {
+            (Some(x), Some(y)) => x.total_cmp(&y),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+        .then_with(|| a.1.cmp(b.1))
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct LangQuery {
+    /// Language for common names, as in BirdNET's label files: `fr`, `es`.
+    pub lang: Option<String>,
+}
+
+impl LangQuery {
+    pub fn translation(&self) -> Option<Translation> {
+        Translation::new(self.lang.as_deref()?)
+    }
+}
+
+/// Translates the English common names BirdNET-Pi stores by their scientific
+/// names, using the labels loaded once along with the taxonomy.
+pub struct Translation {
+    language: String,
+}
+
+impl Translation {
+    /// English is what's stored, so there's nothing to do for it, and there's
+    /// nothing to do for languages without bundled labels either.
+    pub fn new(language: &str) -> Option<Self> {
+        let language = language.trim().to_lowercase();
+        if language.is_empty() || language == "en" {
+            return None;
+        }
+
+        if !BUNDLED_LABELS.iter().any(|(l, _)| *l == language) {
+            warn!(
+                "no {:?} labels, names are only translated to {}",
+                language,
+                BUNDLED_LABELS
+                    .iter()
+                    .map(|(l, _)| *l)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            return None;
+        }
+
+        Some(Self { language })
+    }
+
+    /// Replaces a common name with its translation, leaving it be when there
+    /// isn't one. Without a scientific name, the common name is looked up in
+    /// the taxonomy.
+    pub fn localize(&self, scientific_name: Option<&str>, common_name: &mut String) {
+        let taxonomy = taxonomy();
+        let scientific_name = match scientific_name {
+            Some(scientific_name) => scientific_name,
+            None => match taxonomy.by_common_name(common_name) {
+                Some(taxon) => taxon.scientific_name.as_str(),
+                None => return,
+            },
+        };
+
+        if let Some(name) = taxonomy.translate(&self.language, scientific_name) {
+            *common_name = name.to_owned();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAXA: &str = "\
+SCIENTIFIC_NAME,COMMON_NAME,SPECIES_CODE,CATEGORY,TAXON_ORDER,ORDER,FAMILY_COM_NAME,FAMILY_SCI_NAME
+Turdus migratorius,American Robin,amerob,species,29134,Passeriformes,Thrushes and Allies,Turdidae
+Corvus brachyrhynchos,American Crow,amecro,species,21316,Passeriformes,\"Crows, Jays, and Magpies\",Corvidae
+Columba livia,Rock Pigeon,rocpig,species,,Columbiformes,Pigeons and Doves,Columbidae
+";
+
+    #[test]
+    fn reads_the_bundled_taxonomy_and_labels() {
+        let taxonomy = Taxonomy::new(read_taxa(BUNDLED_TAXONOMY.as_bytes()).unwrap());
+
+        let mallard = taxonomy.by_common_name("Mallard").unwrap();
+        assert_eq!(mallard.scientific_name, "Anas platyrhynchos");
+        assert_eq!(mallard.species_code, "mallar3");
+        assert!(taxonomy.taxa.iter().all(|t| t.taxon_order.is_none()));
+
+        assert_eq!(
+            taxonomy.names("Anas platyrhynchos"),
+            BTreeMap::from([
+                ("es".into(), "Ánade Real".into()),
+                ("fr".into(), "Canard colvert".into()),
+            ])
+        );
+        assert!(taxonomy.names("Corvus renamedus").is_empty());
+    }
+
+    #[test]
+    fn translates_by_scientific_name() {
+        let translation = Translation::new(" FR ").unwrap();
+
+        let mut name = "Crow".to_owned();
+        translation.localize(Some("Corvus brachyrhynchos"), &mut name);
+        assert_eq!(name, "Corneille d'Amérique");
+
+        let mut name = "American Robin".to_owned();
+        translation.localize(None, &mut name);
+        assert_eq!(name, "Merle d'Amérique");
+
+        let mut name = "Mystery Bird".to_owned();
+        translation.localize(Some("Aves incognita"), &mut name);
+        translation.localize(None, &mut name);
+        assert_eq!(name, "Mystery Bird");
+
+        assert!(Translation::new("en").is_none());
+        assert!(Translation::new("").is_none());
+        assert!(Translation::new("de").is_none());
+    }
+
+    #[test]
+    fn orders_by_taxon_order_then_name() {
+        let taxonomy = Taxonomy::new(read_taxa(TAXA.as_bytes()).unwrap());
+        let mut species = [
+            ("Turdus migratorius", "American Robin"),
+            ("Columba livia", "Rock Pigeon"),
+            ("Aves incognita", "Mystery Bird"),
+            ("Corvus brachyrhynchos", "American Crow"),
+        ];
+
+        species.sort_by(|a, b| taxonomy.compare(*a, *b));
+
+        assert_eq!(
+            species.iter().map(|(_, c)| *c).collect::<Vec<_>>(),
+            vec![
+                "American Crow",
+                "American Robin",
+                "Mystery Bird",
+                "Rock Pigeon"
+            ]
+        );
+    }
+}